@@ -0,0 +1,87 @@
+//! Host-side benchmarks for the vmem allocation table, run with `cargo bench`.
+//!
+//! Each benchmark fills a table with `n` used tags and then measures removing
+//! and re-inserting one of them, which is the lookup `Vmem::free` does. The
+//! time per iteration should stay flat as `n` grows.
+
+use std::{hint::black_box, mem::MaybeUninit, ptr::NonNull, time::Instant};
+
+use mem::vmem::{
+    segment_queue::{allocation_table::AllocationTable, SegmentQueue},
+    tag_pool::TagPool,
+    Bt, BtKind, Link,
+};
+
+const ITERATIONS: u32 = 1_000_000;
+
+fn used_tags(n: usize) -> Vec<Bt> {
+    (0..n)
+        .map(|i| Bt {
+            kind: BtKind::Used,
+            base: i * 4096,
+            len: 4096,
+            segment_list: Link {
+                next: None,
+                prev: None,
+            },
+            segment_queue: MaybeUninit::uninit(),
+        })
+        .collect()
+}
+
+/// A pool with room for every bucket array a table of `n` tags grows through.
+fn pool(n: usize) -> TagPool {
+    let tags = Box::leak(Box::new_uninit_slice(TagPool::slots::<SegmentQueue>(2 * n)));
+    let mut pool = TagPool::new();
+    pool.add(NonNull::from(tags));
+    pool
+}
+
+fn bench(name: &str, iterations: u32, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let per_iteration = start.elapsed() / iterations;
+    println!("{name:<20} {:>10} ns/iter", per_iteration.as_nanos());
+}
+
+fn free_realloc(n: usize) {
+    let mut tags = used_tags(n);
+    let mut pool = pool(n);
+    let mut table = AllocationTable::new();
+    for tag in &mut tags {
+        table.insert(NonNull::from(tag), &mut pool);
+    }
+    let mut i = 0;
+    bench(&format!("free_{n}"), ITERATIONS, || {
+        let base = (i % n) * 4096;
+        let tag = table.get(black_box(base)).unwrap();
+        table.remove(tag, &mut pool);
+        table.insert(tag, &mut pool);
+        i = i.wrapping_mul(31).wrapping_add(7);
+    });
+    assert_eq!(table.len(), n);
+}
+
+fn grow_shrink(n: usize) {
+    let mut tags = used_tags(n);
+    let mut pool = pool(n);
+    bench(&format!("grow_shrink_{n}"), 10, || {
+        let mut table = AllocationTable::new();
+        for tag in &mut tags {
+            table.insert(NonNull::from(tag), &mut pool);
+        }
+        for tag in &mut tags {
+            table.remove(NonNull::from(tag), &mut pool);
+        }
+        assert_eq!(table.num_buckets(), AllocationTable::INITIAL_BUCKETS);
+    });
+}
+
+fn main() {
+    free_realloc(64);
+    free_realloc(4096);
+    free_realloc(65536);
+    grow_shrink(65536);
+}
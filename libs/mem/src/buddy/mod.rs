@@ -0,0 +1,233 @@
+//! A binary buddy allocator for physically contiguous pages.
+//!
+//! Memory is handed out in blocks of `2^order` pages, aligned to their own
+//! size, from order 0 (one page) up to [`MAX_ORDER`] (1 GiB). Free blocks are
+//! kept on one list per order, linked through a header written into the block
+//! itself, and a bitmap with a bit per page marks which pages start a free
+//! block, so freeing can tell whether a block's buddy is free without
+//! touching memory that isn't its own.
+//!
+//! The allocator works in physical addresses, and reaches the memory behind
+//! them at a fixed offset (the HHDM, or 0 before paging is set up).
+
+use core::ptr::NonNull;
+
+#[cfg(test)]
+mod tests;
+
+pub const PAGE_SIZE: usize = 4096;
+/// The largest order, 2^18 pages (1 GiB).
+pub const MAX_ORDER: usize = 18;
+
+/// Written to the start of every free block.
+struct Block {
+    next: Option<NonNull<Block>>,
+    prev: Option<NonNull<Block>>,
+    order: usize,
+}
+
+pub const fn block_size(order: usize) -> usize {
+    PAGE_SIZE << order
+}
+
+/// The smallest order whose blocks hold `size` bytes.
+pub const fn order_for(size: usize) -> usize {
+    let pages = size.div_ceil(PAGE_SIZE);
+    if pages <= 1 {
+        0
+    } else {
+        (pages - 1).ilog2() as usize + 1
+    }
+}
+
+pub struct Buddy {
+    /// The first page the bitmap covers.
+    base: usize,
+    pages: usize,
+    offset: usize,
+    lists: [Option<NonNull<Block>>; MAX_ORDER + 1],
+    bitmap: &'static mut [u64],
+    free_pages: usize,
+}
+unsafe impl Send for Buddy {}
+impl Buddy {
+    /// How many words of bitmap are needed to manage `[start, end)`.
+    pub const fn bitmap_words(start: usize, end: usize) -> usize {
+        let pages = (end - start / PAGE_SIZE * PAGE_SIZE).div_ceil(PAGE_SIZE);
+        pages.div_ceil(64)
+    }
+
+    /// Creates an empty allocator able to manage memory in `[start, end)`,
+    /// which is reached at `offset` from its physical address. Memory is
+    /// given to it with [`add_range`](Self::add_range).
+    ///
+    /// `bitmap` must be at least [`bitmap_words`](Self::bitmap_words) long.
+    pub fn new(start: usize, end: usize, offset: usize, bitmap: &'static mut [u64]) -> Self {
+        assert!(
+            bitmap.len() >= Self::bitmap_words(start, end),
+            "buddy bitmap too small"
+        );
+        bitmap.fill(0);
+        let base = start / PAGE_SIZE * PAGE_SIZE;
+        Self {
+            base,
+            pages: (end - base).div_ceil(PAGE_SIZE),
+            offset,
+            lists: [None; MAX_ORDER + 1],
+            bitmap,
+            free_pages: 0,
+        }
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+    pub fn free_pages(&self) -> usize {
+        self.free_pages
+    }
+    /// Whether `[start, end)` lies within the memory this allocator manages.
+    pub fn covers(&self, start: usize, end: usize) -> bool {
+        self.base <= start && end <= self.base + self.pages * PAGE_SIZE
+    }
+
+    /// Gives the whole pages in `[start, end)` to the allocator, coalescing
+    /// them with any free neighbours.
+    ///
+    /// # Safety
+    /// The memory must be unused, and writable at `offset`.
+    pub unsafe fn add_range(&mut self, start: usize, end: usize) {
+        let mut addr = start.next_multiple_of(PAGE_SIZE);
+        let end = end / PAGE_SIZE * PAGE_SIZE;
+        assert!(
+            addr >= end || self.covers(addr, end),
+            "range outside of buddy allocator"
+        );
+        while addr < end {
+            // The largest block that is aligned here and fits.
+            let mut order = ((addr / PAGE_SIZE).trailing_zeros() as usize).min(MAX_ORDER);
+            while addr + block_size(order) > end {
+                order -= 1;
+            }
+            self.free(addr, order);
+            addr += block_size(order);
+        }
+    }
+
+    /// Allocates a block of `2^order` pages, returning its physical address.
+    ///
+    /// The block's contents are whatever they were when it was freed, except
+    /// for the first few bytes, which the allocator used and zeroes again.
+    pub fn alloc(&mut self, order: usize) -> Option<usize> {
+        let found = (order..=MAX_ORDER).find(|&found| self.lists[found].is_some())?;
+        let block = self.lists[found].unwrap();
+        let addr = self.phys(block);
+        self.remove(block);
+        // Split down to size, freeing the upper halves.
+        for split in (order..found).rev() {
+            self.push(addr + block_size(split), split);
+        }
+        self.free_pages -= 1 << order;
+        Some(addr)
+    }
+
+    /// Frees a block of `2^order` pages, merging it with its buddy for as
+    /// long as the buddy is free too.
+    ///
+    /// # Safety
+    /// The block must have come from [`alloc`](Self::alloc) with the same
+    /// order, or be unused memory in range (as with
+    /// [`add_range`](Self::add_range)).
+    pub unsafe fn free(&mut self, mut addr: usize, mut order: usize) {
+        assert!(order <= MAX_ORDER, "order {order} too large");
+        assert!(
+            addr.is_multiple_of(block_size(order)),
+            "block {addr:#x} not aligned to order {order}"
+        );
+        assert!(
+            self.covers(addr, addr + block_size(order)),
+            "block {addr:#x} outside of buddy allocator"
+        );
+        assert!(!self.is_free(addr), "double free of block {addr:#x}");
+        self.free_pages += 1 << order;
+        while order < MAX_ORDER {
+            let buddy = addr ^ block_size(order);
+            if !self.covers(buddy, buddy + block_size(order)) || !self.is_free(buddy) {
+                break;
+            }
+            let buddy_block = self.virt(buddy);
+            if unsafe { buddy_block.as_ref() }.order != order {
+                break;
+            }
+            self.remove(buddy_block);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    /// The number of free blocks of each order.
+    pub fn free_blocks(&self) -> [usize; MAX_ORDER + 1] {
+        let mut counts = [0; MAX_ORDER + 1];
+        for (count, &head) in counts.iter_mut().zip(&self.lists) {
+            let mut block = head;
+            while let Some(next) = block {
+                *count += 1;
+                block = unsafe { next.as_ref() }.next;
+            }
+        }
+        counts
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let block = self.virt(addr);
+        let head = self.lists[order];
+        unsafe {
+            block.as_ptr().write(Block {
+                next: head,
+                prev: None,
+                order,
+            });
+        }
+        if let Some(mut head) = head {
+            unsafe { head.as_mut() }.prev = Some(block);
+        }
+        self.lists[order] = Some(block);
+        self.set_free(addr, true);
+    }
+    /// Unlinks a free block, and zeroes its header, so that blocks it's merged
+    /// into don't carry it around.
+    fn remove(&mut self, block: NonNull<Block>) {
+        let Block { next, prev, order } = unsafe { block.as_ptr().read() };
+        unsafe {
+            block.cast::<u8>().write_bytes(0, size_of::<Block>());
+        }
+        match prev {
+            Some(mut prev) => unsafe { prev.as_mut() }.next = next,
+            None => self.lists[order] = next,
+        }
+        if let Some(mut next) = next {
+            unsafe { next.as_mut() }.prev = prev;
+        }
+        self.set_free(self.phys(block), false);
+    }
+
+    fn is_free(&self, addr: usize) -> bool {
+        let page = (addr - self.base) / PAGE_SIZE;
+        self.bitmap[page / 64] & 1 << (page % 64) != 0
+    }
+    fn set_free(&mut self, addr: usize, free: bool) {
+        let page = (addr - self.base) / PAGE_SIZE;
+        if free {
+            self.bitmap[page / 64] |= 1 << (page % 64);
+        } else {
+            self.bitmap[page / 64] &= !(1 << (page % 64));
+        }
+    }
+
+    fn virt(&self, addr: usize) -> NonNull<Block> {
+        NonNull::new(addr.wrapping_add(self.offset) as *mut Block).unwrap()
+    }
+    fn phys(&self, block: NonNull<Block>) -> usize {
+        (block.as_ptr() as usize).wrapping_sub(self.offset)
+    }
+}
@@ -0,0 +1,166 @@
+//! Tests for [`Buddy`], over a heap buffer standing in for physical memory at
+//! [`BASE`].
+
+use alloc::{
+    alloc::{alloc_zeroed, dealloc},
+    boxed::Box,
+    collections::BTreeMap,
+    vec,
+    vec::Vec,
+};
+use core::alloc::Layout;
+
+use super::{block_size, order_for, Buddy, MAX_ORDER, PAGE_SIZE};
+use crate::test_util::Rng;
+
+const BASE: usize = 0x4000_0000;
+const PAGES: usize = 1024;
+const SIZE: usize = PAGES * PAGE_SIZE;
+
+struct Memory(*mut u8);
+impl Memory {
+    fn new() -> Self {
+        Self(unsafe { alloc_zeroed(Self::layout()) })
+    }
+    fn layout() -> Layout {
+        Layout::from_size_align(SIZE, PAGE_SIZE).unwrap()
+    }
+    fn offset(&self) -> usize {
+        (self.0 as usize).wrapping_sub(BASE)
+    }
+    fn buddy(&self) -> Buddy {
+        let bitmap = vec![0; Buddy::bitmap_words(BASE, BASE + SIZE)];
+        Buddy::new(BASE, BASE + SIZE, self.offset(), Box::leak(bitmap.into()))
+    }
+    #[allow(clippy::mut_from_ref)]
+    fn block(&self, addr: usize, order: usize) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.0.add(addr - BASE), block_size(order)) }
+    }
+}
+impl Drop for Memory {
+    fn drop(&mut self) {
+        unsafe { dealloc(self.0, Self::layout()) }
+    }
+}
+
+fn only_block(order: usize) -> [usize; MAX_ORDER + 1] {
+    let mut blocks = [0; MAX_ORDER + 1];
+    blocks[order] = 1;
+    blocks
+}
+
+#[test]
+fn orders() {
+    assert_eq!(order_for(0), 0);
+    assert_eq!(order_for(1), 0);
+    assert_eq!(order_for(PAGE_SIZE), 0);
+    assert_eq!(order_for(PAGE_SIZE + 1), 1);
+    assert_eq!(order_for(2 * 1024 * 1024), 9);
+    assert_eq!(order_for(1024 * 1024 * 1024), MAX_ORDER);
+}
+
+#[test]
+fn coalesce() {
+    let memory = Memory::new();
+    let mut buddy = memory.buddy();
+    unsafe { buddy.add_range(BASE, BASE + SIZE) };
+    assert_eq!(buddy.free_blocks(), only_block(10));
+
+    let mut pages = (0..PAGES)
+        .map(|_| buddy.alloc(0).unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(buddy.alloc(0), None);
+    assert_eq!(buddy.free_pages(), 0);
+    pages.sort();
+    pages.dedup();
+    assert_eq!(pages.len(), PAGES);
+
+    let mut rng = Rng::new(0);
+    while !pages.is_empty() {
+        let page = pages.swap_remove(rng.below(pages.len()));
+        unsafe { buddy.free(page, 0) };
+    }
+    assert_eq!(buddy.free_blocks(), only_block(10));
+    assert_eq!(buddy.alloc(10), Some(BASE));
+}
+
+#[test]
+fn holes() {
+    let memory = Memory::new();
+    let mut buddy = memory.buddy();
+    // Everything but one page in the middle, added out of order.
+    let hole = BASE + 300 * PAGE_SIZE;
+    unsafe {
+        buddy.add_range(hole + PAGE_SIZE, BASE + SIZE);
+        buddy.add_range(BASE, hole);
+    }
+    assert_eq!(buddy.free_pages(), PAGES - 1);
+    assert_eq!(buddy.alloc(9), Some(BASE + SIZE / 2));
+    assert_eq!(buddy.alloc(9), None);
+
+    while let Some(page) = buddy.alloc(0) {
+        assert_ne!(page, hole);
+    }
+}
+
+#[test]
+fn random() {
+    for seed in 0..16 {
+        let memory = Memory::new();
+        let mut buddy = memory.buddy();
+        unsafe { buddy.add_range(BASE, BASE + SIZE) };
+        let mut rng = Rng::new(seed);
+        let mut live = BTreeMap::new();
+
+        for _ in 0..2000 {
+            if live.is_empty() || rng.below(8) < 5 {
+                let order = rng.below(7);
+                let Some(addr) = buddy.alloc(order) else {
+                    continue;
+                };
+                assert_eq!(addr % block_size(order), 0, "misaligned block");
+                assert!(BASE <= addr && addr + block_size(order) <= BASE + SIZE);
+                if let Some((&before, &before_order)) = live.range(..addr).next_back() {
+                    assert!(before + block_size(before_order) <= addr, "overlap");
+                }
+                if let Some((&after, _)) = live.range(addr..).next() {
+                    assert!(addr + block_size(order) <= after, "overlap");
+                }
+                let block = memory.block(addr, order);
+                assert!(block.iter().all(|&byte| byte == 0), "block not clean");
+                block.fill(0xaa);
+                live.insert(addr, order);
+            } else {
+                let index = rng.below(live.len());
+                let (&addr, &order) = live.iter().nth(index).unwrap();
+                let block = memory.block(addr, order);
+                assert!(block.iter().all(|&byte| byte == 0xaa), "block corrupted");
+                block.fill(0);
+                live.remove(&addr);
+                unsafe { buddy.free(addr, order) };
+            }
+            let used = live.values().map(|&order| 1 << order).sum::<usize>();
+            assert_eq!(buddy.free_pages(), PAGES - used);
+        }
+
+        for (addr, order) in live {
+            memory.block(addr, order).fill(0);
+            unsafe { buddy.free(addr, order) };
+        }
+        assert_eq!(buddy.free_blocks(), only_block(10));
+    }
+}
+
+#[test]
+#[should_panic = "double free"]
+fn double_free() {
+    let memory = Memory::new();
+    let mut buddy = memory.buddy();
+    unsafe { buddy.add_range(BASE, BASE + SIZE) };
+    let a = buddy.alloc(0).unwrap();
+    let _b = buddy.alloc(0).unwrap();
+    unsafe {
+        buddy.free(a, 0);
+        buddy.free(a, 0);
+    }
+}
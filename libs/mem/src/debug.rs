@@ -0,0 +1,73 @@
+//! Allocator debugging checks, enabled by the `debug` feature.
+//!
+//! Free objects are filled with [`POISON`], and checked for it when they are
+//! handed out again, to catch writes after free. Objects may also be
+//! surrounded by redzones filled with [`REDZONE`], checked on free, to catch
+//! overruns. While an object is free its redzones hold [`REDZONE_FREE`]
+//! instead, which is how freeing it twice is caught without keeping track of
+//! objects anywhere else. Every report includes the location of the offending
+//! alloc or free.
+
+use core::panic::Location;
+
+pub const POISON: u8 = 0x6b;
+/// The redzones of an allocated object.
+pub const REDZONE: u8 = 0xbb;
+/// The redzones of a free object.
+pub const REDZONE_FREE: u8 = 0xcc;
+
+pub fn poison(memory: &mut [u8]) {
+    memory.fill(POISON);
+}
+
+pub fn is_poisoned(memory: &[u8]) -> bool {
+    memory.iter().all(|&byte| byte == POISON)
+}
+
+pub fn check_poison(cache: &str, memory: &[u8], caller: &Location) {
+    if let Some(offset) = memory.iter().position(|&byte| byte != POISON) {
+        panic!(
+            "{cache}: object at {:p} was written to after being freed \
+             (byte {offset:#x} is {:#04x}), found when allocating at {caller}",
+            memory.as_ptr(),
+            memory[offset],
+        );
+    }
+}
+
+/// Fills the `redzone` bytes on either side of `memory` with `byte`.
+pub fn fill_redzones(memory: &mut [u8], redzone: usize, byte: u8) {
+    let len = memory.len();
+    memory[..redzone].fill(byte);
+    memory[len - redzone..].fill(byte);
+}
+
+/// Whether the `redzone` bytes on either side of `memory` mark it free.
+pub fn is_free(memory: &[u8], redzone: usize) -> bool {
+    let len = memory.len();
+    memory[..redzone]
+        .iter()
+        .chain(&memory[len - redzone..])
+        .all(|&byte| byte == REDZONE_FREE)
+}
+
+pub fn check_redzones(cache: &str, memory: &[u8], redzone: usize, caller: &Location) {
+    let len = memory.len();
+    let (before, after) = (&memory[..redzone], &memory[len - redzone..]);
+    if let Some(offset) = before.iter().position(|&byte| byte != REDZONE) {
+        panic!(
+            "{cache}: object at {:p} was underrun (redzone byte {offset:#x} before it is \
+             {:#04x}), found when freeing at {caller}",
+            memory[redzone..].as_ptr(),
+            before[offset],
+        );
+    }
+    if let Some(offset) = after.iter().position(|&byte| byte != REDZONE) {
+        panic!(
+            "{cache}: object at {:p} was overrun (redzone byte {offset:#x} after it is \
+             {:#04x}), found when freeing at {caller}",
+            memory[redzone..].as_ptr(),
+            after[offset],
+        );
+    }
+}
@@ -1,8 +1,12 @@
 #![no_std]
-#![allow(incomplete_features)]
-#![feature(async_fn_in_trait)]
 
 extern crate alloc;
 
+pub mod buddy;
+#[cfg(feature = "debug")]
+pub mod debug;
+pub mod memmap;
 pub mod slab;
+#[cfg(test)]
+mod test_util;
 pub mod vmem;
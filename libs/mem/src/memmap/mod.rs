@@ -0,0 +1,330 @@
+//! The early memory map: what the firmware says is RAM, and what's already
+//! spoken for in it, before any allocator exists.
+//!
+//! The map is a sorted list of non-overlapping [`Region`]s, each with a
+//! [`RegionKind`]. Adjacent regions of the same kind are always merged, and
+//! overlaps are resolved rather than rejected: RAM reported twice is only
+//! counted once, and where two reservations overlap the stronger kind wins
+//! (see [`RegionKind`]). Only RAM is tracked, so reserving memory outside of
+//! it is a no-op.
+//!
+//! There's no heap this early, so the map lives in storage handed to it. When
+//! that runs out, operations fail with [`MemoryMapError::Full`] without
+//! changing anything, and [`MemoryMap::grow`] moves the map into a bigger
+//! buffer carved out of the memory it describes.
+
+use core::mem::size_of;
+
+use crate::buddy::PAGE_SIZE;
+
+#[cfg(test)]
+mod tests;
+
+/// What a region of RAM is used for. Where reservations overlap, the later
+/// kind in this list wins.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegionKind {
+    /// Free for the taking.
+    Usable,
+    /// Set aside by the firmware or the device tree.
+    Reserved,
+    /// Set aside by the device tree as `no-map`: the kernel mustn't map it at
+    /// all, not even in the direct map, so that the CPU can't speculatively
+    /// touch it.
+    NoMap,
+    /// The initial ramdisk.
+    Initrd,
+    /// The device tree blob.
+    Dtb,
+    /// The kernel image, and anything taken for it during boot.
+    Kernel,
+}
+impl RegionKind {
+    /// Whether the region belongs in the direct map.
+    pub const fn is_mapped(self) -> bool {
+        !matches!(self, Self::NoMap)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub start: u64,
+    pub end: u64,
+    pub kind: RegionKind,
+}
+impl Region {
+    pub const EMPTY: Self = Self::new(0, 0, RegionKind::Usable);
+
+    pub const fn new(start: u64, end: u64, kind: RegionKind) -> Self {
+        Self { start, end, kind }
+    }
+    pub const fn size(&self) -> u64 {
+        self.end - self.start
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The map has no room for the regions the operation would add. Nothing
+    /// was changed; [`grow`](MemoryMap::grow) it and try again.
+    Full,
+    /// No usable region is large enough.
+    NoRoom,
+}
+
+/// Entries kept free for [`grow`](MemoryMap::grow), which needs up to two to
+/// take its new buffer out of a usable region.
+const HEADROOM: usize = 2;
+
+pub struct MemoryMap<'a> {
+    regions: &'a mut [Region],
+    len: usize,
+}
+impl<'a> MemoryMap<'a> {
+    pub fn new(storage: &'a mut [Region]) -> Self {
+        assert!(storage.len() > HEADROOM, "memory map storage too small");
+        Self {
+            regions: storage,
+            len: 0,
+        }
+    }
+
+    pub fn regions(&self) -> &[Region] {
+        &self.regions[..self.len]
+    }
+    pub fn usable(&self) -> impl Iterator<Item = &Region> {
+        self.regions()
+            .iter()
+            .filter(|region| region.kind == RegionKind::Usable)
+    }
+    pub fn capacity(&self) -> usize {
+        self.regions.len()
+    }
+
+    /// The start of the first region of RAM and the end of the last.
+    pub fn bounds(&self) -> Option<(u64, u64)> {
+        Some((self.regions().first()?.start, self.regions().last()?.end))
+    }
+
+    /// Whether all of `[start, end)` is usable RAM.
+    pub fn is_usable(&self, start: u64, end: u64) -> bool {
+        let mut cursor = start;
+        for region in self.usable() {
+            if cursor >= end {
+                break;
+            }
+            if region.start <= cursor && cursor < region.end {
+                cursor = region.end;
+            }
+        }
+        cursor >= end
+    }
+
+    /// Adds RAM. Whatever part of it is already in the map keeps its kind.
+    pub fn add_usable(&mut self, start: u64, end: u64) -> Result<(), MemoryMapError> {
+        self.update(start, end, HEADROOM, |kind| {
+            Some(kind.unwrap_or(RegionKind::Usable))
+        })
+    }
+
+    /// Marks the RAM in `[start, end)` as `kind`, unless it's already
+    /// something stronger. Anything that isn't RAM is left alone.
+    pub fn reserve(
+        &mut self,
+        start: u64,
+        end: u64,
+        kind: RegionKind,
+    ) -> Result<(), MemoryMapError> {
+        self.update(start, end, HEADROOM, |old| old.map(|old| old.max(kind)))
+    }
+
+    /// Takes `size` bytes aligned to `align` out of the first usable region
+    /// with room, and marks them as `kind`.
+    pub fn take(&mut self, size: u64, align: u64, kind: RegionKind) -> Result<u64, MemoryMapError> {
+        self.take_with_headroom((0, u64::MAX), size, align, kind, HEADROOM)
+    }
+
+    /// Like [`take`](Self::take), but only out of the usable RAM within
+    /// `[start, end)`.
+    pub fn take_within(
+        &mut self,
+        start: u64,
+        end: u64,
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+    ) -> Result<u64, MemoryMapError> {
+        self.take_with_headroom((start, end), size, align, kind, HEADROOM)
+    }
+
+    /// Moves the map into a buffer twice its size, taken out of its own usable
+    /// memory and marked as `kind`. `to_ptr` turns the buffer's physical
+    /// address into a pointer.
+    ///
+    /// The old storage isn't given back.
+    ///
+    /// # Safety
+    /// `to_ptr` must return a pointer to the memory at the address it's given,
+    /// valid for `'a`.
+    pub unsafe fn grow(
+        &mut self,
+        kind: RegionKind,
+        to_ptr: impl FnOnce(u64) -> *mut Region,
+    ) -> Result<(), MemoryMapError> {
+        let size = (self.capacity() * 2 * size_of::<Region>()).next_multiple_of(PAGE_SIZE);
+        let start =
+            self.take_with_headroom((0, u64::MAX), size as u64, PAGE_SIZE as u64, kind, 0)?;
+        let storage = core::slice::from_raw_parts_mut(to_ptr(start), size / size_of::<Region>());
+        storage[..self.len].copy_from_slice(self.regions());
+        storage[self.len..].fill(Region::EMPTY);
+        self.regions = storage;
+        Ok(())
+    }
+
+    fn take_with_headroom(
+        &mut self,
+        (min, max): (u64, u64),
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+        headroom: usize,
+    ) -> Result<u64, MemoryMapError> {
+        let start = self
+            .usable()
+            .find_map(|region| {
+                let start = region.start.max(min).checked_next_multiple_of(align)?;
+                (start.checked_add(size)? <= region.end.min(max)).then_some(start)
+            })
+            .ok_or(MemoryMapError::NoRoom)?;
+        self.update(start, start + size, headroom, |old| old.map(|_| kind))?;
+        Ok(start)
+    }
+
+    /// Sets the kind of every byte in `[start, end)` to `f` of its current
+    /// kind, where `None` means it isn't in the map. Fails without changing
+    /// anything if that would leave fewer than `headroom` free entries.
+    fn update(
+        &mut self,
+        start: u64,
+        end: u64,
+        headroom: usize,
+        f: impl Fn(Option<RegionKind>) -> Option<RegionKind>,
+    ) -> Result<(), MemoryMapError> {
+        if start >= end {
+            return Ok(());
+        }
+        let changes = |region: &Region| f(Some(region.kind)) != Some(region.kind);
+
+        // Count the entries this adds first, so that it can't fail halfway.
+        let mut needed = 0;
+        for region in self.regions() {
+            for at in [start, end] {
+                if region.start < at && at < region.end && changes(region) {
+                    needed += 1;
+                }
+            }
+        }
+        if f(None).is_some() {
+            needed += self.holes(start, end).count();
+        }
+        if self.len + needed + headroom > self.capacity() {
+            return Err(MemoryMapError::Full);
+        }
+
+        // Split the regions that straddle either end, so that every region
+        // that changes lies entirely inside the range.
+        for at in [start, end] {
+            let index = self.regions().partition_point(|region| region.end <= at);
+            if let Some(region) = self.regions().get(index).copied() {
+                if region.start < at && changes(&region) {
+                    self.regions[index].end = at;
+                    self.insert(index + 1, Region::new(at, region.end, region.kind));
+                }
+            }
+        }
+
+        let mut index = self.regions().partition_point(|region| region.end <= start);
+        let mut cursor = start;
+        while cursor < end {
+            let next = self.regions().get(index).copied();
+            let hole_end = match next {
+                Some(region) if region.start <= cursor => None,
+                Some(region) => Some(region.start.min(end)),
+                None => Some(end),
+            };
+            if let Some(hole_end) = hole_end {
+                if let Some(kind) = f(None) {
+                    self.insert(index, Region::new(cursor, hole_end, kind));
+                    index += 1;
+                }
+                cursor = hole_end;
+                continue;
+            }
+
+            let region = next.unwrap();
+            cursor = region.end;
+            match f(Some(region.kind)) {
+                Some(kind) => {
+                    self.regions[index].kind = kind;
+                    index += 1;
+                }
+                None => self.remove(index),
+            }
+        }
+
+        self.coalesce();
+        Ok(())
+    }
+
+    /// The parts of `[start, end)` that aren't in the map.
+    fn holes(&self, start: u64, end: u64) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let mut cursor = start;
+        let mut regions = self.regions().iter();
+        core::iter::from_fn(move || {
+            while cursor < end {
+                let Some(region) = regions.next() else {
+                    let hole = (cursor, end);
+                    cursor = end;
+                    return Some(hole);
+                };
+                if region.end <= cursor {
+                    continue;
+                }
+                let hole = (cursor, region.start.min(end));
+                cursor = region.end;
+                if hole.0 < hole.1 {
+                    return Some(hole);
+                }
+            }
+            None
+        })
+    }
+
+    fn insert(&mut self, index: usize, region: Region) {
+        self.regions.copy_within(index..self.len, index + 1);
+        self.regions[index] = region;
+        self.len += 1;
+    }
+    fn remove(&mut self, index: usize) {
+        self.regions.copy_within(index + 1..self.len, index);
+        self.len -= 1;
+    }
+
+    /// Merges adjacent regions of the same kind.
+    fn coalesce(&mut self) {
+        let mut merged = 0;
+        for index in 0..self.len {
+            let region = self.regions[index];
+            if merged > 0 {
+                let last = &mut self.regions[merged - 1];
+                if last.end == region.start && last.kind == region.kind {
+                    last.end = region.end;
+                    continue;
+                }
+            }
+            self.regions[merged] = region;
+            merged += 1;
+        }
+        self.len = merged;
+    }
+}
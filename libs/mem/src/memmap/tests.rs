@@ -0,0 +1,330 @@
+//! Tests for [`MemoryMap`], mostly with layouts real firmware has been known to
+//! report.
+
+use alloc::{vec, vec::Vec};
+use core::mem::size_of;
+
+use super::{MemoryMap, MemoryMapError, Region, RegionKind};
+use crate::test_util::Rng;
+use RegionKind::*;
+
+const MIB: u64 = 1024 * 1024;
+
+fn map(storage: &mut [Region]) -> MemoryMap<'_> {
+    MemoryMap::new(storage)
+}
+
+fn regions(map: &MemoryMap<'_>) -> Vec<(u64, u64, RegionKind)> {
+    map.regions()
+        .iter()
+        .map(|region| (region.start, region.end, region.kind))
+        .collect()
+}
+
+#[test]
+fn merge() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    // Out of order, and the last one fills the gap between two others.
+    map.add_usable(0, MIB).unwrap();
+    map.add_usable(2 * MIB, 3 * MIB).unwrap();
+    map.add_usable(5 * MIB, 6 * MIB).unwrap();
+    map.add_usable(MIB, 2 * MIB).unwrap();
+    assert_eq!(
+        regions(&map),
+        [(0, 3 * MIB, Usable), (5 * MIB, 6 * MIB, Usable)]
+    );
+    assert_eq!(map.bounds(), Some((0, 6 * MIB)));
+}
+
+#[test]
+fn overlapping_ram() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(MIB, 4 * MIB).unwrap();
+    // Reported twice, partly overlapping, and once entirely inside.
+    map.add_usable(3 * MIB, 6 * MIB).unwrap();
+    map.add_usable(0, 2 * MIB).unwrap();
+    map.add_usable(2 * MIB, 3 * MIB).unwrap();
+    assert_eq!(regions(&map), [(0, 6 * MIB, Usable)]);
+
+    // Reservations made before RAM is (re)reported stay reserved.
+    map.reserve(MIB, 2 * MIB, Kernel).unwrap();
+    map.add_usable(0, 8 * MIB).unwrap();
+    assert_eq!(
+        regions(&map),
+        [
+            (0, MIB, Usable),
+            (MIB, 2 * MIB, Kernel),
+            (2 * MIB, 8 * MIB, Usable)
+        ]
+    );
+}
+
+#[test]
+fn split() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(0, 8 * MIB).unwrap();
+    map.reserve(2 * MIB, 3 * MIB, Dtb).unwrap();
+    map.reserve(0, MIB, Reserved).unwrap();
+    map.reserve(7 * MIB, 8 * MIB, Initrd).unwrap();
+    assert_eq!(
+        regions(&map),
+        [
+            (0, MIB, Reserved),
+            (MIB, 2 * MIB, Usable),
+            (2 * MIB, 3 * MIB, Dtb),
+            (3 * MIB, 7 * MIB, Usable),
+            (7 * MIB, 8 * MIB, Initrd),
+        ]
+    );
+    assert_eq!(map.usable().count(), 2);
+    assert!(map.is_usable(MIB, 2 * MIB));
+    assert!(!map.is_usable(MIB, 3 * MIB));
+}
+
+#[test]
+fn reserve_across_holes() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(0, 2 * MIB).unwrap();
+    map.add_usable(4 * MIB, 6 * MIB).unwrap();
+    // Spans a hole, and runs off the end of RAM: only the RAM gets reserved.
+    map.reserve(MIB, 10 * MIB, Reserved).unwrap();
+    assert_eq!(
+        regions(&map),
+        [
+            (0, MIB, Usable),
+            (MIB, 2 * MIB, Reserved),
+            (4 * MIB, 6 * MIB, Reserved)
+        ]
+    );
+    // Entirely outside of RAM.
+    map.reserve(20 * MIB, 30 * MIB, Kernel).unwrap();
+    assert_eq!(map.regions().len(), 3);
+    assert!(!map.is_usable(2 * MIB, 3 * MIB));
+}
+
+#[test]
+fn priority() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(0, 8 * MIB).unwrap();
+    map.reserve(2 * MIB, 4 * MIB, Kernel).unwrap();
+    // A firmware reservation overlapping the kernel doesn't hide it.
+    map.reserve(MIB, 3 * MIB, Reserved).unwrap();
+    map.reserve(3 * MIB, 5 * MIB, Reserved).unwrap();
+    assert_eq!(
+        regions(&map),
+        [
+            (0, MIB, Usable),
+            (MIB, 2 * MIB, Reserved),
+            (2 * MIB, 4 * MIB, Kernel),
+            (4 * MIB, 5 * MIB, Reserved),
+            (5 * MIB, 8 * MIB, Usable),
+        ]
+    );
+    // Nor does reserving something as usable free it.
+    map.reserve(0, 8 * MIB, Usable).unwrap();
+    assert_eq!(map.regions().len(), 5);
+    // But a stronger kind takes over, and merges with its neighbour.
+    map.reserve(4 * MIB, 5 * MIB, Kernel).unwrap();
+    assert_eq!(map.regions()[2], Region::new(2 * MIB, 5 * MIB, Kernel));
+}
+
+#[test]
+fn take() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(0x1000, 0x3000).unwrap();
+    map.add_usable(0x10_0000, 0x40_0000).unwrap();
+    // Too big for the first region, once aligned.
+    assert_eq!(map.take(0x2000, 0x2000, Kernel), Ok(0x10_0000));
+    assert_eq!(map.take(0x1000, 0x1000, Kernel), Ok(0x1000));
+    assert_eq!(map.take(0x1000, 0x20_0000, Kernel), Ok(0x20_0000));
+    assert_eq!(
+        regions(&map),
+        [
+            (0x1000, 0x2000, Kernel),
+            (0x2000, 0x3000, Usable),
+            (0x10_0000, 0x10_2000, Kernel),
+            (0x10_2000, 0x20_0000, Usable),
+            (0x20_0000, 0x20_1000, Kernel),
+            (0x20_1000, 0x40_0000, Usable),
+        ]
+    );
+    assert_eq!(
+        map.take(0x40_0000, 0x1000, Kernel),
+        Err(MemoryMapError::NoRoom)
+    );
+}
+
+#[test]
+fn take_within() {
+    let mut storage = [Region::EMPTY; 8];
+    let mut map = map(&mut storage);
+    map.add_usable(0x1000, 0x3000).unwrap();
+    map.add_usable(0x10_0000, 0x40_0000).unwrap();
+    // Skips the first region, which lies outside the range.
+    assert_eq!(
+        map.take_within(0x8000, 0x20_0000, 0x1000, 0x1000, NoMap),
+        Ok(0x10_0000)
+    );
+    // The range clips the region before aligning.
+    assert_eq!(
+        map.take_within(0x10_1800, 0x20_0000, 0x1000, 0x1000, NoMap),
+        Ok(0x10_2000)
+    );
+    // And the end of the range is as binding as the end of the region.
+    assert_eq!(
+        map.take_within(0x8000, 0x10_4000, 0x2000, 0x1000, NoMap),
+        Err(MemoryMapError::NoRoom)
+    );
+    assert_eq!(
+        map.take_within(0x8000, 0x10_5000, 0x2000, 0x1000, NoMap),
+        Ok(0x10_3000)
+    );
+    assert_eq!(
+        regions(&map),
+        [
+            (0x1000, 0x3000, Usable),
+            (0x10_0000, 0x10_1000, NoMap),
+            (0x10_1000, 0x10_2000, Usable),
+            (0x10_2000, 0x10_5000, NoMap),
+            (0x10_5000, 0x40_0000, Usable),
+        ]
+    );
+    assert!(!NoMap.is_mapped());
+    assert!(Reserved.is_mapped());
+}
+
+#[test]
+fn full() {
+    let mut storage = [Region::EMPTY; 6];
+    let mut map = map(&mut storage);
+    map.add_usable(0, 8 * MIB).unwrap();
+    map.reserve(MIB, 2 * MIB, Reserved).unwrap();
+    let before = regions(&map);
+    // Would need two more entries, which would eat into the headroom.
+    assert_eq!(
+        map.reserve(4 * MIB, 5 * MIB, Reserved),
+        Err(MemoryMapError::Full)
+    );
+    assert_eq!(regions(&map), before);
+    // Something that needs fewer still works.
+    map.reserve(2 * MIB, 3 * MIB, Reserved).unwrap();
+    map.reserve(MIB, 2 * MIB, Reserved).unwrap();
+    assert_eq!(
+        regions(&map),
+        [
+            (0, MIB, Usable),
+            (MIB, 3 * MIB, Reserved),
+            (3 * MIB, 8 * MIB, Usable)
+        ]
+    );
+}
+
+#[test]
+fn grow() {
+    let mut storage = [Region::EMPTY; 3];
+    let mut buffer = vec![Region::EMPTY; 4096 / size_of::<Region>()];
+    let mut map = map(&mut storage);
+    map.add_usable(0, 0x10_0000).unwrap();
+    assert_eq!(
+        map.add_usable(0x20_0000, 0x30_0000),
+        Err(MemoryMapError::Full)
+    );
+    unsafe { map.grow(Kernel, |_| buffer.as_mut_ptr()) }.unwrap();
+    assert_eq!(map.capacity(), buffer.len());
+    map.add_usable(0x20_0000, 0x30_0000).unwrap();
+    for i in 0..8 {
+        let start = 0x20_0000 + i * 0x2000;
+        map.reserve(start, start + 0x1000, Reserved).unwrap();
+    }
+    assert_eq!(map.regions()[0], Region::new(0, 0x1000, Kernel));
+    assert_eq!(map.regions()[1], Region::new(0x1000, 0x10_0000, Usable));
+    assert_eq!(map.regions().len(), 2 + 16);
+}
+
+#[test]
+fn grow_without_ram() {
+    let mut storage = [Region::EMPTY; 4];
+    let mut map = map(&mut storage);
+    assert_eq!(
+        unsafe { map.grow(Kernel, |_| unreachable!()) },
+        Err(MemoryMapError::NoRoom)
+    );
+}
+
+/// Random operations on a few pages, checked against a kind per page.
+#[test]
+fn random() {
+    const PAGES: usize = 64;
+    const PAGE: u64 = 0x1000;
+    let strength = [Usable, Reserved, NoMap, Initrd, Dtb, Kernel];
+
+    for seed in 0..32 {
+        let mut rng = Rng::new(seed);
+        let mut storage = [Region::EMPTY; PAGES + 2];
+        let mut map = map(&mut storage);
+        let mut model: [Option<RegionKind>; PAGES] = [None; PAGES];
+
+        for _ in 0..500 {
+            let start = rng.below(PAGES) as u64;
+            let end = start + 1 + rng.below(PAGES - start as usize) as u64;
+            let pages = start as usize..end as usize;
+            match rng.below(3) {
+                0 => {
+                    map.add_usable(start * PAGE, end * PAGE).unwrap();
+                    for page in pages {
+                        model[page].get_or_insert(Usable);
+                    }
+                }
+                1 => {
+                    let kind = strength[rng.below(strength.len())];
+                    map.reserve(start * PAGE, end * PAGE, kind).unwrap();
+                    for page in pages {
+                        model[page] = model[page].map(|old| old.max(kind));
+                    }
+                }
+                _ => {
+                    let size = 1 + rng.below(4) as u64;
+                    let align = 1 << rng.below(3);
+                    let first_fit = (0..PAGES as u64).step_by(align).find(|&page| {
+                        page + size <= PAGES as u64
+                            && (page..page + size).all(|page| model[page as usize] == Some(Usable))
+                    });
+                    let taken = map.take(size * PAGE, align as u64 * PAGE, Kernel);
+                    match first_fit {
+                        Some(page) => {
+                            assert_eq!(taken, Ok(page * PAGE), "seed {seed}");
+                            for page in page..page + size {
+                                model[page as usize] = Some(Kernel);
+                            }
+                        }
+                        None => assert_eq!(taken, Err(MemoryMapError::NoRoom), "seed {seed}"),
+                    }
+                }
+            }
+
+            let mut expected: [Option<RegionKind>; PAGES] = [None; PAGES];
+            let mut last: Option<&Region> = None;
+            for region in map.regions() {
+                assert!(region.start < region.end, "seed {seed}: empty region");
+                if let Some(last) = last {
+                    assert!(last.end <= region.start, "seed {seed}: unsorted");
+                    assert!(
+                        last.end < region.start || last.kind != region.kind,
+                        "seed {seed}: not merged"
+                    );
+                }
+                for page in region.start / PAGE..region.end / PAGE {
+                    expected[page as usize] = Some(region.kind);
+                }
+                last = Some(region);
+            }
+            assert_eq!(expected, model, "seed {seed}");
+        }
+    }
+}
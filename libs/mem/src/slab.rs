@@ -1,100 +1,517 @@
-use core::cell::UnsafeCell;
+use core::{cell::UnsafeCell, future::Future, panic::Location, ptr::NonNull};
 
-use alloc::{vec, vec::Vec};
+use alloc::{boxed::Box, vec::Vec};
 use system::sync::{Lock, Mutex};
 
+#[cfg(test)]
+mod tests;
+
+#[allow(async_fn_in_trait)]
 pub trait Alloc {
     type Item: Clone;
+    /// Size of the redzones at either end of each item's memory.
+    const REDZONE: usize = 0;
+    /// Whether items come from [`alloc`](Self::alloc) zeroed, and callers
+    /// count on that. The `debug` feature zeroes them again once it has
+    /// checked their poison, rather than hand them out poisoned.
+    const ZEROED: bool = false;
+
     async fn alloc(&mut self) -> Option<Self::Item>;
     async fn free(&mut self, item: Self::Item);
+
+    /// Allocates up to `count` items, handing each to `push`, and returns
+    /// how many were allocated.
+    async fn alloc_bulk(&mut self, count: usize, mut push: impl FnMut(Self::Item)) -> usize {
+        for allocated in 0..count {
+            let Some(item) = self.alloc().await else {
+                return allocated;
+            };
+            push(item);
+        }
+        count
+    }
+    async fn free_bulk(&mut self, items: impl IntoIterator<Item = Self::Item>) {
+        for item in items {
+            self.free(item).await;
+        }
+    }
+
+    /// The memory behind `item`, including its redzones, for the `debug`
+    /// feature to check. Items without memory aren't checked.
+    fn debug_memory(_item: &Self::Item) -> Option<NonNull<[u8]>> {
+        None
+    }
+}
+
+/// A stack of up to `N` constructed objects.
+type Magazine<T, const N: usize> = heapless::Vec<T, N>;
+
+/// The per-CPU layer: a loaded magazine that alloc and free work on, and the
+/// previously loaded one, so that alternating alloc/free at a magazine
+/// boundary doesn't go to the depot every time.
+struct CpuCache<T, const N: usize> {
+    loaded: Box<Magazine<T, N>>,
+    previous: Box<Magazine<T, N>>,
+}
+impl<T, const N: usize> CpuCache<T, N> {
+    fn new() -> Self {
+        Self {
+            loaded: Box::new(Magazine::new()),
+            previous: Box::new(Magazine::new()),
+        }
+    }
+
+    fn alloc(&mut self) -> Option<T> {
+        if self.loaded.is_empty() && !self.previous.is_empty() {
+            core::mem::swap(&mut self.loaded, &mut self.previous);
+        }
+        self.loaded.pop()
+    }
+    fn len(&self) -> usize {
+        self.loaded.len() + self.previous.len()
+    }
+    fn free(&mut self, item: T) -> Result<(), T> {
+        if self.loaded.is_full() && !self.previous.is_full() {
+            core::mem::swap(&mut self.loaded, &mut self.previous);
+        }
+        self.loaded.push(item)
+    }
+}
+
+/// The global layer, shared between all CPUs. Magazines flushed to it aren't
+/// necessarily full, just not empty.
+struct Depot<T, const N: usize> {
+    full: Vec<Box<Magazine<T, N>>>,
+    empty: Vec<Box<Magazine<T, N>>>,
 }
 
+/// How many objects each CPU holds on to. Freeing to a CPU that holds `high`
+/// objects first flushes all but `low` of them to the depot, under a single
+/// lock, and restocking a CPU from the backing allocator fills it up to `low`.
+/// By default `low` is `N` and `high` is `2 * N`, so a full CPU flushes half
+/// of its objects.
+#[derive(Clone, Copy)]
+pub struct Watermarks {
+    pub low: usize,
+    pub high: usize,
+}
+
+/// An object cache in front of a backing allocator.
+///
+/// Objects are constructed when they come from the backing allocator and
+/// destructed when they go back to it; in between, they sit constructed in
+/// magazines of `N` objects. Each CPU has two magazines of its own, and swaps
+/// whole magazines with the depot rather than going to the backing allocator
+/// for every object; see [`Watermarks`] for how many it keeps.
+///
+/// With the `debug` feature, free objects are poisoned (unless the cache has a
+/// constructor, and zeroed again on the way out if they should be; see
+/// [`Alloc::ZEROED`]), and redzones are checked on free. Double frees are caught by
+/// the redzones marking objects free, or failing that by the poison; a cache
+/// with a constructor and no redzones can't catch them.
 pub struct Slab<A: Alloc, const N: usize> {
-    slabs: UnsafeCell<Vec<heapless::Vec<A::Item, N>>>,
+    name: &'static str,
+    cpus: UnsafeCell<Vec<CpuCache<A::Item, N>>>,
+    depot: Mutex<Depot<A::Item, N>>,
     alloc: Mutex<A>,
+    constructor: Option<fn(&mut A::Item)>,
+    destructor: Option<fn(&mut A::Item)>,
+    watermarks: Watermarks,
 }
 
 unsafe impl<A: Alloc, const N: usize> Send for Slab<A, N> {}
 unsafe impl<A: Alloc, const N: usize> Sync for Slab<A, N> {}
 
 impl<A: Alloc, const N: usize> Slab<A, N> {
-    pub fn new(alloc: A) -> Self {
+    pub fn new(name: &'static str, alloc: A) -> Self {
+        Self::new_for_cpus(name, alloc, system::cpus::CpuInfo::num_cpus())
+    }
+    /// Like [`new`](Self::new), with magazines for the first `num_cpus` CPUs
+    /// rather than asking how many there are.
+    pub fn new_for_cpus(name: &'static str, alloc: A, num_cpus: usize) -> Self {
         Self {
-            slabs: UnsafeCell::new(vec![
-                heapless::Vec::new();
-                system::cpus::CpuInfo::num_cpus()
-            ]),
+            name,
+            cpus: UnsafeCell::new((0..num_cpus).map(|_| CpuCache::new()).collect()),
+            depot: Mutex::new(Depot {
+                full: Vec::new(),
+                empty: Vec::new(),
+            }),
             alloc: Mutex::new(alloc),
+            constructor: None,
+            destructor: None,
+            watermarks: Watermarks {
+                low: N,
+                high: 2 * N,
+            },
         }
     }
+    /// Runs `constructor` on every object coming from the backing allocator.
+    pub fn with_constructor(mut self, constructor: fn(&mut A::Item)) -> Self {
+        self.constructor = Some(constructor);
+        self
+    }
+    /// Runs `destructor` on every object going back to the backing allocator.
+    pub fn with_destructor(mut self, destructor: fn(&mut A::Item)) -> Self {
+        self.destructor = Some(destructor);
+        self
+    }
+
+    /// Sets how many objects each CPU holds on to; see [`Watermarks`].
+    pub fn with_watermarks(mut self, low: usize, high: usize) -> Self {
+        assert!(
+            0 < low && low < high,
+            "watermarks must satisfy 0 < low < high"
+        );
+        assert!(
+            high <= 2 * N,
+            "high watermark above the magazines' capacity"
+        );
+        self.watermarks = Watermarks { low, high };
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
 
     pub fn empty(&self) -> bool {
-        let cpu_id = system::cpus::CpuInfo::cpu_id();
-        let slab = &self.get_slabs()[cpu_id];
-        slab.is_empty()
+        let cpu = self.cpu();
+        cpu.loaded.is_empty() && cpu.previous.is_empty()
     }
 
+    /// Allocates from this CPU's magazines only, without taking any locks.
+    #[track_caller]
     pub fn alloc(&self) -> Option<A::Item> {
-        let cpu_id = system::cpus::CpuInfo::cpu_id();
-        let slab = &mut self.get_slabs_mut()[cpu_id];
-        if let Some(item) = slab.pop() {
-            Some(item)
-        } else {
-            None
-        }
+        let item = self.cpu().alloc()?;
+        self.debug_alloc(&item, Location::caller());
+        Some(item)
     }
 
-    pub async fn free(&self, item: A::Item) {
-        let cpu_id = system::cpus::CpuInfo::cpu_id();
-        let slab = &mut self.get_slabs_mut()[cpu_id];
-        if let Err(item) = slab.push(item) {
-            self.alloc.lock().await.free(item).await;
+    #[track_caller]
+    pub fn free(&self, item: A::Item) -> impl Future<Output = ()> + '_ {
+        let caller = Location::caller();
+        async move {
+            self.debug_free(&item, caller);
+            self.free_flushing(item).await;
+        }
+    }
+    #[track_caller]
+    pub fn free_bulk<'a, I>(&'a self, items: I) -> impl Future<Output = ()> + 'a
+    where
+        I: IntoIterator<Item = A::Item> + 'a,
+    {
+        let caller = Location::caller();
+        async move {
+            for item in items {
+                self.debug_free(&item, caller);
+                self.free_flushing(item).await;
+            }
         }
     }
+    /// Frees into this CPU's magazines only, giving the item back if the
+    /// CPU is at its high watermark.
+    #[track_caller]
     pub fn free_nolock(&self, item: A::Item) -> Result<(), A::Item> {
-        let cpu_id = system::cpus::CpuInfo::cpu_id();
-        let slab = &mut self.get_slabs_mut()[cpu_id];
-        slab.push(item)
+        let cpu = self.cpu();
+        if cpu.len() >= self.watermarks.high {
+            return Err(item);
+        }
+        self.debug_free(&item, Location::caller());
+        cpu.free(item)
     }
 
+    /// Makes sure this CPU has objects to allocate, preferably by swapping
+    /// an empty magazine for a full one from the depot, and otherwise by
+    /// filling it up to its low watermark from the backing allocator, under a
+    /// single lock. Returns `false` if the backing allocator ran out.
+    ///
+    /// The backing allocator may wait while this CPU frees, or move the task
+    /// to another CPU, so each object goes to whichever CPU this is running on
+    /// by then. Objects that would take it past its low watermark go to the
+    /// depot instead.
     pub async fn restock(&self) -> bool {
-        let cpu_id = system::cpus::CpuInfo::cpu_id();
-        let slab = &mut self.get_slabs_mut()[cpu_id];
-        for _ in 0..N - slab.len() {
-            if let Some(item) = self.alloc.lock().await.alloc().await {
-                let _ = slab.push(item);
-            } else {
-                return false;
-            }
+        if self.exchange_full().await {
+            return true;
         }
-        true
+        let mut alloc = self.alloc.lock().await;
+        let count = self.watermarks.low.saturating_sub(self.cpu().len());
+        let mut surplus: Vec<Box<Magazine<A::Item, N>>> = Vec::new();
+        let allocated = alloc
+            .alloc_bulk(count, |mut item| {
+                self.construct(&mut item);
+                let cpu = self.cpu();
+                if cpu.len() < self.watermarks.low {
+                    if cpu.free(item).is_err() {
+                        unreachable!("restocked past the low watermark");
+                    }
+                    return;
+                }
+                if surplus.last().is_none_or(|magazine| magazine.is_full()) {
+                    surplus.push(Box::default());
+                }
+                if surplus.last_mut().unwrap().push(item).is_err() {
+                    unreachable!("pushed to a full magazine");
+                }
+            })
+            .await;
+        drop(alloc);
+        if !surplus.is_empty() {
+            self.depot.lock().await.full.append(&mut surplus);
+        }
+        allocated == count
     }
 
-    pub async fn alloc_restocking(&self) -> Option<A::Item> {
-        if let Some(item) = self.alloc() {
+    #[track_caller]
+    pub fn alloc_restocking(&self) -> impl Future<Output = Option<A::Item>> + '_ {
+        let caller = Location::caller();
+        async move {
+            let item = match self.cpu().alloc() {
+                Some(item) => item,
+                None => {
+                    self.restock().await;
+                    self.cpu().alloc()?
+                }
+            };
+            self.debug_alloc(&item, caller);
             Some(item)
-        } else {
-            self.restock().await;
-            self.alloc()
         }
     }
 
-    pub async fn alloc_shortcircuiting(&self) -> Option<A::Item> {
-        if let Some(item) = self.alloc() {
+    #[track_caller]
+    pub fn alloc_shortcircuiting(&self) -> impl Future<Output = Option<A::Item>> + '_ {
+        let caller = Location::caller();
+        async move {
+            let item = match self.cpu().alloc() {
+                Some(item) => item,
+                None if self.exchange_full().await => self.cpu().alloc()?,
+                None => self.alloc_constructed().await?,
+            };
+            self.debug_alloc(&item, caller);
             Some(item)
-        } else {
-            self.alloc.lock().await.alloc().await
         }
     }
 
+    /// Allocates up to `count` objects, handing each to `push`, and returns
+    /// how many were allocated. Whatever the magazines can't provide comes
+    /// from the backing allocator under a single lock.
+    #[track_caller]
+    pub fn alloc_bulk<'a>(
+        &'a self,
+        count: usize,
+        mut push: impl FnMut(A::Item) + 'a,
+    ) -> impl Future<Output = usize> + 'a {
+        let caller = Location::caller();
+        async move {
+            let mut allocated = 0;
+            while allocated < count {
+                let item = match self.cpu().alloc() {
+                    Some(item) => item,
+                    None if self.exchange_full().await => continue,
+                    None => break,
+                };
+                self.debug_alloc(&item, caller);
+                push(item);
+                allocated += 1;
+            }
+            if allocated < count {
+                let mut alloc = self.alloc.lock().await;
+                allocated += alloc
+                    .alloc_bulk(count - allocated, |mut item| {
+                        self.construct(&mut item);
+                        self.debug_alloc(&item, caller);
+                        push(item);
+                    })
+                    .await;
+            }
+            allocated
+        }
+    }
+
+    /// Gives every object held by the depot back to the backing allocator,
+    /// and frees the depot's empty magazines. Returns the number of objects
+    /// released. Objects in the per-CPU magazines are left alone.
+    pub async fn reap(&self) -> usize {
+        let (full, empty) = {
+            let mut depot = self.depot.lock().await;
+            (
+                core::mem::take(&mut depot.full),
+                core::mem::take(&mut depot.empty),
+            )
+        };
+        drop(empty);
+        self.release(full).await
+    }
+
     pub async fn lock_alloc(&self) -> Lock<'_, A> {
         self.alloc.lock().await
     }
 
-    fn get_slabs(&self) -> &Vec<heapless::Vec<A::Item, N>> {
-        unsafe { &*self.slabs.get() }
+    /// Swaps this CPU's previous magazine for a full one from the depot, and
+    /// loads it. Returns `false` if the depot has no full magazines.
+    async fn exchange_full(&self) -> bool {
+        let mut depot = self.depot.lock().await;
+        let Some(full) = depot.full.pop() else {
+            return false;
+        };
+        let cpu = self.cpu();
+        let empty = core::mem::replace(&mut cpu.previous, full);
+        if empty.is_empty() {
+            depot.empty.push(empty);
+        } else {
+            // Only happens if this CPU freed in the meantime; keep the
+            // objects rather than losing them.
+            depot.full.push(empty);
+        }
+        drop(depot);
+        core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+        true
+    }
+
+    /// Frees into this CPU's magazines, flushing them to the depot first if
+    /// the CPU is at its high watermark. That can take more than one go if
+    /// this ran on another CPU after waiting for the depot.
+    async fn free_flushing(&self, item: A::Item) {
+        while self.cpu().len() >= self.watermarks.high {
+            self.flush().await;
+        }
+        if self.cpu().free(item).is_err() {
+            unreachable!("freed past the high watermark");
+        }
+    }
+
+    /// Moves this CPU's objects to the depot until it's down to its low
+    /// watermark, as whole magazines where possible.
+    async fn flush(&self) {
+        let mut depot = self.depot.lock().await;
+        let cpu = self.cpu();
+        let mut excess = cpu.len().saturating_sub(self.watermarks.low);
+        while excess > 0 {
+            if cpu.previous.is_empty() {
+                core::mem::swap(&mut cpu.loaded, &mut cpu.previous);
+            }
+            let empty = depot.empty.pop().unwrap_or_default();
+            let magazine = if cpu.previous.len() <= excess {
+                core::mem::replace(&mut cpu.previous, empty)
+            } else {
+                let mut magazine = empty;
+                let previous = &mut cpu.previous;
+                magazine.extend(core::iter::from_fn(|| previous.pop()).take(excess));
+                magazine
+            };
+            excess -= magazine.len();
+            depot.full.push(magazine);
+        }
+    }
+
+    /// Destructs the objects in `magazines` and gives them back to the
+    /// backing allocator, returning how many there were.
+    async fn release(&self, magazines: Vec<Box<Magazine<A::Item, N>>>) -> usize {
+        let released = magazines.iter().map(|magazine| magazine.len()).sum();
+        let items = magazines
+            .into_iter()
+            .flat_map(|magazine| *magazine)
+            .map(|mut item| {
+                if let Some(destructor) = self.destructor {
+                    destructor(&mut item);
+                }
+                item
+            });
+        self.alloc.lock().await.free_bulk(items).await;
+        released
+    }
+
+    async fn alloc_constructed(&self) -> Option<A::Item> {
+        let mut item = self.alloc.lock().await.alloc().await?;
+        self.construct(&mut item);
+        Some(item)
+    }
+    /// Gets an object fresh from the backing allocator ready for the cache.
+    fn construct(&self, item: &mut A::Item) {
+        if let Some(constructor) = self.constructor {
+            constructor(item);
+        }
+        #[cfg(feature = "debug")]
+        if let Some(memory) = A::debug_memory(item) {
+            if self.constructor.is_none() {
+                crate::debug::poison(Self::debug_object(memory));
+            }
+            if A::REDZONE > 0 {
+                let memory = unsafe { &mut *memory.as_ptr() };
+                crate::debug::fill_redzones(memory, A::REDZONE, crate::debug::REDZONE_FREE);
+            }
+        }
+    }
+
+    #[cfg(feature = "debug")]
+    fn debug_object<'a>(memory: NonNull<[u8]>) -> &'a mut [u8] {
+        let memory = unsafe { &mut *memory.as_ptr() };
+        let len = memory.len();
+        &mut memory[A::REDZONE..len - A::REDZONE]
     }
-    fn get_slabs_mut(&self) -> &mut Vec<heapless::Vec<A::Item, N>> {
-        unsafe { &mut *self.slabs.get() }
+    /// Checks an object that is being handed out: it must be marked free,
+    /// and must still be poisoned. Objects that should be zeroed are zeroed
+    /// again.
+    #[cfg(feature = "debug")]
+    fn debug_alloc(&self, item: &A::Item, caller: &Location) {
+        let Some(memory) = A::debug_memory(item) else {
+            return;
+        };
+        if A::REDZONE > 0 && !crate::debug::is_free(unsafe { memory.as_ref() }, A::REDZONE) {
+            panic!(
+                "{}: object at {:p} handed out while allocated, at {caller}",
+                self.name,
+                Self::debug_object(memory).as_ptr(),
+            );
+        }
+        if self.constructor.is_none() {
+            crate::debug::check_poison(self.name, Self::debug_object(memory), caller);
+            if A::ZEROED {
+                Self::debug_object(memory).fill(0);
+            }
+        }
+        if A::REDZONE > 0 {
+            let memory = unsafe { &mut *memory.as_ptr() };
+            crate::debug::fill_redzones(memory, A::REDZONE, crate::debug::REDZONE);
+        }
+    }
+    #[cfg(not(feature = "debug"))]
+    fn debug_alloc(&self, _item: &A::Item, _caller: &Location) {}
+    /// Checks an object that is being freed: it mustn't be free already, and
+    /// its redzones must be intact. It's poisoned and marked free until it's
+    /// handed out again.
+    #[cfg(feature = "debug")]
+    fn debug_free(&self, item: &A::Item, caller: &Location) {
+        let Some(memory) = A::debug_memory(item) else {
+            return;
+        };
+        let double_free = if A::REDZONE > 0 {
+            crate::debug::is_free(unsafe { memory.as_ref() }, A::REDZONE)
+        } else {
+            // Only a free object should be poisoned all the way through.
+            self.constructor.is_none() && crate::debug::is_poisoned(Self::debug_object(memory))
+        };
+        if double_free {
+            panic!(
+                "{}: double free of object at {:p}, at {caller}",
+                self.name,
+                Self::debug_object(memory).as_ptr(),
+            );
+        }
+        if A::REDZONE > 0 {
+            crate::debug::check_redzones(self.name, unsafe { memory.as_ref() }, A::REDZONE, caller);
+            let memory = unsafe { &mut *memory.as_ptr() };
+            crate::debug::fill_redzones(memory, A::REDZONE, crate::debug::REDZONE_FREE);
+        }
+        if self.constructor.is_none() {
+            crate::debug::poison(Self::debug_object(memory));
+        }
+    }
+    #[cfg(not(feature = "debug"))]
+    fn debug_free(&self, _item: &A::Item, _caller: &Location) {}
+
+    #[allow(clippy::mut_from_ref)]
+    fn cpu(&self) -> &mut CpuCache<A::Item, N> {
+        let cpu_id = system::cpus::CpuInfo::cpu_id();
+        unsafe { &mut (&mut *self.cpus.get())[cpu_id] }
     }
 }
@@ -0,0 +1,486 @@
+//! Tests for [`Slab`], over a backing allocator that hands out numbered
+//! objects and keeps everything it's given back.
+
+use alloc::vec::Vec;
+
+use super::{Alloc, Slab};
+use crate::test_util::{block_on, set_cpu};
+
+const N: usize = 4;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Object {
+    id: usize,
+    constructed: u32,
+    destructed: u32,
+}
+
+struct Backing {
+    allocated: usize,
+    limit: usize,
+    freed: Vec<Object>,
+    /// After handing out this many objects, the task carries on on this CPU,
+    /// as if it had been moved while waiting.
+    move_to: Option<(usize, usize)>,
+}
+impl Backing {
+    fn new() -> Self {
+        Self::with_limit(usize::MAX)
+    }
+    fn with_limit(limit: usize) -> Self {
+        Self {
+            allocated: 0,
+            limit,
+            freed: Vec::new(),
+            move_to: None,
+        }
+    }
+}
+impl Alloc for Backing {
+    type Item = Object;
+
+    async fn alloc(&mut self) -> Option<Object> {
+        if self.allocated == self.limit {
+            return None;
+        }
+        if let Some((after, cpu)) = self.move_to {
+            if self.allocated == after {
+                set_cpu(cpu);
+            }
+        }
+        self.allocated += 1;
+        Some(Object {
+            id: self.allocated,
+            constructed: 0,
+            destructed: 0,
+        })
+    }
+    async fn free(&mut self, object: Object) {
+        self.freed.push(object);
+    }
+}
+
+fn construct(object: &mut Object) {
+    object.constructed += 1;
+}
+fn destruct(object: &mut Object) {
+    object.destructed += 1;
+}
+
+fn slab(cpus: usize) -> Slab<Backing, N> {
+    Slab::new_for_cpus("test", Backing::new(), cpus)
+        .with_constructor(construct)
+        .with_destructor(destruct)
+}
+
+fn alloc(slab: &Slab<Backing, N>, count: usize) -> Vec<Object> {
+    (0..count)
+        .map(|_| block_on(slab.alloc_restocking()).unwrap())
+        .collect()
+}
+
+fn backing_allocated(slab: &Slab<Backing, N>) -> usize {
+    block_on(slab.lock_alloc()).allocated
+}
+
+/// (loaded, previous) magazine lengths of the current CPU.
+fn cpu_lens(slab: &Slab<Backing, N>) -> (usize, usize) {
+    let cpu = slab.cpu();
+    (cpu.loaded.len(), cpu.previous.len())
+}
+
+/// (full, empty) magazine counts in the depot.
+fn depot_lens(slab: &Slab<Backing, N>) -> (usize, usize) {
+    let depot = block_on(slab.depot.lock());
+    (depot.full.len(), depot.empty.len())
+}
+
+#[test]
+fn round_trips() {
+    set_cpu(0);
+    let slab = slab(1);
+    let count = 3 * N + 1;
+
+    let mut objects = alloc(&slab, count);
+    let mut ids = objects.iter().map(|object| object.id).collect::<Vec<_>>();
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), count);
+    // Restocking fills whole magazines.
+    assert_eq!(backing_allocated(&slab), 4 * N);
+
+    block_on(slab.free_bulk(objects.drain(..)));
+    assert_eq!(cpu_lens(&slab), (N, N));
+    assert_eq!(depot_lens(&slab).0, 2);
+
+    // Everything comes back out of the magazines and the depot.
+    let objects = alloc(&slab, 4 * N);
+    assert_eq!(backing_allocated(&slab), 4 * N);
+    assert_eq!(depot_lens(&slab).0, 0);
+    let mut ids = objects.iter().map(|object| object.id).collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, (1..=4 * N).collect::<Vec<_>>());
+}
+
+#[test]
+fn alternating_at_magazine_boundary() {
+    set_cpu(0);
+    let slab = slab(1);
+    let objects = alloc(&slab, N);
+    let mut extra = block_on(slab.alloc_shortcircuiting()).unwrap();
+    block_on(slab.free_bulk(objects));
+    assert_eq!(cpu_lens(&slab), (N, 0));
+
+    // Freeing into a full loaded magazine swaps in the empty previous one...
+    for _ in 0..10 {
+        block_on(slab.free(extra));
+        extra = slab.alloc().unwrap();
+    }
+    assert_eq!(cpu_lens(&slab), (0, N));
+    // ...and allocating from an empty one swaps the full one back.
+    for _ in 0..10 {
+        let object = slab.alloc().unwrap();
+        block_on(slab.free(object));
+    }
+    assert_eq!(cpu_lens(&slab), (N, 0));
+
+    // Neither needs the depot.
+    assert_eq!(depot_lens(&slab), (0, 0));
+    assert_eq!(backing_allocated(&slab), N + 1);
+    block_on(slab.free(extra));
+}
+
+#[test]
+fn exchange_when_both_empty() {
+    set_cpu(0);
+    let slab = slab(2);
+    assert!(!block_on(slab.exchange_full()));
+    assert!(slab.alloc().is_none());
+
+    // CPU 1 frees enough to pass a full magazine to the depot.
+    set_cpu(1);
+    let objects = alloc(&slab, 3 * N);
+    block_on(slab.free_bulk(objects));
+    assert_eq!(depot_lens(&slab), (1, 0));
+
+    set_cpu(0);
+    assert!(block_on(slab.exchange_full()));
+    assert_eq!(cpu_lens(&slab), (N, 0));
+    assert_eq!(depot_lens(&slab), (0, 1));
+}
+
+#[test]
+fn exchange_when_both_full() {
+    set_cpu(0);
+    let slab = slab(1);
+    let objects = alloc(&slab, 2 * N);
+    let extra = block_on(slab.alloc_shortcircuiting()).unwrap();
+    block_on(slab.free_bulk(objects));
+    assert_eq!(cpu_lens(&slab), (N, N));
+    assert_eq!(depot_lens(&slab), (0, 0));
+
+    block_on(slab.free(extra));
+    assert_eq!(cpu_lens(&slab), (1, N));
+    assert_eq!(depot_lens(&slab), (1, 0));
+    assert_eq!(block_on(slab.depot.lock()).full[0].len(), N);
+}
+
+#[test]
+fn constructor_and_destructor() {
+    set_cpu(0);
+    let slab = slab(1);
+    let mut objects = alloc(&slab, 3 * N);
+    objects.extend(block_on(slab.alloc_shortcircuiting()));
+    for object in &objects {
+        assert_eq!((object.constructed, object.destructed), (1, 0));
+    }
+
+    // Going round the cache again doesn't construct anything twice.
+    block_on(slab.free_bulk(objects));
+    for object in alloc(&slab, 3 * N) {
+        assert_eq!((object.constructed, object.destructed), (1, 0));
+        block_on(slab.free(object));
+    }
+
+    let released = block_on(slab.reap());
+    let backing = block_on(slab.lock_alloc());
+    assert_eq!(released, 2 * N);
+    assert_eq!(backing.freed.len(), released);
+    for object in &backing.freed {
+        assert_eq!((object.constructed, object.destructed), (1, 1));
+    }
+}
+
+#[test]
+fn alloc_shortcircuiting() {
+    set_cpu(0);
+    let slab = Slab::<_, N>::new_for_cpus("test", Backing::with_limit(4 * N), 2);
+
+    // Straight from the backing allocator, without filling a magazine.
+    let object = block_on(slab.alloc_shortcircuiting()).unwrap();
+    assert_eq!(backing_allocated(&slab), 1);
+    assert!(slab.empty());
+    block_on(slab.free(object));
+
+    // From a full magazine in the depot, if there is one.
+    set_cpu(1);
+    let objects = alloc(&slab, 3 * N - 1);
+    block_on(slab.free_bulk(objects));
+    assert_eq!(depot_lens(&slab).0, 1);
+    set_cpu(0);
+    let allocated = backing_allocated(&slab);
+    slab.alloc().unwrap();
+    assert!(block_on(slab.alloc_shortcircuiting()).is_some());
+    assert_eq!(backing_allocated(&slab), allocated);
+    assert_eq!(depot_lens(&slab).0, 0);
+    assert_eq!(cpu_lens(&slab), (N - 1, 0));
+
+    // Nothing, once the backing allocator runs out.
+    set_cpu(1);
+    while slab.alloc().is_some() {}
+    while block_on(slab.alloc_shortcircuiting()).is_some() {}
+    assert_eq!(backing_allocated(&slab), 4 * N);
+}
+
+#[test]
+fn alloc_bulk_and_free_bulk() {
+    set_cpu(0);
+    let slab = Slab::<_, N>::new_for_cpus("test", Backing::with_limit(4 * N), 1)
+        .with_constructor(construct);
+
+    // Straight from the backing allocator when the magazines are empty.
+    let mut objects = Vec::new();
+    assert_eq!(
+        block_on(slab.alloc_bulk(3 * N, |object| objects.push(object))),
+        3 * N
+    );
+    assert_eq!(backing_allocated(&slab), 3 * N);
+    assert!(slab.empty());
+    for object in &objects {
+        assert_eq!(object.constructed, 1);
+    }
+
+    block_on(slab.free_bulk(objects.drain(..)));
+    assert_eq!(cpu_lens(&slab), (N, N));
+    assert_eq!(depot_lens(&slab).0, 1);
+
+    // From the magazines and the depot first, then the backing allocator
+    // until it runs out.
+    let allocated = block_on(slab.alloc_bulk(5 * N, |object| objects.push(object)));
+    assert_eq!(allocated, 4 * N);
+    assert_eq!(objects.len(), 4 * N);
+    assert_eq!(backing_allocated(&slab), 4 * N);
+    assert_eq!(depot_lens(&slab).0, 0);
+}
+
+#[test]
+fn flushes_down_to_low_watermark() {
+    set_cpu(0);
+    let slab = slab(1).with_watermarks(1, 2 * N - 1);
+    let mut objects = Vec::new();
+    block_on(slab.alloc_bulk(2 * N, |object| objects.push(object)));
+
+    let last = objects.pop().unwrap();
+    block_on(slab.free_bulk(objects));
+    assert_eq!(cpu_lens(&slab), (N - 1, N));
+    assert_eq!(depot_lens(&slab), (0, 0));
+
+    // All but one object goes, a whole magazine and part of another.
+    block_on(slab.free(last));
+    assert_eq!(slab.cpu().len(), 2);
+    let depot = block_on(slab.depot.lock());
+    let lens = depot.full.iter().map(|magazine| magazine.len());
+    assert_eq!(lens.collect::<Vec<_>>(), [N, N - 2]);
+}
+
+#[test]
+fn full_cpu_flushes_half() {
+    set_cpu(0);
+    let slab = slab(1);
+    let mut objects = Vec::new();
+    block_on(slab.alloc_bulk(3 * N + 1, |object| objects.push(object)));
+    let rest = objects.split_off(2 * N + 1);
+    block_on(slab.free_bulk(objects));
+    assert_eq!(cpu_lens(&slab), (1, N));
+    assert_eq!(depot_lens(&slab), (1, 0));
+
+    // free_nolock stops at the high watermark instead.
+    let rejected = rest
+        .into_iter()
+        .filter_map(|object| slab.free_nolock(object).err());
+    assert_eq!(rejected.count(), 1);
+    assert_eq!(cpu_lens(&slab), (N, N));
+}
+
+#[test]
+fn restocks_up_to_low_watermark() {
+    set_cpu(0);
+    let narrow = slab(1).with_watermarks(N / 2, 2 * N);
+    block_on(narrow.alloc_restocking()).unwrap();
+    assert_eq!(backing_allocated(&narrow), N / 2);
+    assert_eq!(narrow.cpu().len(), N / 2 - 1);
+
+    // Past one magazine, the previous one is filled too.
+    let wide = slab(1).with_watermarks(N + 2, 2 * N);
+    block_on(wide.alloc_restocking()).unwrap();
+    assert_eq!(backing_allocated(&wide), N + 2);
+    assert_eq!(wide.cpu().len(), N + 1);
+}
+
+/// Restocking waits on the backing allocator, and may carry on on another
+/// CPU. Each object goes to the CPU it's running on by then, and once that's
+/// at its low watermark, the rest go to the depot.
+#[test]
+fn restock_after_moving_cpus() {
+    set_cpu(1);
+    let mut backing = Backing::new();
+    backing.move_to = Some((N / 2, 1));
+    let slab = Slab::new_for_cpus("test", backing, 2).with_constructor(construct);
+    let objects = (0..N).map(|id| Object {
+        id: 1000 + id,
+        constructed: 1,
+        destructed: 0,
+    });
+    block_on(slab.free_bulk(objects));
+
+    set_cpu(0);
+    assert!(block_on(slab.restock()));
+    assert_eq!(backing_allocated(&slab), N);
+    assert_eq!(slab.cpu().len(), N);
+    assert_eq!(depot_lens(&slab), (1, 0));
+    set_cpu(0);
+    assert_eq!(slab.cpu().len(), N / 2);
+}
+
+#[test]
+#[should_panic(expected = "0 < low < high")]
+fn low_watermark_below_high() {
+    slab(1).with_watermarks(N, N);
+}
+
+#[test]
+#[should_panic(expected = "high watermark above")]
+fn high_watermark_within_magazines() {
+    slab(1).with_watermarks(N, 2 * N + 1);
+}
+
+/// The `debug` feature's checks, over objects on the heap.
+#[cfg(feature = "debug")]
+mod debug {
+    use alloc::{boxed::Box, vec};
+    use core::ptr::NonNull;
+
+    use super::super::{Alloc, Slab};
+    use crate::test_util::{block_on, set_cpu};
+
+    const SIZE: usize = 16;
+
+    struct Heap<const REDZONE: usize, const ZEROED: bool = false>;
+    impl<const REDZONE: usize, const ZEROED: bool> Alloc for Heap<REDZONE, ZEROED> {
+        type Item = NonNull<[u8]>;
+        const REDZONE: usize = REDZONE;
+        const ZEROED: bool = ZEROED;
+
+        async fn alloc(&mut self) -> Option<Self::Item> {
+            let memory = vec![0; SIZE + 2 * REDZONE].into_boxed_slice();
+            Some(NonNull::from(Box::leak(memory)))
+        }
+        async fn free(&mut self, item: Self::Item) {
+            drop(unsafe { Box::from_raw(item.as_ptr()) });
+        }
+
+        fn debug_memory(item: &Self::Item) -> Option<NonNull<[u8]>> {
+            Some(*item)
+        }
+    }
+
+    fn slab<const REDZONE: usize>() -> Slab<Heap<REDZONE>, 4> {
+        set_cpu(0);
+        Slab::new_for_cpus("debug", Heap, 1)
+    }
+
+    /// The whole of `item`'s memory, redzones and all.
+    #[allow(clippy::mut_from_ref)]
+    fn memory(item: &NonNull<[u8]>) -> &mut [u8] {
+        unsafe { &mut *item.as_ptr() }
+    }
+
+    #[test]
+    fn clean_round_trips() {
+        let slab = slab::<8>();
+        for _ in 0..3 {
+            let item = block_on(slab.alloc_restocking()).unwrap();
+            memory(&item)[8..8 + SIZE].fill(1);
+            block_on(slab.free(item));
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "written to after being freed")]
+    fn use_after_free() {
+        let slab = slab::<8>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        block_on(slab.free(item));
+        memory(&item)[8 + 3] = 0;
+        block_on(slab.alloc_restocking());
+    }
+
+    #[test]
+    #[should_panic(expected = "was overrun")]
+    fn overrun() {
+        let slab = slab::<8>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        memory(&item)[8 + SIZE] = 0;
+        block_on(slab.free(item));
+    }
+
+    #[test]
+    #[should_panic(expected = "was underrun")]
+    fn underrun() {
+        let slab = slab::<8>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        memory(&item)[7] = 0;
+        block_on(slab.free(item));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free() {
+        let slab = slab::<8>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        block_on(slab.free(item));
+        block_on(slab.free(item));
+    }
+
+    #[test]
+    #[should_panic(expected = "double free")]
+    fn double_free_without_redzones() {
+        let slab = slab::<0>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        block_on(slab.free(item));
+        block_on(slab.free(item));
+    }
+
+    #[test]
+    #[should_panic(expected = "handed out while allocated")]
+    fn handed_out_while_allocated() {
+        let slab = slab::<8>();
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        // Back in the cache without going through free.
+        slab.cpu().free(item).unwrap();
+        slab.alloc();
+    }
+
+    #[test]
+    fn zeroed_items_stay_zeroed() {
+        set_cpu(0);
+        let slab = Slab::<_, 4>::new_for_cpus("debug", Heap::<0, true>, 1);
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        assert!(memory(&item).iter().all(|&byte| byte == 0));
+        memory(&item).fill(1);
+        block_on(slab.free(item));
+        // Still poisoned while it was free, then zeroed on the way out.
+        let item = block_on(slab.alloc_restocking()).unwrap();
+        assert!(memory(&item).iter().all(|&byte| byte == 0));
+    }
+}
@@ -0,0 +1,52 @@
+//! Helpers shared by the tests of the allocators in this crate.
+
+extern crate std;
+
+use core::{
+    cell::Cell,
+    future::Future,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+std::thread_local! {
+    static CPU: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Makes the calling test thread look like it's running on `cpu`.
+pub fn set_cpu(cpu: usize) {
+    CPU.set(cpu);
+}
+
+/// The backend for `CpuInfo::cpu_id`, which tests choose with [`set_cpu`].
+#[no_mangle]
+fn cpu_id() -> usize {
+    CPU.get()
+}
+
+/// xorshift64*, so failures are reproducible from the seed alone.
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Self(seed.wrapping_mul(0x9e3779b97f4a7c15) | 1)
+    }
+    pub fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+    pub fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
@@ -4,11 +4,22 @@ use system::sync::Mutex;
 
 use self::{
     segment_list::SegmentList,
-    segment_queue::{allocation_table::AllocationTable, freelists::Freelists},
+    segment_queue::{allocation_table::AllocationTable, freelists::Freelists, SegmentQueue},
+    tag_pool::TagPool,
 };
 
 pub mod segment_list;
 pub mod segment_queue;
+pub mod tag_pool;
+
+#[cfg(test)]
+mod tests;
+
+/// The most quantum caches an arena can have, for allocations of one up to
+/// this many quanta.
+pub const QCACHE_MAX: usize = 8;
+/// How many free segments each quantum cache holds on to.
+pub const QCACHE_DEPTH: usize = 16;
 
 #[derive(Copy, Clone)]
 pub struct Link {
@@ -34,12 +45,54 @@ pub struct Bt {
     pub segment_queue: MaybeUninit<Link>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpanError {
+    /// The span overlaps one that is already in the arena.
+    Overlapping,
+    /// No span matches the given range.
+    NotFound,
+    /// The span still has live allocations.
+    InUse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmemError {
+    /// The address isn't the start of an allocation in this arena.
+    NotAllocated,
+    /// The address lies in free space, most likely because it was already
+    /// freed.
+    DoubleFree,
+    /// The size passed to `free` doesn't match the allocation.
+    SizeMismatch {
+        allocated: usize,
+        freed: usize,
+    },
+    /// No free segment is large enough.
+    Exhausted,
+    /// Part of the range asked for is allocated, or outside of every span.
+    Unavailable,
+    InvalidSpan(SpanError),
+    /// Spans can only be borrowed from a parent arena.
+    NoParent,
+    ParentAlreadySet,
+}
+impl From<SpanError> for VmemError {
+    fn from(err: SpanError) -> Self {
+        Self::InvalidSpan(err)
+    }
+}
+
 pub enum AllocPolicy {
     InstantFit,
     BestFit,
     NextFit,
 }
 
+/// A vmem arena.
+///
+/// Every operation that can fail has a `try_` variant returning a
+/// [`VmemError`]; the plain variants panic instead (except for `alloc`, which
+/// returns `None` when the arena is exhausted).
 pub struct Vmem<'src> {
     inner: Mutex<VmemInner<'src>>,
 }
@@ -51,73 +104,280 @@ impl<'src> Vmem<'src> {
             inner: Mutex::new(VmemInner::new(quantum)),
         }
     }
+    /// Caches freed segments of up to `count` quanta (at most
+    /// [`QCACHE_MAX`]), so that small allocations can skip splitting and
+    /// coalescing. [`reap`](Self::reap) gives them back.
+    pub fn with_quantum_caches(self, count: usize) -> Self {
+        // Nothing else can hold the lock, we own the arena.
+        unsafe { self.inner.get_unchecked_mut() }.set_quantum_caches(count);
+        self
+    }
+    /// Hands the arena `tags` to grow its allocation table into. Arenas that
+    /// allocate their own tags get this memory from the heap as they need it,
+    /// but ones that are only ever given tags through the `_ptr` calls have no
+    /// other source. `tags` must stay valid, and unused by anything else, for
+    /// as long as the arena.
+    pub async fn add_tags(&self, tags: NonNull<[MaybeUninit<Bt>]>) {
+        let mut inner = self.inner.lock().await;
+        inner.add_tags(tags);
+    }
 
+    /// Adds the span `[base, base + len)`, returning the arena so that calls
+    /// can be chained while setting it up.
+    ///
+    /// # Panics
+    /// If the span overlaps one the arena already has. Use
+    /// [`try_add_span`](Self::try_add_span) to get the error instead.
     pub async fn add_span(&self, base: usize, len: usize) -> &Vmem<'src> {
+        self.try_add_span(base, len).await.unwrap()
+    }
+    pub async fn try_add_span(&self, base: usize, len: usize) -> Result<&Vmem<'src>, VmemError> {
         let mut inner = self.inner.lock().await;
-        inner.add_span(base, len);
-        self
+        inner.add_span(base, len)?;
+        Ok(self)
     }
+    /// Like [`add_span`](Self::add_span), with the span's tags provided by
+    /// the caller.
+    ///
+    /// # Panics
+    /// If the span overlaps one the arena already has. Use
+    /// [`try_add_span_ptrs`](Self::try_add_span_ptrs) to get the error
+    /// instead.
     pub async fn add_span_ptrs(&self, base: usize, len: usize, ptrs: [NonNull<Bt>; 2]) {
+        self.try_add_span_ptrs(base, len, ptrs).await.unwrap()
+    }
+    pub async fn try_add_span_ptrs(
+        &self,
+        base: usize,
+        len: usize,
+        ptrs: [NonNull<Bt>; 2],
+    ) -> Result<(), VmemError> {
+        let mut inner = self.inner.lock().await;
+        inner.add_span_ptrs(base, len, ptrs)
+    }
+
+    pub async fn remove_span(&self, base: usize, len: usize) {
+        self.try_remove_span(base, len).await.unwrap()
+    }
+    pub async fn try_remove_span(&self, base: usize, len: usize) -> Result<(), VmemError> {
+        let mut inner = self.inner.lock().await;
+        inner.remove_span(base, len).await
+    }
+    pub async fn remove_span_func<Fn, Fut>(&self, base: usize, len: usize, free: Fn)
+    where
+        Fn: FnMut(NonNull<Bt>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.try_remove_span_func(base, len, free).await.unwrap()
+    }
+    pub async fn try_remove_span_func<Fn, Fut>(
+        &self,
+        base: usize,
+        len: usize,
+        free: Fn,
+    ) -> Result<(), VmemError>
+    where
+        Fn: FnMut(NonNull<Bt>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let mut inner = self.inner.lock().await;
+        inner.remove_span_func(base, len, free).await
+    }
+
+    pub async fn extend_span(&self, base: usize, len: usize) {
+        self.try_extend_span(base, len).await.unwrap()
+    }
+    pub async fn try_extend_span(&self, base: usize, len: usize) -> Result<(), VmemError> {
+        let mut inner = self.inner.lock().await;
+        inner.extend_span(base, len)
+    }
+    pub async fn extend_span_func<Fn>(&self, base: usize, len: usize, alloc: Fn)
+    where
+        Fn: FnOnce() -> NonNull<Bt>,
+    {
+        self.try_extend_span_func(base, len, alloc).await.unwrap()
+    }
+    pub async fn try_extend_span_func<Fn>(
+        &self,
+        base: usize,
+        len: usize,
+        alloc: Fn,
+    ) -> Result<(), VmemError>
+    where
+        Fn: FnOnce() -> NonNull<Bt>,
+    {
         let mut inner = self.inner.lock().await;
-        inner.add_span_ptrs(base, len, ptrs);
+        inner.extend_span_func(base, len, alloc)
     }
 
     pub async fn borrow_span(&self, base: usize, len: usize) -> &Vmem<'src> {
+        self.try_borrow_span(base, len).await.unwrap()
+    }
+    pub async fn try_borrow_span(&self, base: usize, len: usize) -> Result<&Vmem<'src>, VmemError> {
         let mut inner = self.inner.lock().await;
-        inner.borrow_span(base, len);
-        self
+        inner.borrow_span(base, len)?;
+        Ok(self)
     }
 
     pub async fn set_parent(&self, parent: &'src Vmem<'src>) -> &Vmem<'src> {
+        self.try_set_parent(parent).await.unwrap()
+    }
+    pub async fn try_set_parent(&self, parent: &'src Vmem<'src>) -> Result<&Vmem<'src>, VmemError> {
         let mut inner = self.inner.lock().await;
-        inner.set_parent(parent);
-        self
+        inner.set_parent(parent)?;
+        Ok(self)
     }
 
     pub async fn alloc(&self, len: usize, policy: AllocPolicy) -> Option<usize> {
+        self.try_alloc(len, policy).await.ok()
+    }
+    pub async fn try_alloc(&self, len: usize, policy: AllocPolicy) -> Result<usize, VmemError> {
         let mut inner = self.inner.lock().await;
         inner.alloc(policy, len)
     }
 
-    pub async fn free(&self, base: usize) {
+    /// Allocates exactly `[base, base + len)`, which must be free.
+    pub async fn alloc_at(&self, base: usize, len: usize) -> Option<usize> {
+        self.try_alloc_at(base, len).await.ok()
+    }
+    pub async fn try_alloc_at(&self, base: usize, len: usize) -> Result<usize, VmemError> {
+        let mut inner = self.inner.lock().await;
+        inner.alloc_at(base, len).await
+    }
+
+    pub async fn free(&self, base: usize, len: usize) {
+        self.try_free(base, len).await.unwrap()
+    }
+    pub async fn try_free(&self, base: usize, len: usize) -> Result<(), VmemError> {
         let mut inner = self.inner.lock().await;
-        inner.free(base).await;
+        inner.free(base, len).await
     }
-    pub async fn free_func<Fn, Fut>(&self, base: usize, free: Fn)
+    pub async fn free_func<Fn, Fut>(&self, base: usize, len: usize, free: Fn)
+    where
+        Fn: FnMut(NonNull<Bt>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        self.try_free_func(base, len, free).await.unwrap()
+    }
+    pub async fn try_free_func<Fn, Fut>(
+        &self,
+        base: usize,
+        len: usize,
+        free: Fn,
+    ) -> Result<(), VmemError>
     where
         Fn: FnMut(NonNull<Bt>) -> Fut,
         Fut: Future<Output = ()>,
     {
         let mut inner = self.inner.lock().await;
-        inner.free_func(base, free).await;
+        inner.free_func(base, len, free).await
+    }
+
+    /// Empties the quantum caches back into the arena. Returns the number of
+    /// segments released.
+    pub async fn reap(&self) -> usize {
+        let mut inner = self.inner.lock().await;
+        inner.reap().await
     }
 }
 
 struct VmemInner<'src> {
     segment_list: SegmentList,
     allocation_table: AllocationTable,
+    /// Where the allocation table's bucket arrays come from.
+    tag_pool: TagPool,
     freelists: Freelists,
     quantum: usize,
     parent: Option<&'src Vmem<'src>>,
     last: Option<NonNull<Bt>>,
+    /// Freed segments of one quantum, two quanta, and so on, still marked as
+    /// allocated.
+    qcaches: heapless::Vec<heapless::Vec<usize, QCACHE_DEPTH>, QCACHE_MAX>,
 }
 impl<'src> VmemInner<'src> {
     pub fn new(quantum: usize) -> Self {
         Self {
             segment_list: SegmentList::new(),
             allocation_table: AllocationTable::new(),
+            tag_pool: TagPool::new(),
             freelists: Freelists::new(),
             quantum,
             parent: None,
             last: None,
+            qcaches: heapless::Vec::new(),
         }
     }
 
+    pub fn set_quantum_caches(&mut self, count: usize) {
+        assert!(count <= QCACHE_MAX, "too many quantum caches");
+        self.qcaches.resize_default(count).unwrap();
+    }
+    fn qcache(&mut self, size: usize) -> Option<&mut heapless::Vec<usize, QCACHE_DEPTH>> {
+        self.qcaches.get_mut(size / self.quantum - 1)
+    }
+
     fn alloc_bt() -> NonNull<Bt> {
         unsafe { NonNull::new_unchecked(alloc::alloc::alloc(Layout::new::<Bt>()) as *mut Bt) }
     }
+    fn dealloc_bt(tag: NonNull<Bt>) {
+        unsafe {
+            tag.as_ptr().drop_in_place();
+            alloc::alloc::dealloc(tag.as_ptr() as *mut u8, Layout::new::<Bt>());
+        }
+    }
+    /// Makes sure the tag pool has room for the allocation table to grow, by
+    /// topping it up from the heap that [`alloc_bt`](Self::alloc_bt) takes
+    /// tags from. Only for the callers that allocate their own tags: arenas
+    /// that are handed theirs only grow into what [`add_tags`](Self::add_tags)
+    /// gave them.
+    fn reserve_buckets(&mut self) {
+        let Some(buckets) = self.allocation_table.next_growth() else {
+            return;
+        };
+        if self.tag_pool.has_room::<SegmentQueue>(buckets) {
+            return;
+        }
+        let slots = TagPool::slots::<SegmentQueue>(buckets);
+        let Ok(layout) = Layout::array::<Bt>(slots) else {
+            return;
+        };
+        let Some(tags) = NonNull::new(unsafe { alloc::alloc::alloc(layout) }) else {
+            return;
+        };
+        self.tag_pool
+            .add(NonNull::slice_from_raw_parts(tags.cast(), slots));
+    }
+    pub fn add_tags(&mut self, tags: NonNull<[MaybeUninit<Bt>]>) {
+        self.tag_pool.add(tags);
+    }
 
-    pub fn add_span(&mut self, base: usize, len: usize) {
+    fn is_span(tag: NonNull<Bt>) -> bool {
+        matches!(
+            unsafe { tag.as_ref() }.kind,
+            BtKind::Span | BtKind::ImportedSpan
+        )
+    }
+    fn spans(&self) -> impl Iterator<Item = NonNull<Bt>> {
+        self.segment_list.iter().filter(|&tag| Self::is_span(tag))
+    }
+    /// The segments belonging to `span`, in address order.
+    fn span_segments(&self, span: NonNull<Bt>) -> impl Iterator<Item = NonNull<Bt>> {
+        self.segment_list
+            .iter_from(span)
+            .skip(1)
+            .take_while(|&tag| !Self::is_span(tag))
+    }
+    fn overlaps_span(&self, base: usize, len: usize) -> bool {
+        self.spans().any(|span| {
+            let span = unsafe { span.as_ref() };
+            base < span.base + span.len && span.base < base + len
+        })
+    }
+
+    pub fn add_span(&mut self, base: usize, len: usize) -> Result<(), VmemError> {
+        if self.overlaps_span(base, len) {
+            return Err(SpanError::Overlapping.into());
+        }
         self.add_span_ptrs(base, len, [Self::alloc_bt(), Self::alloc_bt()])
     }
     pub fn add_span_ptrs(
@@ -125,7 +385,10 @@ impl<'src> VmemInner<'src> {
         base: usize,
         len: usize,
         [span, initial_segment]: [NonNull<Bt>; 2],
-    ) {
+    ) -> Result<(), VmemError> {
+        if self.overlaps_span(base, len) {
+            return Err(SpanError::Overlapping.into());
+        }
         unsafe {
             *span.as_ptr() = Bt {
                 kind: BtKind::Span,
@@ -156,14 +419,127 @@ impl<'src> VmemInner<'src> {
         self.segment_list.add(span);
         self.segment_list.add(initial_segment);
         self.freelists.insert(initial_segment, self.quantum);
+        Ok(())
     }
 
-    pub fn borrow_span(&mut self, base: usize, len: usize) {
+    pub async fn remove_span(&mut self, base: usize, len: usize) -> Result<(), VmemError> {
+        self.remove_span_func(base, len, |tag| async move { Self::dealloc_bt(tag) })
+            .await
+    }
+    /// Removes the span `[base, base + len)` from the arena, handing its tags
+    /// to `free`. Fails if any part of the span is still allocated.
+    pub async fn remove_span_func<Fn, Fut>(
+        &mut self,
+        base: usize,
+        len: usize,
+        mut free: Fn,
+    ) -> Result<(), VmemError>
+    where
+        Fn: FnMut(NonNull<Bt>) -> Fut,
+        Fut: core::future::Future,
+    {
+        let span = self
+            .spans()
+            .find(|&span| {
+                let span = unsafe { span.as_ref() };
+                span.base == base && span.len == len
+            })
+            .ok_or(VmemError::InvalidSpan(SpanError::NotFound))?;
+        // Cached segments aren't really in use.
+        self.reap_func(base, len, &mut free).await;
+        if self
+            .span_segments(span)
+            .any(|tag| unsafe { tag.as_ref() }.kind == BtKind::Used)
+        {
+            return Err(SpanError::InUse.into());
+        }
+        for tag in self.span_segments(span) {
+            self.freelists.remove(tag, self.quantum);
+            self.segment_list.remove(tag);
+            free(tag).await;
+        }
+        self.segment_list.remove(span);
+        free(span).await;
+        Ok(())
+    }
+
+    pub fn extend_span(&mut self, base: usize, len: usize) -> Result<(), VmemError> {
+        self.extend_span_func(base, len, Self::alloc_bt)
+    }
+    /// Grows the span ending at `base` by `len`. The new range is merged into
+    /// the span's trailing free segment if it has one; otherwise a new free
+    /// segment is created with a tag from `alloc`.
+    pub fn extend_span_func<Fn>(
+        &mut self,
+        base: usize,
+        len: usize,
+        alloc: Fn,
+    ) -> Result<(), VmemError>
+    where
+        Fn: FnOnce() -> NonNull<Bt>,
+    {
+        let mut span = self
+            .spans()
+            .find(|&span| {
+                let span = unsafe { span.as_ref() };
+                span.base + span.len == base
+            })
+            .ok_or(VmemError::InvalidSpan(SpanError::NotFound))?;
+        if self.overlaps_span(base, len) {
+            return Err(SpanError::Overlapping.into());
+        }
+        unsafe { span.as_mut() }.len += len;
+
+        let last = self.span_segments(span).last().unwrap_or(span);
+        if unsafe { last.as_ref() }.kind == BtKind::Free {
+            let mut last = last;
+            self.freelists.remove(last, self.quantum);
+            unsafe { last.as_mut() }.len += len;
+            self.freelists.insert(last, self.quantum);
+            return Ok(());
+        }
+
+        let segment = alloc();
+        unsafe {
+            *segment.as_ptr() = Bt {
+                kind: BtKind::Free,
+                base,
+                len,
+                segment_list: Link {
+                    next: None,
+                    prev: None,
+                },
+                segment_queue: MaybeUninit::new(Link {
+                    next: None,
+                    prev: None,
+                }),
+            };
+        }
+        self.segment_list.insert_after(segment, last);
+        self.freelists.insert(segment, self.quantum);
+        Ok(())
+    }
+
+    pub fn borrow_span(&mut self, base: usize, len: usize) -> Result<(), VmemError> {
+        if self.parent.is_none() {
+            return Err(VmemError::NoParent);
+        }
+        if self.overlaps_span(base, len) {
+            return Err(SpanError::Overlapping.into());
+        }
         self.borrow_span_ptr(base, len, Self::alloc_bt())
     }
-    pub fn borrow_span_ptr(&mut self, base: usize, len: usize, span: NonNull<Bt>) {
+    pub fn borrow_span_ptr(
+        &mut self,
+        base: usize,
+        len: usize,
+        span: NonNull<Bt>,
+    ) -> Result<(), VmemError> {
         if self.parent.is_none() {
-            panic!("Attempting to borrow span from vmem with no parent");
+            return Err(VmemError::NoParent);
+        }
+        if self.overlaps_span(base, len) {
+            return Err(SpanError::Overlapping.into());
         }
         unsafe {
             *span.as_ptr() = Bt {
@@ -178,49 +554,117 @@ impl<'src> VmemInner<'src> {
             };
         }
         self.segment_list.add(span);
+        Ok(())
     }
 
-    pub fn set_parent(&mut self, parent: &'src Vmem<'src>) {
+    pub fn set_parent(&mut self, parent: &'src Vmem<'src>) -> Result<(), VmemError> {
         if self.parent.is_some() {
-            panic!("Attempting to change existing parent of vmem");
+            return Err(VmemError::ParentAlreadySet);
         }
         self.parent = Some(parent);
+        Ok(())
     }
 
-    pub fn alloc(&mut self, policy: AllocPolicy, size: usize) -> Option<usize> {
-        self.alloc_ptr(policy, size, Self::alloc_bt())
+    pub fn alloc(&mut self, policy: AllocPolicy, size: usize) -> Result<usize, VmemError> {
+        if let Some(base) = self
+            .qcache(self.round_up(size))
+            .and_then(|cache| cache.pop())
+        {
+            return Ok(base);
+        }
+        let new_tag = Self::alloc_bt();
+        self.reserve_buckets();
+        self.alloc_ptr(policy, size, new_tag)
+            .inspect_err(|_| Self::dealloc_bt(new_tag))
     }
+    /// Allocates `size` from the arena, using `new_tag` to describe the
+    /// allocation. If this fails, `new_tag` is left untouched.
     pub fn alloc_ptr(
         &mut self,
         policy: AllocPolicy,
         size: usize,
         new_tag: NonNull<Bt>,
-    ) -> Option<usize> {
-        let mut tag = match policy {
-            AllocPolicy::InstantFit => self.freelists.instant_fit(size, self.quantum)?,
-            AllocPolicy::BestFit => self.freelists.best_fit(size, self.quantum)?,
+    ) -> Result<usize, VmemError> {
+        let size = self.round_up(size);
+        self.find_fit(policy, size)
+            .map(|tag| self.split(tag, size, new_tag))
+            .ok_or(VmemError::Exhausted)
+    }
+    fn find_fit(&self, policy: AllocPolicy, size: usize) -> Option<NonNull<Bt>> {
+        match policy {
+            AllocPolicy::InstantFit => self.freelists.instant_fit(size, self.quantum),
+            AllocPolicy::BestFit => self.freelists.best_fit(size, self.quantum),
             AllocPolicy::NextFit => {
-                let next = match self.last {
-                    Some(last) => self
-                        .segment_list
-                        .next(last)
-                        .or_else(|| self.freelists.instant_fit(size, self.quantum))?,
-                    None => self.segment_list.first()?,
+                let fits = |&tag: &NonNull<Bt>| {
+                    let tag = unsafe { tag.as_ref() };
+                    tag.kind == BtKind::Free && tag.len >= size
                 };
-                let mut final_tag = None;
-                for tag in self.segment_list.iter_from(next) {
-                    if unsafe { tag.as_ref() }.len >= size {
-                        final_tag = Some(tag);
-                        break;
-                    }
-                }
-                final_tag?
+                let after_last = self.last.and_then(|last| self.segment_list.next(last));
+                after_last
+                    .and_then(|next| self.segment_list.iter_from(next).find(fits))
+                    .or_else(|| self.segment_list.iter().find(fits))
             }
-        };
+        }
+    }
+
+    /// Allocates exactly `[base, base + size)`. Cached segments in the range
+    /// are released first, since they aren't really in use.
+    pub async fn alloc_at(&mut self, base: usize, size: usize) -> Result<usize, VmemError> {
+        let size = self.round_up(size);
+        if !base.is_multiple_of(self.quantum) {
+            return Err(VmemError::Unavailable);
+        }
+        self.reap_func(base, size, |tag| async move { Self::dealloc_bt(tag) })
+            .await;
+        let mut tag = self
+            .segment_list
+            .iter()
+            .find(|&tag| {
+                let tag = unsafe { tag.as_ref() };
+                tag.kind == BtKind::Free
+                    && tag.base <= base
+                    && base
+                        .checked_add(size)
+                        .is_some_and(|end| end <= tag.base + tag.len)
+            })
+            .ok_or(VmemError::Unavailable)?;
+
+        let head_len = base - unsafe { tag.as_ref() }.base;
+        if head_len > 0 {
+            let head = Self::alloc_bt();
+            self.freelists.remove(tag, self.quantum);
+            let tag_mut = unsafe { tag.as_mut() };
+            unsafe {
+                *head.as_ptr() = Bt {
+                    kind: BtKind::Free,
+                    base: tag_mut.base,
+                    len: head_len,
+                    segment_list: Link {
+                        next: None,
+                        prev: None,
+                    },
+                    segment_queue: MaybeUninit::new(Link {
+                        next: None,
+                        prev: None,
+                    }),
+                };
+            }
+            tag_mut.base = base;
+            tag_mut.len -= head_len;
+            self.freelists.insert(tag, self.quantum);
+            self.segment_list.insert_before(head, tag);
+            self.freelists.insert(head, self.quantum);
+        }
+        self.reserve_buckets();
+        Ok(self.split(tag, size, Self::alloc_bt()))
+    }
+    fn split(&mut self, mut tag: NonNull<Bt>, size: usize, new_tag: NonNull<Bt>) -> usize {
+        self.freelists.remove(tag, self.quantum);
         let tag_mut = unsafe { tag.as_mut() };
         let base = tag_mut.base;
         tag_mut.base += size;
         tag_mut.len -= size;
+        self.freelists.insert(tag, self.quantum);
         unsafe {
             *new_tag.as_ptr() = Bt {
                 kind: BtKind::Used,
@@ -237,50 +681,127 @@ impl<'src> VmemInner<'src> {
             };
         }
         self.segment_list.insert_before(new_tag, tag);
-        self.allocation_table.insert(new_tag);
+        self.allocation_table.insert(new_tag, &mut self.tag_pool);
         self.last = Some(new_tag);
-        Some(base)
+        base
     }
 
-    pub async fn free(&mut self, base: usize) {
-        self.free_func(base, |tag| async move {
-            unsafe {
-                tag.as_ptr().drop_in_place();
-                alloc::alloc::dealloc(tag.as_ptr() as *mut u8, Layout::new::<Bt>());
+    pub async fn free(&mut self, base: usize, len: usize) -> Result<(), VmemError> {
+        let size = self.round_up(len);
+        let allocated = self
+            .allocation_table
+            .get(base)
+            .is_some_and(|tag| unsafe { tag.as_ref() }.len == size);
+        if let Some(cache) = self.qcache(size).filter(|_| allocated) {
+            if cache.contains(&base) {
+                return Err(VmemError::DoubleFree);
             }
-        })
-        .await;
+            if cache.push(base).is_ok() {
+                return Ok(());
+            }
+        }
+        self.free_func(base, len, |tag| async move { Self::dealloc_bt(tag) })
+            .await
     }
-    pub async fn free_func<Fn, Fut>(&mut self, base: usize, mut free: Fn)
+
+    pub async fn free_func<Fn, Fut>(
+        &mut self,
+        base: usize,
+        len: usize,
+        mut free: Fn,
+    ) -> Result<(), VmemError>
     where
         Fn: FnMut(NonNull<Bt>) -> Fut,
         Fut: core::future::Future,
     {
-        let mut tag = self.allocation_table.get(base).unwrap();
-        let tag_mut = unsafe { tag.as_mut() };
-        tag_mut.kind = BtKind::Free;
-        self.allocation_table.remove(tag);
-        for new_tag in self.segment_list.iter_from(tag).skip(1) {
-            let new_tag_ref = unsafe { new_tag.as_ref() };
-            if new_tag_ref.kind != BtKind::Free {
+        let Some(mut tag) = self.allocation_table.get(base) else {
+            return Err(self.unallocated_error(base));
+        };
+        let allocated = unsafe { tag.as_ref() }.len;
+        if allocated != self.round_up(len) {
+            return Err(VmemError::SizeMismatch {
+                allocated,
+                freed: len,
+            });
+        }
+        self.allocation_table.remove(tag, &mut self.tag_pool);
+        unsafe { tag.as_mut() }.kind = BtKind::Free;
+        if self.last == Some(tag) {
+            self.last = None;
+        }
+
+        while let Some(next) = self.segment_list.next(tag) {
+            let next_ref = unsafe { next.as_ref() };
+            if next_ref.kind != BtKind::Free {
                 break;
             }
-            tag_mut.len += new_tag_ref.len;
-            self.segment_list.remove(tag);
-            self.freelists.remove(tag, self.quantum);
-            free(tag).await;
+            unsafe { tag.as_mut() }.len += next_ref.len;
+            self.freelists.remove(next, self.quantum);
+            self.segment_list.remove(next);
+            free(next).await;
         }
-        for new_tag in self.segment_list.iter_from(tag).rev().skip(1) {
-            let new_tag_ref = unsafe { new_tag.as_ref() };
-            if new_tag_ref.kind != BtKind::Free {
+        while let Some(prev) = self.segment_list.prev(tag) {
+            let prev_ref = unsafe { prev.as_ref() };
+            if prev_ref.kind != BtKind::Free {
                 break;
             }
-            tag_mut.base -= new_tag_ref.len;
-            tag_mut.len += new_tag_ref.len;
-            self.segment_list.remove(tag);
-            self.freelists.remove(tag, self.quantum);
-            free(tag).await;
+            let tag_mut = unsafe { tag.as_mut() };
+            tag_mut.base = prev_ref.base;
+            tag_mut.len += prev_ref.len;
+            self.freelists.remove(prev, self.quantum);
+            self.segment_list.remove(prev);
+            free(prev).await;
         }
         self.freelists.insert(tag, self.quantum);
+        Ok(())
+    }
+
+    /// Empties the quantum caches back into the arena. Returns the number of
+    /// segments released.
+    pub async fn reap(&mut self) -> usize {
+        self.reap_func(0, usize::MAX, |tag| async move { Self::dealloc_bt(tag) })
+            .await
+    }
+    /// Releases the cached segments within `[base, base + len)`, handing the
+    /// tags this frees to `free`.
+    async fn reap_func<Fn, Fut>(&mut self, base: usize, len: usize, mut free: Fn) -> usize
+    where
+        Fn: FnMut(NonNull<Bt>) -> Fut,
+        Fut: core::future::Future,
+    {
+        let mut released = 0;
+        for index in 0..self.qcaches.len() {
+            let size = (index + 1) * self.quantum;
+            while let Some(position) = self.qcaches[index]
+                .iter()
+                .position(|&segment| segment.wrapping_sub(base) < len)
+            {
+                let segment = self.qcaches[index].swap_remove(position);
+                self.free_func(segment, size, &mut free)
+                    .await
+                    .expect("quantum cache held a segment that isn't allocated");
+                released += 1;
+            }
+        }
+        released
+    }
+
+    /// Rounds `size` up to a whole number of quanta (and at least one).
+    fn round_up(&self, size: usize) -> usize {
+        size.max(1).div_ceil(self.quantum) * self.quantum
+    }
+
+    /// Works out why `base` isn't in the allocation table. This walks the
+    /// whole segment list, but only runs when `free` is already failing.
+    fn unallocated_error(&self, base: usize) -> VmemError {
+        let freed = self.segment_list.iter().any(|tag| {
+            let tag = unsafe { tag.as_ref() };
+            tag.kind == BtKind::Free && tag.base <= base && base < tag.base + tag.len
+        });
+        if freed {
+            VmemError::DoubleFree
+        } else {
+            VmemError::NotAllocated
+        }
     }
 }
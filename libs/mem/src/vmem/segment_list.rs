@@ -60,12 +60,28 @@ impl SegmentList {
             self.head = Some(new);
         }
     }
+    pub fn insert_after(&mut self, mut new: NonNull<Bt>, mut old: NonNull<Bt>) {
+        let new_mut = unsafe { new.as_mut() };
+        let old_mut = unsafe { old.as_mut() };
+        new_mut.segment_list.prev = Some(old);
+        new_mut.segment_list.next = old_mut.segment_list.next;
+        if let Some(mut next) = old_mut.segment_list.next {
+            unsafe { next.as_mut() }.segment_list.prev = Some(new);
+        }
+        old_mut.segment_list.next = Some(new);
+        if self.tail == Some(old) {
+            self.tail = Some(new);
+        }
+    }
     pub fn first(&self) -> Option<NonNull<Bt>> {
         self.head
     }
     pub fn next(&self, bt: NonNull<Bt>) -> Option<NonNull<Bt>> {
         unsafe { bt.as_ref() }.segment_list.next
     }
+    pub fn prev(&self, bt: NonNull<Bt>) -> Option<NonNull<Bt>> {
+        unsafe { bt.as_ref() }.segment_list.prev
+    }
     pub fn iter(&self) -> SegmentListIter {
         SegmentListIter::new(self)
     }
@@ -1,42 +1,137 @@
 use core::ptr::NonNull;
 
-use crate::vmem::Bt;
+use crate::vmem::{tag_pool::TagPool, Bt};
 
 use super::SegmentQueue;
 
+/// Hash table of used segments, keyed by their base.
+///
+/// Starts out with an embedded array of [`Self::INITIAL_BUCKETS`] buckets, and
+/// rehashes into an array taken from the arena's [`TagPool`] whenever the load
+/// factor leaves `[1/2, 2]`. The table doesn't own that array: it goes back to
+/// the pool when the table rehashes again, and is left to it otherwise.
 pub struct AllocationTable {
-    pub buckets: [SegmentQueue; Self::BUCKETS],
+    initial: [SegmentQueue; Self::INITIAL_BUCKETS],
+    buckets: Option<NonNull<[SegmentQueue]>>,
+    len: usize,
+}
+impl Default for AllocationTable {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 impl AllocationTable {
-    pub const BUCKETS: usize = 64;
+    pub const INITIAL_BUCKETS: usize = 64;
+    /// Maximum average number of tags per bucket before the table grows.
+    pub const MAX_LOAD: usize = 2;
 
-    pub const fn new() -> Self {
+    const EMPTY: [SegmentQueue; Self::INITIAL_BUCKETS] = {
         const TEMP: SegmentQueue = SegmentQueue::new();
+        [TEMP; Self::INITIAL_BUCKETS]
+    };
+
+    pub const fn new() -> Self {
         Self {
-            buckets: [TEMP; Self::BUCKETS],
+            initial: Self::EMPTY,
+            buckets: None,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    pub fn num_buckets(&self) -> usize {
+        self.buckets().len()
+    }
+
+    fn buckets(&self) -> &[SegmentQueue] {
+        match self.buckets {
+            Some(buckets) => unsafe { buckets.as_ref() },
+            None => &self.initial,
+        }
+    }
+    fn buckets_mut(&mut self) -> &mut [SegmentQueue] {
+        match self.buckets {
+            Some(mut buckets) => unsafe { buckets.as_mut() },
+            None => &mut self.initial,
         }
     }
 
-    const fn get_bucket(n: usize) -> usize {
-        Self::murmur(n) % Self::BUCKETS
+    const fn get_bucket(n: usize, buckets: usize) -> usize {
+        Self::murmur(n) & (buckets - 1)
     }
 
-    pub fn insert(&mut self, bt: NonNull<Bt>) {
-        let bucket = Self::get_bucket(unsafe { bt.as_ref() }.base);
-        self.buckets[bucket].add(bt);
+    /// The number of buckets the next [`insert`](Self::insert) grows the
+    /// table to, if it does.
+    pub fn next_growth(&self) -> Option<usize> {
+        (self.len + 1 > self.num_buckets() * Self::MAX_LOAD).then(|| self.num_buckets() * 2)
     }
-    pub fn remove(&mut self, bt: NonNull<Bt>) {
-        let bucket = Self::get_bucket(unsafe { bt.as_ref() }.base);
-        self.buckets[bucket].remove(bt);
+
+    pub fn insert(&mut self, bt: NonNull<Bt>, pool: &mut TagPool) {
+        let buckets = self.buckets_mut();
+        let bucket = Self::get_bucket(unsafe { bt.as_ref() }.base, buckets.len());
+        buckets[bucket].add(bt);
+        self.len += 1;
+        if self.len > self.num_buckets() * Self::MAX_LOAD {
+            self.resize(self.num_buckets() * 2, pool);
+        }
+    }
+    pub fn remove(&mut self, bt: NonNull<Bt>, pool: &mut TagPool) {
+        let buckets = self.buckets_mut();
+        let bucket = Self::get_bucket(unsafe { bt.as_ref() }.base, buckets.len());
+        buckets[bucket].remove(bt);
+        self.len -= 1;
+        if self.num_buckets() > Self::INITIAL_BUCKETS && self.len < self.num_buckets() / 2 {
+            self.resize(self.num_buckets() / 2, pool);
+        }
     }
 
     pub fn get(&self, base: usize) -> Option<NonNull<Bt>> {
-        let bucket = Self::get_bucket(base);
-        self.buckets[bucket]
+        let buckets = self.buckets();
+        let bucket = Self::get_bucket(base, buckets.len());
+        buckets[bucket]
             .iter()
             .find(|&bt| unsafe { bt.as_ref() }.base == base)
     }
 
+    /// Moves every tag into a table of `new_len` buckets. If `pool` has no
+    /// room for the new bucket array, the table is left as it is; it still
+    /// works, just with longer chains.
+    fn resize(&mut self, new_len: usize, pool: &mut TagPool) {
+        let new = if new_len == Self::INITIAL_BUCKETS {
+            None
+        } else {
+            let Some(new) = pool.alloc::<SegmentQueue>(new_len) else {
+                return;
+            };
+            for i in 0..new_len {
+                unsafe { new.cast::<SegmentQueue>().add(i).write(SegmentQueue::new()) };
+            }
+            Some(new)
+        };
+        let old = self.buckets;
+        let mut moved = core::mem::replace(&mut self.initial, Self::EMPTY);
+        let old_buckets = match old {
+            Some(mut old) => unsafe { old.as_mut() },
+            None => &mut moved[..],
+        };
+        self.buckets = new;
+        let new_buckets = self.buckets_mut();
+        for queue in old_buckets.iter_mut() {
+            while let Some(bt) = queue.pop() {
+                let bucket = Self::get_bucket(unsafe { bt.as_ref() }.base, new_buckets.len());
+                new_buckets[bucket].add(bt);
+            }
+        }
+        if let Some(old) = old {
+            unsafe { pool.free(old) };
+        }
+    }
+
     #[cfg(target_pointer_width = "64")]
     const fn murmur(mut key: usize) -> usize {
         key ^= key >> 33;
@@ -4,9 +4,16 @@ use crate::vmem::Bt;
 
 use super::SegmentQueue;
 
+/// Power-of-two freelists: list `n` holds the free segments that are between
+/// `2^n` and `2^(n + 1)` quanta long.
 pub struct Freelists {
     lists: [SegmentQueue; Self::LISTS],
 }
+impl Default for Freelists {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 impl Freelists {
     #[cfg(target_pointer_width = "32")]
     pub const LISTS: usize = 32;
@@ -20,18 +27,13 @@ impl Freelists {
         }
     }
 
-    const fn get_list(size: usize) -> usize {
-        let po2 = size.next_power_of_two();
-        let list = po2.trailing_zeros() as usize;
-        list
+    /// The list a segment of `quanta` quanta belongs on.
+    const fn get_list(quanta: usize) -> usize {
+        quanta.ilog2() as usize
     }
 
     pub fn best_fit(&self, size: usize, quantum: usize) -> Option<NonNull<Bt>> {
-        let size = (size + (quantum - 1)) / quantum;
-        let mut list = Self::get_list(size);
-        if !size.is_power_of_two() {
-            list -= 1;
-        }
+        let list = Self::get_list(size.div_ceil(quantum));
         for list in &self.lists[list..] {
             if let Some(min) = list
                 .iter()
@@ -44,25 +46,34 @@ impl Freelists {
         None
     }
 
+    /// Takes the first segment from the smallest list whose segments are all
+    /// guaranteed to fit, without searching any list.
     pub fn instant_fit(&self, size: usize, quantum: usize) -> Option<NonNull<Bt>> {
-        let size = (size + (quantum - 1)) / quantum;
-        let list = Self::get_list(size);
-        for list in &self.lists[list..] {
-            if let Some(fit) = list.iter().next() {
-                return Some(fit);
-            }
-        }
-        None
+        let list = size.div_ceil(quantum).next_power_of_two().trailing_zeros() as usize;
+        self.lists
+            .get(list..)?
+            .iter()
+            .find_map(|list| list.iter().next())
     }
 
+    /// Free segments shorter than a quantum (such as the zero-length ones left
+    /// behind by exact-fit allocations) can never be allocated from, so they
+    /// are never put on a freelist; `insert` and `remove` ignore them.
     pub fn insert(&mut self, bt: NonNull<Bt>, quantum: usize) {
-        let size = (unsafe { bt.as_ref() }.len + (quantum - 1)) / quantum;
-        let list = Self::get_list(size);
-        self.lists[list].add(bt);
+        let quanta = unsafe { bt.as_ref() }.len / quantum;
+        if quanta == 0 {
+            return;
+        }
+        self.lists[Self::get_list(quanta)].add(bt);
     }
     pub fn remove(&mut self, bt: NonNull<Bt>, quantum: usize) {
-        let size = (unsafe { bt.as_ref() }.len + (quantum - 1)) / quantum;
-        let list = Self::get_list(size);
-        self.lists[list].remove(bt);
+        let quanta = unsafe { bt.as_ref() }.len / quantum;
+        if quanta == 0 {
+            return;
+        }
+        self.lists[Self::get_list(quanta)].remove(bt);
+    }
+    pub fn iter(&self) -> impl Iterator<Item = NonNull<Bt>> + '_ {
+        self.lists.iter().flat_map(|list| list.iter())
     }
 }
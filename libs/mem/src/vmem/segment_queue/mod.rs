@@ -77,7 +77,7 @@ impl SegmentQueue {
         }
         bt
     }
-    pub fn iter(&self) -> SegmentQueueIter {
+    pub fn iter(&self) -> SegmentQueueIter<'_> {
         SegmentQueueIter::new(self)
     }
 }
@@ -0,0 +1,100 @@
+use core::{
+    mem::{align_of, size_of, MaybeUninit},
+    ptr::NonNull,
+};
+
+use super::Bt;
+
+/// Spare memory for an arena's bookkeeping, kept as runs of boundary tag
+/// sized slots. The allocation table's bucket arrays come out of it.
+///
+/// The pool only hands out what it's been given: tags from
+/// [`Vmem::add_tags`](super::Vmem::add_tags), and, for arenas that allocate
+/// their own tags, memory from the same heap. Runs aren't merged when they
+/// come back, but the table only ever asks for the sizes it gave back on its
+/// way up.
+pub struct TagPool {
+    runs: Option<NonNull<Run>>,
+}
+/// A free run of slots, described in its first one.
+struct Run {
+    slots: usize,
+    next: Option<NonNull<Run>>,
+}
+impl Default for TagPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl TagPool {
+    pub const fn new() -> Self {
+        Self { runs: None }
+    }
+
+    /// The number of slots `len` `T`s take up.
+    pub const fn slots<T>(len: usize) -> usize {
+        (len * size_of::<T>()).div_ceil(size_of::<Bt>())
+    }
+
+    /// Adds the memory of `tags` to the pool. It must stay valid, and unused
+    /// by anything else, for as long as the pool.
+    pub fn add(&mut self, tags: NonNull<[MaybeUninit<Bt>]>) {
+        if tags.is_empty() {
+            return;
+        }
+        let run = tags.cast::<Run>();
+        unsafe {
+            run.as_ptr().write(Run {
+                slots: tags.len(),
+                next: self.runs,
+            })
+        };
+        self.runs = Some(run);
+    }
+
+    /// Whether some run has room for `len` `T`s.
+    pub fn has_room<T>(&self, len: usize) -> bool {
+        let slots = Self::slots::<T>(len);
+        self.iter()
+            .any(|run| unsafe { run.as_ref() }.slots >= slots)
+    }
+
+    /// Takes room for `len` `T`s from the end of the first run with enough.
+    pub fn alloc<T>(&mut self, len: usize) -> Option<NonNull<[T]>> {
+        const { assert!(align_of::<T>() <= align_of::<Bt>()) };
+        let slots = Self::slots::<T>(len).max(1);
+        let mut prev: Option<NonNull<Run>> = None;
+        for mut run in self.iter() {
+            let run_mut = unsafe { run.as_mut() };
+            if run_mut.slots < slots {
+                prev = Some(run);
+                continue;
+            }
+            let start = if run_mut.slots == slots {
+                match prev {
+                    Some(mut prev) => unsafe { prev.as_mut() }.next = run_mut.next,
+                    None => self.runs = run_mut.next,
+                }
+                run.cast::<Bt>()
+            } else {
+                run_mut.slots -= slots;
+                unsafe { run.cast::<Bt>().add(run_mut.slots) }
+            };
+            return Some(NonNull::slice_from_raw_parts(start.cast(), len));
+        }
+        None
+    }
+
+    /// Gives back memory from [`alloc`](Self::alloc).
+    ///
+    /// # Safety
+    /// `memory` must have come from this pool, and not be used again.
+    pub unsafe fn free<T>(&mut self, memory: NonNull<[T]>) {
+        let slots = Self::slots::<T>(memory.len()).max(1);
+        self.add(NonNull::slice_from_raw_parts(memory.cast(), slots));
+    }
+
+    fn iter(&self) -> impl Iterator<Item = NonNull<Run>> {
+        core::iter::successors(self.runs, |run| unsafe { run.as_ref() }.next)
+    }
+}
@@ -0,0 +1,477 @@
+//! Randomised tests for [`VmemInner`]: every allocation policy is run against
+//! random alloc/free sequences, checking the arena's internal invariants and
+//! comparing it against a simple reference allocator after every operation.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{mem::MaybeUninit, ptr::NonNull};
+
+use super::{
+    segment_queue::{allocation_table::AllocationTable, SegmentQueue},
+    tag_pool::TagPool,
+    AllocPolicy, Bt, BtKind, SpanError, VmemError, VmemInner,
+};
+use crate::test_util::{block_on, Rng};
+
+const QUANTUM: usize = 16;
+const SEEDS: u64 = 32;
+const OPS: usize = 1000;
+
+/// The simplest allocator that could possibly work: a list of spans and a map
+/// of allocations, with free space worked out on demand.
+#[derive(Default)]
+struct Reference {
+    spans: Vec<(usize, usize)>,
+    allocated: BTreeMap<usize, usize>,
+}
+impl Reference {
+    /// Maximal free runs, in address order.
+    fn free_runs(&self) -> Vec<(usize, usize)> {
+        let mut runs = Vec::new();
+        for &(base, len) in &self.spans {
+            let mut cursor = base;
+            for (&alloc_base, &alloc_len) in self.allocated.range(base..base + len) {
+                if alloc_base > cursor {
+                    runs.push((cursor, alloc_base - cursor));
+                }
+                cursor = alloc_base + alloc_len;
+            }
+            if cursor < base + len {
+                runs.push((cursor, base + len - cursor));
+            }
+        }
+        runs
+    }
+}
+
+fn tag(bt: NonNull<Bt>) -> &'static Bt {
+    unsafe { &*bt.as_ptr() }
+}
+
+fn check_invariants(vmem: &VmemInner, reference: &Reference) {
+    let mut used = BTreeMap::new();
+    let mut free = BTreeMap::new();
+
+    // Segment list order: each span is followed by segments that tile it
+    // exactly, in address order.
+    let mut list = vmem.segment_list.iter().peekable();
+    while let Some(span) = list.next() {
+        let span = tag(span);
+        assert!(
+            span.kind == BtKind::Span,
+            "segment at {:#x} is outside of any span",
+            span.base
+        );
+        let mut cursor = span.base;
+        let mut prev_free = false;
+        while let Some(&segment) = list.peek() {
+            let segment = tag(segment);
+            let is_free = match segment.kind {
+                BtKind::Free => true,
+                BtKind::Used => false,
+                _ => break,
+            };
+            list.next();
+            assert_eq!(segment.base, cursor, "segments out of order or overlapping");
+            cursor += segment.len;
+            // Full coalescing: no two neighbouring free segments.
+            assert!(
+                !(is_free && prev_free),
+                "uncoalesced free segments at {:#x}",
+                segment.base
+            );
+            prev_free = is_free;
+            if is_free {
+                if segment.len > 0 {
+                    free.insert(segment.base, segment.len);
+                }
+            } else {
+                used.insert(segment.base, segment.len);
+            }
+        }
+        assert_eq!(cursor, span.base + span.len, "segments don't cover span");
+    }
+
+    // Freelist membership: exactly the free segments that can be allocated
+    // from, each on the list for its size.
+    let mut on_freelists = BTreeMap::new();
+    for bt in vmem.freelists.iter() {
+        let bt = tag(bt);
+        assert!(bt.kind == BtKind::Free, "used segment on a freelist");
+        assert!(
+            on_freelists.insert(bt.base, bt.len).is_none(),
+            "segment on a freelist twice"
+        );
+    }
+    assert_eq!(on_freelists, free);
+
+    // Allocation table: exactly the used segments.
+    assert_eq!(vmem.allocation_table.len(), used.len());
+    for &base in used.keys() {
+        let bt = vmem
+            .allocation_table
+            .get(base)
+            .expect("used segment not hashed");
+        assert_eq!(tag(bt).base, base);
+    }
+
+    // Differential: same allocations and same free space as the reference.
+    assert_eq!(used, reference.allocated);
+    assert_eq!(free.into_iter().collect::<Vec<_>>(), reference.free_runs());
+}
+
+fn policy(i: usize) -> AllocPolicy {
+    match i {
+        0 => AllocPolicy::InstantFit,
+        1 => AllocPolicy::BestFit,
+        _ => AllocPolicy::NextFit,
+    }
+}
+
+fn run(seed: u64, policy_index: usize) {
+    let mut rng = Rng::new(seed);
+    let mut vmem = VmemInner::new(QUANTUM);
+    let mut reference = Reference::default();
+
+    for i in 0..1 + rng.below(4) {
+        let base = i * 0x100_0000 + rng.below(16) * QUANTUM;
+        let len = (1 + rng.below(4096)) * QUANTUM;
+        vmem.add_span(base, len).unwrap();
+        reference.spans.push((base, len));
+    }
+    check_invariants(&vmem, &reference);
+
+    let mut live = Vec::new();
+    for _ in 0..OPS {
+        if live.is_empty() || rng.below(8) < 5 {
+            // Mostly small sizes, with the occasional large or unaligned one.
+            let size = match rng.below(8) {
+                0 => 1 + rng.below(QUANTUM * 1024),
+                1 => 1 + rng.below(QUANTUM),
+                _ => (1 + rng.below(64)) * QUANTUM,
+            };
+            let rounded = size.div_ceil(QUANTUM) * QUANTUM;
+            let runs = reference.free_runs();
+            let needed = match policy_index {
+                0 => (rounded / QUANTUM).next_power_of_two() * QUANTUM,
+                _ => rounded,
+            };
+            match vmem.alloc(policy(policy_index), size) {
+                Ok(base) => {
+                    let &(_, run_len) = runs
+                        .iter()
+                        .find(|&&(run_base, _)| run_base == base)
+                        .expect("allocation not at the start of a free run");
+                    assert!(run_len >= needed, "allocated from a run that's too small");
+                    if policy_index == 1 {
+                        let best = runs
+                            .iter()
+                            .map(|&(_, len)| len)
+                            .filter(|&len| len >= rounded);
+                        assert_eq!(Some(run_len), best.min(), "best fit isn't the best");
+                    }
+                    reference.allocated.insert(base, rounded);
+                    live.push((base, size));
+                }
+                Err(err) => {
+                    assert_eq!(err, VmemError::Exhausted);
+                    assert!(
+                        runs.iter().all(|&(_, len)| len < needed),
+                        "failed to allocate {size:#x} with space available"
+                    );
+                }
+            }
+        } else {
+            let (base, size) = live.swap_remove(rng.below(live.len()));
+            if rng.below(16) == 0 {
+                assert!(matches!(
+                    block_on(vmem.free(base, size + QUANTUM)),
+                    Err(VmemError::SizeMismatch { .. })
+                ));
+            }
+            block_on(vmem.free(base, size)).unwrap();
+            reference.allocated.remove(&base);
+            if rng.below(16) == 0 {
+                assert_eq!(block_on(vmem.free(base, size)), Err(VmemError::DoubleFree));
+            }
+        }
+        check_invariants(&vmem, &reference);
+    }
+
+    for (base, size) in live.drain(..) {
+        block_on(vmem.free(base, size)).unwrap();
+        reference.allocated.remove(&base);
+    }
+    check_invariants(&vmem, &reference);
+    assert_eq!(vmem.freelists.iter().count(), reference.spans.len());
+}
+
+#[test]
+fn instant_fit() {
+    for seed in 0..SEEDS {
+        run(seed, 0);
+    }
+}
+
+#[test]
+fn best_fit() {
+    for seed in 0..SEEDS {
+        run(seed, 1);
+    }
+}
+
+#[test]
+fn next_fit() {
+    for seed in 0..SEEDS {
+        run(seed, 2);
+    }
+}
+
+#[test]
+fn free_errors() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    let base = vmem.alloc(AllocPolicy::InstantFit, 0x100).unwrap();
+    assert_eq!(
+        block_on(vmem.free(0x5000, 0x100)),
+        Err(VmemError::NotAllocated)
+    );
+    assert_eq!(
+        block_on(vmem.free(base + QUANTUM, 0x100)),
+        Err(VmemError::NotAllocated)
+    );
+    assert_eq!(
+        block_on(vmem.free(base, 0x80)),
+        Err(VmemError::SizeMismatch {
+            allocated: 0x100,
+            freed: 0x80
+        })
+    );
+    block_on(vmem.free(base, 0x100)).unwrap();
+    assert_eq!(block_on(vmem.free(base, 0x100)), Err(VmemError::DoubleFree));
+}
+
+#[test]
+fn quantum_caches() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    vmem.set_quantum_caches(2);
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    let small = vmem.alloc(AllocPolicy::InstantFit, QUANTUM).unwrap();
+    let large = vmem.alloc(AllocPolicy::InstantFit, 4 * QUANTUM).unwrap();
+
+    // Cached, so the next allocation of the same size gets it straight back.
+    block_on(vmem.free(small, QUANTUM)).unwrap();
+    assert_eq!(
+        block_on(vmem.free(small, QUANTUM)),
+        Err(VmemError::DoubleFree)
+    );
+    assert_eq!(vmem.alloc(AllocPolicy::BestFit, QUANTUM), Ok(small));
+    block_on(vmem.free(small, QUANTUM)).unwrap();
+
+    // Too large to be cached.
+    block_on(vmem.free(large, 4 * QUANTUM)).unwrap();
+    assert_eq!(block_on(vmem.reap()), 1);
+    assert_eq!(block_on(vmem.reap()), 0);
+    // Everything coalesced back into one segment.
+    assert_eq!(vmem.alloc(AllocPolicy::InstantFit, 0x1000), Ok(0x1000));
+    block_on(vmem.free(0x1000, 0x1000)).unwrap();
+
+    // A span with only cached segments left in it can be removed.
+    let small = vmem.alloc(AllocPolicy::InstantFit, 2 * QUANTUM).unwrap();
+    block_on(vmem.free(small, 2 * QUANTUM)).unwrap();
+    block_on(vmem.remove_span(0x1000, 0x1000)).unwrap();
+    assert_eq!(block_on(vmem.reap()), 0);
+}
+
+#[test]
+fn alloc_at() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    let mut reference = Reference::default();
+    reference.spans.push((0x1000, 0x1000));
+
+    // In the middle of the free space, then at either end of what's left.
+    for (base, len) in [(0x1400, 0x100), (0x1000, QUANTUM), (0x1f00, 0x100)] {
+        assert_eq!(block_on(vmem.alloc_at(base, len)), Ok(base));
+        reference.allocated.insert(base, len);
+        check_invariants(&vmem, &reference);
+    }
+    for (base, len) in [
+        (0x1480, 0x100),
+        (0x13f0, 0x20),
+        (0x1f80, 0x100),
+        (0x800, 0x100),
+    ] {
+        assert_eq!(
+            block_on(vmem.alloc_at(base, len)),
+            Err(VmemError::Unavailable)
+        );
+    }
+    assert_eq!(
+        block_on(vmem.alloc_at(0x1008, QUANTUM)),
+        Err(VmemError::Unavailable)
+    );
+    check_invariants(&vmem, &reference);
+
+    // Gives the right shape of hole back.
+    block_on(vmem.free(0x1400, 0x100)).unwrap();
+    reference.allocated.remove(&0x1400);
+    assert_eq!(block_on(vmem.alloc_at(0x1500, 0x200)), Ok(0x1500));
+    reference.allocated.insert(0x1500, 0x200);
+    check_invariants(&vmem, &reference);
+
+    // Cached segments count as free.
+    vmem.set_quantum_caches(1);
+    block_on(vmem.free(0x1000, QUANTUM)).unwrap();
+    reference.allocated.remove(&0x1000);
+    assert_eq!(block_on(vmem.alloc_at(0x1000, 2 * QUANTUM)), Ok(0x1000));
+    reference.allocated.insert(0x1000, 2 * QUANTUM);
+    check_invariants(&vmem, &reference);
+}
+
+#[test]
+fn overlapping_spans() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    let mut reference = Reference::default();
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    vmem.add_span(0x3000, 0x1000).unwrap();
+    reference.spans.extend([(0x1000, 0x1000), (0x3000, 0x1000)]);
+
+    let overlapping = Err(VmemError::InvalidSpan(SpanError::Overlapping));
+    assert_eq!(vmem.add_span(0x1800, 0x1000), overlapping);
+    assert_eq!(vmem.add_span(0x800, 0x900), overlapping);
+    assert_eq!(vmem.add_span(0x1000, 0x1000), overlapping);
+    assert_eq!(vmem.add_span(0, 0x5000), overlapping);
+    assert_eq!(vmem.extend_span(0x2000, 0x1001), overlapping);
+    check_invariants(&vmem, &reference);
+
+    // Touching is fine.
+    vmem.add_span(0x2000, 0x1000).unwrap();
+    reference.spans.insert(1, (0x2000, 0x1000));
+    check_invariants(&vmem, &reference);
+}
+
+#[test]
+fn remove_span_in_use() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    let mut reference = Reference::default();
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    vmem.add_span(0x4000, 0x1000).unwrap();
+    reference.spans.extend([(0x1000, 0x1000), (0x4000, 0x1000)]);
+    let base = block_on(vmem.alloc_at(0x1800, 0x100)).unwrap();
+    reference.allocated.insert(base, 0x100);
+
+    assert_eq!(
+        block_on(vmem.remove_span(0x1000, 0x1000)),
+        Err(VmemError::InvalidSpan(SpanError::InUse))
+    );
+    check_invariants(&vmem, &reference);
+
+    block_on(vmem.free(base, 0x100)).unwrap();
+    reference.allocated.remove(&base);
+    block_on(vmem.remove_span(0x1000, 0x1000)).unwrap();
+    reference.spans.remove(0);
+    check_invariants(&vmem, &reference);
+    assert_eq!(
+        block_on(vmem.remove_span(0x1000, 0x1000)),
+        Err(VmemError::InvalidSpan(SpanError::NotFound))
+    );
+}
+
+#[test]
+fn extend_span_merges_into_trailing_free_segment() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    let mut reference = Reference::default();
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    let base = block_on(vmem.alloc_at(0x1000, 0x800)).unwrap();
+    reference.spans.push((0x1000, 0x2000));
+    reference.allocated.insert(base, 0x800);
+
+    vmem.extend_span_func(0x2000, 0x1000, || unreachable!("new segment"))
+        .unwrap();
+    check_invariants(&vmem, &reference);
+    let free = tag(vmem.segment_list.tail.unwrap());
+    assert_eq!((free.base, free.len), (0x1800, 0x1800));
+
+    // An exact fit leaves an empty free segment behind, which is grown too.
+    let base = block_on(vmem.alloc_at(0x1800, 0x1800)).unwrap();
+    reference.allocated.insert(base, 0x1800);
+    vmem.extend_span_func(0x3000, 0x1000, || unreachable!("new segment"))
+        .unwrap();
+    reference.spans[0].1 += 0x1000;
+    check_invariants(&vmem, &reference);
+}
+
+#[test]
+fn extend_span_after_used_segment() {
+    let mut vmem = VmemInner::new(QUANTUM);
+    let mut reference = Reference::default();
+    vmem.add_span(0x1000, 0x1000).unwrap();
+    let base = block_on(vmem.alloc_at(0x1000, 0x1000)).unwrap();
+    reference.spans.push((0x1000, 0x2000));
+    reference.allocated.insert(base, 0x1000);
+    // Allocations always leave a free tail behind, if only an empty one, so
+    // take it away to get a span that ends in a used segment.
+    let tail = vmem.segment_list.tail.unwrap();
+    assert_eq!(tag(tail).len, 0);
+    vmem.segment_list.remove(tail);
+
+    let mut allocated = 0;
+    vmem.extend_span_func(0x2000, 0x1000, || {
+        allocated += 1;
+        VmemInner::alloc_bt()
+    })
+    .unwrap();
+    assert_eq!(allocated, 1);
+    check_invariants(&vmem, &reference);
+    let free = tag(vmem.segment_list.tail.unwrap());
+    assert!(free.kind == BtKind::Free);
+    assert_eq!((free.base, free.len), (0x2000, 0x1000));
+    VmemInner::dealloc_bt(tail);
+}
+
+/// An arena that's only ever handed tags doesn't go to the heap for its
+/// allocation table: it keeps its embedded buckets until it's given tags to
+/// grow into, and hands them back as it shrinks.
+#[test]
+fn allocation_table_grows_into_given_tags() {
+    const INITIAL: usize = AllocationTable::INITIAL_BUCKETS;
+    const ALLOCATIONS: usize = 4 * INITIAL * AllocationTable::MAX_LOAD;
+    let mut storage: Vec<MaybeUninit<Bt>> = (0..ALLOCATIONS + 2)
+        .map(|_| MaybeUninit::uninit())
+        .collect();
+    let mut tags = storage
+        .iter_mut()
+        .map(|tag| NonNull::from(tag).cast::<Bt>());
+    let mut vmem = VmemInner::new(QUANTUM);
+    let span = [tags.next().unwrap(), tags.next().unwrap()];
+    vmem.add_span_ptrs(0, ALLOCATIONS * QUANTUM, span).unwrap();
+
+    let mut bases = Vec::new();
+    for tag in tags.by_ref().take(ALLOCATIONS / 2) {
+        bases.push(
+            vmem.alloc_ptr(AllocPolicy::InstantFit, QUANTUM, tag)
+                .unwrap(),
+        );
+    }
+    assert_eq!(vmem.allocation_table.num_buckets(), INITIAL);
+
+    // Room for the table to grow twice, holding on to the first array while
+    // it rehashes into the second.
+    let slots =
+        TagPool::slots::<SegmentQueue>(2 * INITIAL) + TagPool::slots::<SegmentQueue>(4 * INITIAL);
+    let mut pool: Vec<MaybeUninit<Bt>> = (0..slots).map(|_| MaybeUninit::uninit()).collect();
+    vmem.add_tags(NonNull::from(&mut pool[..]));
+    for tag in tags {
+        bases.push(
+            vmem.alloc_ptr(AllocPolicy::InstantFit, QUANTUM, tag)
+                .unwrap(),
+        );
+    }
+    assert_eq!(vmem.allocation_table.num_buckets(), 4 * INITIAL);
+
+    for base in bases {
+        block_on(vmem.free_func(base, QUANTUM, |_| async {})).unwrap();
+    }
+    assert_eq!(vmem.allocation_table.num_buckets(), INITIAL);
+    assert!(vmem.tag_pool.has_room::<SegmentQueue>(4 * INITIAL));
+}
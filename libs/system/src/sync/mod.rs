@@ -142,3 +142,90 @@ impl<'a, T> Drop for Lock<'a, T> {
 
 unsafe impl<T: Send> Send for Mutex<T> {}
 unsafe impl<T: Send> Sync for Mutex<T> {}
+
+/// A wake-up call for tasks. [`notify`](Self::notify) wakes every task waiting
+/// in [`wait`](Self::wait); if none are, the next call to `wait` returns
+/// immediately instead.
+pub struct Event {
+    notified: AtomicBool,
+    wakers: spin::Mutex<PinList<PinListTypes>>,
+}
+impl Event {
+    pub fn new() -> Self {
+        Self {
+            notified: AtomicBool::new(false),
+            wakers: spin::Mutex::new(PinList::new(pin_list::id::Checked::new())),
+        }
+    }
+
+    pub fn notify(&self) {
+        self.notified.store(true, Ordering::Release);
+        loop {
+            let mut lock = self.wakers.lock();
+            let Ok(waker) = lock.cursor_front_mut().remove_current(()) else {
+                break;
+            };
+            drop(lock);
+            waker.wake();
+        }
+    }
+
+    pub fn wait(&self) -> impl Future<Output = ()> + '_ {
+        WaitFuture {
+            event: self,
+            waker: Node::new(),
+        }
+    }
+}
+impl Default for Event {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[pin_project(PinnedDrop)]
+pub struct WaitFuture<'a> {
+    event: &'a Event,
+    #[pin]
+    waker: Node<PinListTypes>,
+}
+impl<'a> Future for WaitFuture<'a> {
+    type Output = ();
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut lock = self.event.wakers.lock();
+        let mut projected = self.project();
+        if let Some(initialized) = projected.waker.as_mut().initialized_mut() {
+            return match initialized.take_removed(&lock) {
+                // Woken by `notify`.
+                Ok(_) => {
+                    projected.event.notified.store(false, Ordering::Relaxed);
+                    Poll::Ready(())
+                }
+                Err(node) => {
+                    *node.protected_mut(&mut lock).unwrap() = cx.waker().clone();
+                    Poll::Pending
+                }
+            };
+        }
+
+        if projected.event.notified.swap(false, Ordering::Acquire) {
+            Poll::Ready(())
+        } else {
+            lock.push_front(projected.waker, cx.waker().clone(), ());
+            Poll::Pending
+        }
+    }
+}
+#[pinned_drop]
+impl<'a> PinnedDrop for WaitFuture<'a> {
+    fn drop(self: Pin<&mut Self>) {
+        let projected = self.project();
+        if let Some(node) = projected.waker.initialized_mut() {
+            let mut lock = projected.event.wakers.lock();
+            let _ = node.reset(&mut lock);
+        }
+    }
+}
+
+unsafe impl Send for Event {}
+unsafe impl Sync for Event {}
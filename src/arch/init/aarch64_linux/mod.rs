@@ -1,121 +1,287 @@
-use core::{arch::global_asm, mem::MaybeUninit, ptr::NonNull};
+use core::{arch::global_asm, mem::MaybeUninit};
 
-use fdt::{standard_nodes::MemoryRegion, Fdt};
-use log::{error, info, trace};
+use fdt::{
+    node::{CellSizes, FdtNode},
+    standard_nodes::MemoryRegion,
+    Fdt,
+};
+use log::{error, info, trace, warn};
+use mem::{
+    buddy::Buddy,
+    memmap::{MemoryMap, MemoryMapError, Region, RegionKind},
+};
 use spin::Once;
 
 use crate::{
+    arch::{interrupts, paging, paging::aarch64::shootdown},
     common::{
         elf64::dynamic::{self, Dyn},
         sizes::Size,
     },
-    drivers::serial::{
-        pl011::{Config, Parity, Pl011},
-        Serial, SerialLogger,
+    drivers::{
+        gic::{Gic, GIC},
+        serial::{
+            pl011::{Config, Parity, Pl011},
+            Serial, SerialLogger,
+        },
     },
     kernel::memory::{
-        physalloc::{Node, PhysAlloc, PhysAllocInner},
-        PHYS_ALLOC,
+        cma::{Cma, CmaPool},
+        frames::{Frame, FrameDb, FrameFlags},
+        numa::{Topology, MAX_NODES},
+        physalloc::{NodeAlloc, PhysAlloc, PhysAllocInner},
+        stats::{Watermarks, PHYS_STATS},
+        CMA, FRAMES, HHDM_START, NUMA, PHYS_ALLOC,
     },
-    label,
+    label, size_of,
 };
 
-#[derive(Debug)]
-struct MemRange {
-    start: u64,
-    end: u64,
+/// The early memory map, grown out of its own memory whenever it fills up.
+struct InitRanges<'a> {
+    map: MemoryMap<'a>,
 }
-impl MemRange {
-    fn new(start: u64, end: u64) -> Self {
-        Self { start, end }
+impl<'a> InitRanges<'a> {
+    fn new(storage: &'a mut [Region]) -> Self {
+        Self {
+            map: MemoryMap::new(storage),
+        }
     }
-    fn size(&self) -> u64 {
-        self.end - self.start
+
+    fn add_usable(&mut self, start: u64, end: u64) {
+        self.retry(|map| map.add_usable(start, end))
+            .expect("no room to grow the memory map");
     }
-    fn overlaps(&self, other: &Self) -> bool {
-        self.start <= other.end && other.start <= self.end
+
+    fn reserve(&mut self, start: u64, end: u64, kind: RegionKind) {
+        self.retry(|map| map.reserve(start, end, kind))
+            .expect("no room to grow the memory map");
     }
-    fn contains(&self, other: &Self) -> bool {
-        self.start <= other.start && other.end <= self.end
+
+    /// Takes `size` bytes, aligned to `align` (a power of two), out of the
+    /// first range with room.
+    fn take(&mut self, size: u64, align: u64, kind: RegionKind) -> Option<u64> {
+        self.retry(|map| map.take(size, align, kind)).ok()
     }
-}
 
-struct InitRanges {
-    pub ranges: heapless::Vec<MemRange, 128>,
-}
-impl InitRanges {
-    fn new() -> Self {
-        Self {
-            ranges: heapless::Vec::new(),
-        }
+    /// Like [`take`](Self::take), but only out of `[start, end)`.
+    fn take_within(
+        &mut self,
+        start: u64,
+        end: u64,
+        size: u64,
+        align: u64,
+        kind: RegionKind,
+    ) -> Option<u64> {
+        self.retry(|map| map.take_within(start, end, size, align, kind))
+            .ok()
     }
 
-    fn insert(&mut self, range: MemRange) {
-        if self.ranges.iter().any(|other| other.overlaps(&range)) {
-            panic!("Overlapping memory ranges");
+    fn retry<T>(
+        &mut self,
+        mut op: impl FnMut(&mut MemoryMap<'a>) -> Result<T, MemoryMapError>,
+    ) -> Result<T, MemoryMapError> {
+        loop {
+            match op(&mut self.map) {
+                // Physical memory is identity mapped this early.
+                Err(MemoryMapError::Full) => unsafe {
+                    self.map
+                        .grow(RegionKind::Kernel, |addr| addr as *mut Region)?
+                },
+                result => return result,
+            }
         }
+    }
+}
 
-        let insertion_point = self
-            .ranges
-            .partition_point(|other| range.start < other.start);
+/// The `numa-node-id` of `node`, or 0.
+fn numa_node_id(node: FdtNode<'_, '_>) -> usize {
+    let id = node
+        .property("numa-node-id")
+        .and_then(|id| id.as_usize())
+        .unwrap_or(0);
+    if id >= MAX_NODES {
+        warn!("{}: NUMA node {id} out of range, using node 0", node.name);
+        return 0;
+    }
+    id
+}
 
-        let mut inserted = false;
+/// Works out the NUMA topology from the device tree. `spans` are the memory
+/// of each node; if they overlap, they're all flattened into node 0, spanning
+/// `ram`.
+fn numa_topology(
+    device_tree: &Fdt<'_>,
+    spans: &mut [Option<(u64, u64)>; MAX_NODES],
+    ram: (u64, u64),
+) -> Topology {
+    let overlapping = spans.iter().flatten().enumerate().any(|(i, a)| {
+        spans
+            .iter()
+            .flatten()
+            .skip(i + 1)
+            .any(|b| a.0 < b.1 && b.0 < a.1)
+    });
+    if overlapping {
+        warn!("NUMA nodes' memory overlaps, ignoring NUMA");
+        *spans = [None; MAX_NODES];
+        spans[0] = Some(ram);
+        return Topology::new(1);
+    }
 
-        if insertion_point > 0 {
-            let prev = &mut self.ranges[insertion_point - 1];
-            if prev.end == range.start {
-                prev.end += range.size();
-                inserted = true;
+    let cpus = || {
+        device_tree.find_node("/cpus").into_iter().flat_map(|cpus| {
+            cpus.children()
+                .filter(|cpu| cpu.name.split('@').next() == Some("cpu"))
+        })
+    };
+    // Nodes can have CPUs and no memory, or the other way round.
+    let nodes = cpus()
+        .map(numa_node_id)
+        .chain(spans.iter().rposition(Option::is_some))
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut topology = Topology::new(nodes);
+    for (cpu, node) in cpus().enumerate() {
+        topology.set_cpu_node(cpu, numa_node_id(node));
+    }
+    if let Some(matrix) = device_tree
+        .find_node("/distance-map")
+        .and_then(|map| map.property("distance-matrix"))
+    {
+        // (from, to, distance) triples of cells.
+        for entry in matrix.value.chunks_exact(12) {
+            let cell = |i: usize| u32::from_be_bytes(entry[i * 4..i * 4 + 4].try_into().unwrap());
+            let (from, to) = (cell(0) as usize, cell(1) as usize);
+            if from < nodes && to < nodes {
+                topology.set_distance(from, to, cell(2).min(u8::MAX as u32) as u8);
             }
         }
+    }
 
-        if !self.ranges.is_empty() && insertion_point < self.ranges.len() - 1 {
-            let next = &mut self.ranges[insertion_point + 1];
-            if range.end == next.start {
-                next.start = range.start;
-                inserted = true;
+    if nodes > 1 {
+        for (node, span) in spans.iter().enumerate() {
+            if let Some((start, end)) = span {
+                info!(
+                    "NUMA node {node}: {start:#x} - {end:#x} ({})",
+                    Size((end - start) as usize)
+                );
             }
         }
-
-        if inserted {
-            return;
-        }
-
-        assert!(!self.ranges.is_full(), "too many memory ranges");
-        self.ranges.insert(insertion_point, range);
     }
+    topology
+}
 
-    fn remove(&mut self, range: MemRange) -> Option<MemRange> {
-        let full = self.ranges.is_full();
+/// The `(start, end)` pairs in a list of addresses and sizes, such as
+/// `alloc-ranges`, given the `#address-cells` and `#size-cells` that apply.
+fn address_ranges(value: &[u8], cells: CellSizes) -> impl Iterator<Item = (u64, u64)> + '_ {
+    let read = |cells: &[u8]| {
+        cells.chunks_exact(4).fold(0, |acc, cell| {
+            acc << 32 | u32::from_be_bytes(cell.try_into().unwrap()) as u64
+        })
+    };
+    let address = cells.address_cells * 4;
+    let pair = (address + cells.size_cells * 4).max(1);
+    value.chunks_exact(pair).map(move |pair| {
+        let start = read(&pair[..address]);
+        (start, start.saturating_add(read(&pair[address..])))
+    })
+}
 
-        let (index, from_range) = self
-            .ranges
-            .iter_mut()
-            .enumerate()
-            .find(|(_, other)| other.contains(&range))?;
+fn log_region(kind: &str, start: u64, end: u64) {
+    info!(
+        "  {start:#012x} - {end:#012x} {kind:<12} ({})",
+        Size(end.saturating_sub(start) as usize)
+    );
+}
 
-        if range.start == from_range.start && range.end == from_range.end {
-            self.ranges.remove(index);
-            return Some(range);
+/// Takes the children of `/reserved-memory` out of `ranges`, as
+/// [`RegionKind::NoMap`] if they're `no-map`. Those that are `reusable` or a
+/// `shared-dma-pool` go to `cma` as well.
+fn reserve_memory(reserved_memory: FdtNode<'_, 'static>, ranges: &mut InitRanges, cma: &mut Cma) {
+    for node in reserved_memory.children() {
+        let status = node.property("status").and_then(|status| status.as_str());
+        if status.is_some_and(|status| status != "okay" && status != "ok") {
+            continue;
         }
-
-        if range.start == from_range.start {
-            from_range.start = range.end;
-            return Some(range);
+        let no_map = node.property("no-map").is_some();
+        let dma_pool = node
+            .compatible()
+            .is_some_and(|compatible| compatible.all().any(|c| c == "shared-dma-pool"));
+        let to_cma = dma_pool || node.property("reusable").is_some();
+
+        let kind = match (to_cma, no_map) {
+            (true, _) => "cma",
+            (false, true) => "no-map",
+            (false, false) => "reserved",
+        };
+        let region_kind = if no_map {
+            RegionKind::NoMap
+        } else {
+            RegionKind::Reserved
+        };
+        let mut regions = heapless::Vec::<(u64, u64), 8>::new();
+        if let Some(reg) = node.reg() {
+            for region in reg {
+                let start = region.starting_address as u64;
+                let end = start + region.size.unwrap_or(0) as u64;
+                if !ranges.map.is_usable(start, end) {
+                    // Overlaps another reservation, or isn't all RAM.
+                    warn!("{}: {start:#x} - {end:#x} isn't usable memory", node.name);
+                }
+                ranges.reserve(start, end, region_kind);
+                if regions.push((start, end)).is_err() {
+                    warn!("{}: too many regions, ignoring the rest", node.name);
+                    break;
+                }
+            }
+        } else {
+            // Dynamically placed, in the first of its `alloc-ranges` with
+            // room, or anywhere that fits if it has none.
+            let Some(size) = node.property("size").and_then(|size| size.as_usize()) else {
+                warn!("{}: neither reg nor size, ignoring", node.name);
+                continue;
+            };
+            let align = node
+                .property("alignment")
+                .and_then(|align| align.as_usize())
+                .unwrap_or(4096)
+                .max(4096);
+            let size = (size as u64 + 4095) & !4095;
+            let align = align as u64;
+            let start = match node.property("alloc-ranges") {
+                Some(alloc_ranges) => {
+                    address_ranges(alloc_ranges.value, reserved_memory.cell_sizes()).find_map(
+                        |(start, end)| ranges.take_within(start, end, size, align, region_kind),
+                    )
+                }
+                None => ranges.take(size, align, region_kind),
+            };
+            let Some(start) = start else {
+                warn!("{}: no room for {}", node.name, Size(size as usize));
+                continue;
+            };
+            let _ = regions.push((start, start + size));
         }
 
-        if range.end == from_range.end {
-            from_range.end = range.start;
-            return Some(range);
+        for (start, end) in regions {
+            log_region(kind, start, end);
+            if to_cma {
+                let (start, end) = ((start + 4095) & !4095, end & !4095);
+                if start >= end {
+                    continue;
+                }
+                let len = (end - start) as usize;
+                let words = CmaPool::bitmap_words(len);
+                let bitmap_size = (words * size_of!(u64)).next_multiple_of(4096) as u64;
+                let Some(bitmap) = ranges.take(bitmap_size, 4096, RegionKind::Kernel) else {
+                    warn!("{}: no room for a CMA bitmap", node.name);
+                    continue;
+                };
+                let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap as *mut u64, words) };
+                cma.add_pool(CmaPool::new(node.name, start as usize, len, bitmap));
+            }
         }
-
-        assert!(!full, "too many memory ranges");
-
-        let new_range = MemRange::new(range.end, from_range.end);
-        from_range.end = range.start;
-        self.ranges.insert(index + 1, new_range);
-
-        Some(range)
     }
 }
 
@@ -161,6 +327,11 @@ static mut PL011: Once<SerialLogger<Pl011>> = Once::new();
 
 #[no_mangle]
 pub unsafe extern "C" fn init(dtb_ptr: *const u8) -> ! {
+    paging::aarch64::init_mair();
+    paging::aarch64::asid::init();
+    paging::aarch64::access::init();
+    interrupts::aarch64::init();
+
     let device_tree = Fdt::from_ptr(dtb_ptr).unwrap();
 
     if let Some(stdout) = device_tree.chosen().stdout() {
@@ -189,80 +360,164 @@ pub unsafe extern "C" fn init(dtb_ptr: *const u8) -> ! {
         }
     }
 
-    let mut ranges = InitRanges::new();
+    let mut storage = [Region::EMPTY; 64];
+    let mut ranges = InitRanges::new(&mut storage);
+
+    // The span of memory on each NUMA node.
+    let mut spans = [None; MAX_NODES];
+    for memory in device_tree
+        .all_nodes()
+        .filter(|node| node.property("device_type").and_then(|ty| ty.as_str()) == Some("memory"))
+    {
+        let node = numa_node_id(memory);
+        for region in memory.reg().into_iter().flatten() {
+            let start = region.starting_address as u64;
+            let end = start + region.size.unwrap_or(0) as u64;
+            ranges.add_usable(start, end);
+            let span = spans[node].get_or_insert((start, end));
+            *span = (span.0.min(start), span.1.max(end));
+        }
+    }
 
-    for region in device_tree.memory().regions() {
-        ranges.insert(MemRange::new(
-            region.starting_address as u64,
-            region.starting_address as u64 + region.size.unwrap() as u64,
-        ));
+    let Some((ram_start, ram_end)) = ranges.map.bounds() else {
+        panic!("no usable memory");
+    };
+    let topology = numa_topology(&device_tree, &mut spans, (ram_start, ram_end));
+    NUMA.call_once(|| topology);
+
+    let gic_regs = device_tree
+        .find_compatible(&["arm,gic-v3"])
+        .and_then(|gic| gic.reg())
+        .map(|mut reg| (reg.next(), reg.next()));
+    if let Some((Some(distributor), Some(redistributors))) = gic_regs {
+        let mpidrs = device_tree
+            .find_node("/cpus")
+            .into_iter()
+            .flat_map(|cpus| cpus.children())
+            .filter(|cpu| cpu.name.split('@').next() == Some("cpu"))
+            .map(|cpu| {
+                cpu.reg()
+                    .and_then(|mut reg| reg.next())
+                    .map_or(0, |reg| reg.starting_address as u64)
+            });
+        let gic = GIC.call_once(|| {
+            Gic::new(
+                distributor.starting_address as *mut u8,
+                redistributors.starting_address as *mut u8,
+                mpidrs,
+            )
+        });
+        gic.init();
+        // Only this CPU is up. The others have to do the same before they
+        // run any user address space.
+        if gic.init_cpu(&[shootdown::SGI_SHOOTDOWN]) {
+            shootdown::enable_ipis();
+            interrupts::aarch64::unmask_irqs();
+            trace!("GICv3 with {} CPUs", gic.cpus());
+        } else {
+            warn!("No GICv3 redistributor for this CPU, TLB shootdowns will always be broadcast");
+        }
+    } else {
+        warn!("No GICv3, TLB shootdowns will always be broadcast");
     }
+    let (ram_start, ram_end) = (ram_start as usize, ram_end as usize);
+
+    info!("Memory map:");
 
     for region in device_tree.memory_reservations() {
-        ranges.remove(MemRange::new(
-            region.address() as u64,
-            region.address() as u64 + region.size() as u64,
-        ));
+        let start = region.address() as u64;
+        let end = start + region.size() as u64;
+        log_region("memreserve", start, end);
+        ranges.reserve(start, end, RegionKind::Reserved);
     }
 
-    ranges.remove(MemRange::new(
-        dtb_ptr as u64,
-        dtb_ptr as u64 + device_tree.total_size() as u64,
-    ));
-
-    ranges.remove(MemRange::new(
-        label!(kernel_start) as u64,
-        label!(kernel_end) as u64,
-    ));
-
-    for range in ranges.ranges.windows(2) {
-        let start = (range[0].start + 4095) & !4095;
-        let end = range[0].end & !4095;
-        trace!(
-            "Region: {:x} - {:x} ({})",
-            start,
-            end,
-            Size(end as usize - start as usize)
-        );
-        for i in (start..end).step_by(4096) {
-            let node = Node {
-                next: NonNull::new((i + 4096) as *mut Node),
-            };
-            (i as *mut Node).write(node);
-        }
-        let next_start = (range[1].start + 4095) & !4095;
-        ((end - 4096) as *mut Node).write(Node {
-            next: NonNull::new(next_start as *mut Node),
-        });
-    }
+    let dtb_end = dtb_ptr as u64 + device_tree.total_size() as u64;
+    log_region("dtb", dtb_ptr as u64, dtb_end);
+    ranges.reserve(dtb_ptr as u64, dtb_end, RegionKind::Dtb);
 
-    if let Some(last) = ranges.ranges.last() {
-        let start = (last.start + 4095) & !4095;
-        let end = last.end & !4095;
-        trace!(
-            "Region: {:x} - {:x} ({})",
-            start,
-            end,
-            Size(end as usize - start as usize)
-        );
-        for i in (start..end).step_by(4096) {
-            let node = Node {
-                next: NonNull::new((i + 4096) as *mut Node),
-            };
-            (i as *mut Node).write(node);
+    if let Some(chosen) = device_tree.find_node("/chosen") {
+        let initrd = |name| chosen.property(name).and_then(|prop| prop.as_usize());
+        if let (Some(start), Some(end)) = (initrd("linux,initrd-start"), initrd("linux,initrd-end"))
+        {
+            log_region("initrd", start as u64, end as u64);
+            ranges.reserve(start as u64, end as u64, RegionKind::Initrd);
         }
-        ((end - 4096) as *mut Node).write(Node { next: None });
     }
 
-    let head = || NonNull::new(((ranges.ranges.first()?.start + 4095) / 4096 * 4096) as *mut Node);
-    let physalloc = PhysAllocInner {
-        free: head(),
-        dirty: None,
-    };
+    let (kernel_start, kernel_end) = (label!(kernel_start) as u64, label!(kernel_end) as u64);
+    log_region("kernel", kernel_start, kernel_end);
+    ranges.reserve(kernel_start, kernel_end, RegionKind::Kernel);
 
-    trace!("Initialized physical allocator: {physalloc:?}");
+    let mut cma = Cma::new();
+    if let Some(reserved_memory) = device_tree.find_node("/reserved-memory") {
+        reserve_memory(reserved_memory, &mut ranges, &mut cma);
+    }
+    CMA.call_once(|| cma);
+
+    // The frame database and the buddy allocators' bitmaps come out of the
+    // memory they manage.
+    let frames_size = FrameDb::size(ram_start, ram_end).next_multiple_of(4096) as u64;
+    let frames_start = ranges
+        .take(frames_size, 4096, RegionKind::Kernel)
+        .expect("no room for the frame database");
+    let mut buddies = heapless::Vec::<(usize, u64, u64, Buddy), MAX_NODES>::new();
+    for (node, &span) in spans.iter().enumerate() {
+        let Some((start, end)) = span else {
+            continue;
+        };
+        let words = Buddy::bitmap_words(start as usize, end as usize);
+        let bitmap_size = (words * size_of!(u64)).next_multiple_of(4096) as u64;
+        let bitmap_start = ranges
+            .take(bitmap_size, 4096, RegionKind::Kernel)
+            .expect("no room for a buddy bitmap");
+        let bitmap = core::slice::from_raw_parts_mut(bitmap_start as *mut u64, words);
+        let buddy = Buddy::new(start as usize, end as usize, 0, bitmap);
+        let _ = buddies.push((node, start, end, buddy));
+    }
 
-    PHYS_ALLOC.call_once(|| PhysAlloc::new(physalloc));
+    let frames = FRAMES.call_once(|| FrameDb::new(ram_start, ram_end, frames_start as *mut Frame));
+
+    // Everything else stays reserved in the frame database.
+    for region in ranges.map.regions() {
+        let start = (region.start + 4095) & !4095;
+        let end = region.end & !4095;
+        match region.kind {
+            RegionKind::Usable if start < end => {
+                log_region("usable", start, end);
+                for (_, node_start, node_end, buddy) in &mut buddies {
+                    let (start, end) = (start.max(*node_start), end.min(*node_end));
+                    if start < end {
+                        buddy.add_range(start as usize, end as usize);
+                    }
+                }
+                frames.set_range(start as usize, end as usize, FrameFlags::FREE);
+                PHYS_STATS.add_total(((end - start) / 4096) as usize);
+            }
+            RegionKind::Kernel => {
+                frames.set_range(
+                    region.start as usize,
+                    region.end as usize,
+                    FrameFlags::KERNEL | FrameFlags::PINNED,
+                );
+                PHYS_STATS.add_total(
+                    frames
+                        .range(region.start as usize, region.end as usize)
+                        .len(),
+                );
+            }
+            _ => {}
+        }
+    }
+    PHYS_STATS.set_watermarks(Watermarks::for_total(PHYS_STATS.stats().total));
+
+    // The MMU is still off, so physical memory is where it is.
+    HHDM_START.call_once(|| 0);
+    let nodes = buddies.into_iter().map(|(node, start, end, buddy)| {
+        let physalloc = PhysAllocInner::new(buddy);
+        trace!("Initialized physical allocator for node {node}: {physalloc:?}");
+        NodeAlloc::new(node, start as usize, end as usize, physalloc)
+    });
+    PHYS_ALLOC.call_once(|| PhysAlloc::new(nodes));
 
     crate::main();
 }
@@ -0,0 +1,267 @@
+//! The exception vectors, and decoding of instruction and data aborts from
+//! ESR_EL1 and FAR_EL1.
+
+use core::arch::{asm, global_asm};
+
+use log::warn;
+
+use crate::{
+    arch::paging::aarch64::shootdown::{self, SGI_SHOOTDOWN},
+    drivers::gic::Gic,
+    kernel::memory::fault,
+};
+
+/// What the access that faulted was trying to do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+/// Why the access faulted, with the level of the walk it faulted at.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultKind {
+    /// No descriptor was present.
+    Translation(u8),
+    /// The descriptor's access flag was clear.
+    AccessFlag(u8),
+    /// The descriptor doesn't allow the access.
+    Permission(u8),
+    /// Anything else, such as alignment faults or external aborts, with its
+    /// fault status code.
+    Other(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Fault {
+    /// The virtual address that was accessed.
+    pub addr: usize,
+    pub access: Access,
+    pub kind: FaultKind,
+    /// Whether the access was made from EL0.
+    pub user: bool,
+}
+impl Fault {
+    /// Decodes an abort. Returns `None` if the exception isn't one.
+    pub fn decode(esr: u64, far: u64) -> Option<Self> {
+        let (user, instruction) = match esr >> 26 & 0x3f {
+            0x20 => (true, true),
+            0x21 => (false, true),
+            0x24 => (true, false),
+            0x25 => (false, false),
+            _ => return None,
+        };
+        let status = (esr & 0x3f) as u8;
+        let level = status & 0b11;
+        let kind = match status >> 2 {
+            0b0001 => FaultKind::Translation(level),
+            0b0010 => FaultKind::AccessFlag(level),
+            0b0011 => FaultKind::Permission(level),
+            _ => FaultKind::Other(status),
+        };
+        let access = if instruction {
+            Access::Execute
+        } else if esr & 1 << 6 != 0 {
+            // WnR
+            Access::Write
+        } else {
+            Access::Read
+        };
+        Some(Self {
+            addr: far as usize,
+            access,
+            kind,
+            user,
+        })
+    }
+
+    /// Decodes the abort being handled.
+    ///
+    /// # Safety
+    /// Must be called from the synchronous exception handler, before anything
+    /// that could fault again.
+    pub unsafe fn read() -> Option<Self> {
+        let (esr, far) = syndrome();
+        Self::decode(esr, far)
+    }
+}
+
+/// ESR_EL1 and FAR_EL1.
+unsafe fn syndrome() -> (u64, u64) {
+    let esr: u64;
+    let far: u64;
+    asm!(
+        "mrs {}, esr_el1",
+        "mrs {}, far_el1",
+        out(reg) esr,
+        out(reg) far,
+        options(nomem, nostack)
+    );
+    (esr, far)
+}
+
+/// The registers the vectors save on the stack, which are put back when the
+/// handler returns.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TrapFrame {
+    pub x: [u64; 31],
+    pub elr: u64,
+    pub spsr: u64,
+    /// Keeps the stack 16 byte aligned.
+    _pad: u64,
+}
+
+/// The kinds of exception, in vector table order.
+const EXCEPTION_KINDS: [&str; 4] = ["synchronous exception", "IRQ", "FIQ", "SError"];
+
+// Each vector saves a TrapFrame and calls the handler with it. Exceptions
+// from EL1 while on SP_EL0, and from AArch32, are never expected, and
+// neither are FIQs and SErrors.
+global_asm!(
+    r#"
+.macro exception_entry handler, kind
+    .balign 0x80
+    sub sp, sp, #272
+    stp x0, x1, [sp, #0]
+    stp x2, x3, [sp, #16]
+    stp x4, x5, [sp, #32]
+    stp x6, x7, [sp, #48]
+    stp x8, x9, [sp, #64]
+    stp x10, x11, [sp, #80]
+    stp x12, x13, [sp, #96]
+    stp x14, x15, [sp, #112]
+    stp x16, x17, [sp, #128]
+    stp x18, x19, [sp, #144]
+    stp x20, x21, [sp, #160]
+    stp x22, x23, [sp, #176]
+    stp x24, x25, [sp, #192]
+    stp x26, x27, [sp, #208]
+    stp x28, x29, [sp, #224]
+    mrs x0, elr_el1
+    mrs x1, spsr_el1
+    stp x30, x0, [sp, #240]
+    str x1, [sp, #256]
+    mov x0, sp
+    mov x1, #\kind
+    bl \handler
+    b exception_return
+.endm
+
+.section .text
+.balign 0x800
+.global exception_vectors
+exception_vectors:
+    // EL1, SP_EL0
+    exception_entry exception_unexpected, 0
+    exception_entry exception_unexpected, 1
+    exception_entry exception_unexpected, 2
+    exception_entry exception_unexpected, 3
+    // EL1, SP_EL1
+    exception_entry exception_sync, 0
+    exception_entry exception_irq, 1
+    exception_entry exception_unexpected, 2
+    exception_entry exception_unexpected, 3
+    // EL0, AArch64
+    exception_entry exception_sync, 0
+    exception_entry exception_irq, 1
+    exception_entry exception_unexpected, 2
+    exception_entry exception_unexpected, 3
+    // EL0, AArch32
+    exception_entry exception_unexpected, 0
+    exception_entry exception_unexpected, 1
+    exception_entry exception_unexpected, 2
+    exception_entry exception_unexpected, 3
+
+exception_return:
+    ldr x1, [sp, #256]
+    ldp x30, x0, [sp, #240]
+    msr elr_el1, x0
+    msr spsr_el1, x1
+    ldp x0, x1, [sp, #0]
+    ldp x2, x3, [sp, #16]
+    ldp x4, x5, [sp, #32]
+    ldp x6, x7, [sp, #48]
+    ldp x8, x9, [sp, #64]
+    ldp x10, x11, [sp, #80]
+    ldp x12, x13, [sp, #96]
+    ldp x14, x15, [sp, #112]
+    ldp x16, x17, [sp, #128]
+    ldp x18, x19, [sp, #144]
+    ldp x20, x21, [sp, #160]
+    ldp x22, x23, [sp, #176]
+    ldp x24, x25, [sp, #192]
+    ldp x26, x27, [sp, #208]
+    ldp x28, x29, [sp, #224]
+    add sp, sp, #272
+    eret
+"#
+);
+
+extern "C" {
+    static exception_vectors: [u8; 0x800];
+}
+
+/// Installs the exception vectors on this CPU.
+///
+/// # Safety
+/// The current stack must be able to take a [`TrapFrame`] and a handler's
+/// frames whenever an exception is taken.
+pub unsafe fn init() {
+    asm!(
+        "msr vbar_el1, {}",
+        "isb",
+        in(reg) core::ptr::addr_of!(exception_vectors),
+        options(nostack)
+    );
+}
+
+/// Lets IRQs through on this CPU.
+///
+/// # Safety
+/// Every IRQ that can be signalled must be safe to take from here on.
+pub unsafe fn unmask_irqs() {
+    asm!("msr daifclr, #2", options(nomem, nostack));
+}
+
+/// Page faults go to [`fault::handle_abort`]; nothing else is handled yet.
+#[no_mangle]
+extern "C" fn exception_sync(frame: &mut TrapFrame, _kind: u64) {
+    let Some(fault) = (unsafe { Fault::read() }) else {
+        let (esr, _) = unsafe { syndrome() };
+        panic!(
+            "unhandled synchronous exception at {:#x}, ESR {esr:#x}",
+            frame.elr
+        );
+    };
+    if !fault::handle_abort(fault) {
+        // There are no user threads to kill yet.
+        panic!(
+            "user {:?} at {:#x} from {:#x} can't be retried",
+            fault.access, fault.addr, frame.elr
+        );
+    }
+}
+
+/// Takes an interrupt from the GIC. Only shootdown IPIs are expected so far.
+#[no_mangle]
+extern "C" fn exception_irq(_frame: &mut TrapFrame, _kind: u64) {
+    let Some(intid) = Gic::acknowledge() else {
+        return;
+    };
+    if intid == SGI_SHOOTDOWN as u32 {
+        shootdown::handle_ipi();
+    } else {
+        warn!("unexpected interrupt {intid}");
+    }
+    Gic::end_interrupt(intid);
+}
+
+#[no_mangle]
+extern "C" fn exception_unexpected(frame: &mut TrapFrame, kind: u64) {
+    panic!(
+        "unexpected {} at {:#x}",
+        EXCEPTION_KINDS[kind as usize], frame.elr
+    );
+}
@@ -1,5 +1,9 @@
 use crate::kernel::{memory::address::PhysAddr, Process};
 
+use self::aarch64::Fault;
+
+pub mod aarch64;
+
 pub enum SyncException {
     FailedLoad,
     InvalidOpcode,
@@ -8,13 +12,13 @@ pub enum SyncException {
 }
 
 pub struct IntHandlers {
-    page_fault: fn(PhysAddr, Process) -> bool,
+    page_fault: fn(Fault, Process) -> bool,
     sync_exception: fn(PhysAddr, SyncException) -> bool,
     irq: fn(PhysAddr) -> bool,
 }
 impl IntHandlers {
     pub fn new(
-        page_fault: fn(PhysAddr, Process) -> bool,
+        page_fault: fn(Fault, Process) -> bool,
         sync_exception: fn(PhysAddr, SyncException) -> bool,
         irq: fn(PhysAddr) -> bool,
     ) -> IntHandlers {
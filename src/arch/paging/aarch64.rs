@@ -1,51 +1,239 @@
-use core::arch::asm;
+use core::{arch::asm, marker::PhantomData};
 
 use crate::kernel::memory::{
-    address::{PhysAddr, PhysPtr},
+    address::{PhysAddr, PhysPtr, VirtAddr},
+    frames::{self, Frame, FrameFlags, Owner},
     HHDM_START, PHYS_ALLOC,
 };
 
+use self::{
+    access::{DIRTY_BIT_MODIFIER, READ_ONLY},
+    asid::{Asid, Scope},
+};
 use super::{
-    sealed::PageSize, MapError, Mapper, PageFlags, PhysPage, RuntimePageSize, Size1G, Size2M,
-    Size4K, TranslateError, VirtPage,
+    sealed::PageSize, CacheFlush, MapError, Mapper, MemoryType, PageFlags, PhysPage,
+    RuntimePageSize, Size1G, Size2M, Size4K, TranslateError, VirtPage,
 };
 
-pub struct Flush<Size: PageSize>(Option<VirtPage<Size>>);
+pub mod access;
+pub mod asid;
+pub mod shootdown;
+#[cfg(feature = "test")]
+mod tests;
+
+/// The attributes in MAIR_EL1, by index. A descriptor's AttrIndx picks one.
+const MAIR_ATTRS: [(MemoryType, u8); 4] = [
+    // Inner and outer write-back, read- and write-allocate, non-transient.
+    (MemoryType::Normal, 0xff),
+    // Inner and outer non-cacheable.
+    (MemoryType::NormalNc, 0x44),
+    (MemoryType::DeviceNGnRE, 0x04),
+    (MemoryType::DeviceNGnRnE, 0x00),
+];
+/// The value for MAIR_EL1.
+pub const MAIR: u64 = {
+    let mut mair = 0;
+    let mut index = 0;
+    while index < MAIR_ATTRS.len() {
+        mair |= (MAIR_ATTRS[index].1 as u64) << (index * 8);
+        index += 1;
+    }
+    mair
+};
+
+/// Programs MAIR_EL1 with the attributes the descriptors here refer to.
+///
+/// # Safety
+/// Must be done before any mapping made here is used, and not changed while
+/// one is live.
+pub unsafe fn init_mair() {
+    asm!("msr mair_el1, {}", "isb", in(reg) MAIR, options(nostack));
+}
+
+const fn attr_index(ty: MemoryType) -> u64 {
+    let mut index = 0;
+    while index < MAIR_ATTRS.len() {
+        if MAIR_ATTRS[index].0 as u8 == ty as u8 {
+            return index as u64;
+        }
+        index += 1;
+    }
+    panic!("memory type missing from MAIR");
+}
+const fn memory_type(index: u64) -> MemoryType {
+    MAIR_ATTRS[index as usize].0
+}
+
+/// The output address bits of any descriptor.
+const OUTPUT_ADDRESS: u64 = 0x0000_ffff_ffff_f000;
+/// AttrIndx, bits 2 to 4 of a block or page descriptor.
+const ATTR_INDEX_SHIFT: u64 = 2;
+const ATTR_INDEX_MASK: u64 = 0b111 << ATTR_INDEX_SHIFT;
+/// Inner shareable, in SH. Device memory is always outer shareable, whatever
+/// this says.
+const INNER_SHAREABLE: u64 = 0b11 << 8;
+/// The access flag. Without it, the first access to the page faults.
+const ACCESSED: u64 = 1 << 10;
+/// nG: the TLB entries belong to the current ASID only.
+const NOT_GLOBAL: u64 = 1 << 11;
+/// Bits 55 to 58 of a block or page descriptor are left for software. This
+/// one is [`PageFlags::COW`].
+const COPY_ON_WRITE: u64 = 1 << 55;
+
+/// The memory attribute bits of a block or page descriptor for `flags`.
+/// Device memory is never executable, since speculative instruction fetches
+/// could touch it.
+const fn memory_attributes(flags: &PageFlags) -> u64 {
+    let ty = flags.memory_type();
+    let mut data = attr_index(ty) << ATTR_INDEX_SHIFT | INNER_SHAREABLE | ACCESSED;
+    if ty.is_device() {
+        data |= 1 << 54 | 1 << 53;
+    }
+    data
+}
+
+/// Invalidates the page or block that changed, if one did.
+pub struct Flush<Size: PageSize>(RangeFlush, PhantomData<Size>);
+impl<Size: PageSize> Flush<Size> {
+    fn none() -> Self {
+        Self(RangeFlush::new(Scope::Nothing), PhantomData)
+    }
+    fn page(scope: Scope, virt: usize) -> Self {
+        let mut flush = RangeFlush::new(scope);
+        flush.add(virt, virt + Size::size());
+        Self(flush, PhantomData)
+    }
+}
 impl<Size: PageSize> super::CacheFlush for Flush<Size> {
     fn flush(self) {
-        if let Some(page) = self.0 {
-            unsafe {
-                asm!(
-                    "dsb sy",
-                    "tlbi vae1, {}",
-                    "dsb sy", "isb",
-                    in(reg) page.addr.get() as usize / Size::size(),
-                    options(nostack)
-                );
-            }
+        self.0.flush();
+    }
+
+    fn flush_all(self) {
+        self.0.flush_all();
+    }
+}
+impl<Size: PageSize> From<Flush<Size>> for RangeFlush {
+    fn from(flush: Flush<Size>) -> Self {
+        flush.0
+    }
+}
+
+/// Past this many pages, a [`RangeFlush`] flushes its whole scope instead.
+const RANGE_FLUSH_PAGES: usize = 64;
+/// Past this many separate ranges, likewise.
+const RANGE_FLUSH_RANGES: usize = 8;
+/// How many leaves [`PageTable::unmap_range`] unmaps before flushing them and
+/// freeing their frames.
+const FREE_BATCH: usize = RANGE_FLUSH_PAGES;
+
+/// Invalidates a batch of ranges in one address space at once, after the
+/// changes to all of them have been made.
+pub struct RangeFlush {
+    scope: Scope,
+    ranges: heapless::Vec<(usize, usize), RANGE_FLUSH_RANGES>,
+    pages: usize,
+    /// Too many ranges to keep track of; flush everything.
+    overflowed: bool,
+}
+impl RangeFlush {
+    pub const fn new(scope: Scope) -> Self {
+        Self {
+            scope,
+            ranges: heapless::Vec::new(),
+            pages: 0,
+            overflowed: false,
+        }
+    }
+    pub fn add(&mut self, start: usize, end: usize) {
+        if start >= end || self.scope == Scope::Nothing {
+            return;
+        }
+        self.pages = self.pages.saturating_add((end - start) / Size4K::size());
+        if let Some(last) = self.ranges.last_mut().filter(|last| last.1 == start) {
+            last.1 = end;
+        } else if self.ranges.push((start, end)).is_err() {
+            self.overflowed = true;
+        }
+    }
+    /// Adds everything `other` would flush. They must be for the same
+    /// address space.
+    pub fn merge(&mut self, other: impl Into<RangeFlush>) {
+        let other = other.into();
+        if other.is_empty() {
+            return;
+        }
+        if self.is_empty() {
+            *self = other;
+            return;
+        }
+        assert_eq!(
+            self.scope, other.scope,
+            "merging flushes of different address spaces"
+        );
+        self.overflowed |= other.overflowed;
+        for (start, end) in other.ranges {
+            self.add(start, end);
+        }
+    }
+    pub fn is_empty(&self) -> bool {
+        !self.overflowed && self.ranges.is_empty()
+    }
+}
+impl CacheFlush for RangeFlush {
+    fn flush(self) {
+        if self.overflowed || self.pages > RANGE_FLUSH_PAGES {
+            return self.flush_all();
+        }
+        if !self.ranges.is_empty() {
+            shootdown::flush_ranges(self.scope, &self.ranges);
         }
     }
 
     fn flush_all(self) {
-        if let Some(page) = self.0 {
-            unsafe {
-                asm!(
-                    "dsb sy",
-                    "tlbi vmalle1is, {}",
-                    "dsb sy", "isb",
-                    in(reg) page.addr.get() as usize / Size::size(),
-                    options(nostack)
-                );
-            }
+        if !self.is_empty() {
+            shootdown::flush_scope(self.scope);
         }
     }
 }
 
+/// The level 0 tables come first, so that they're page aligned.
+#[repr(C, align(4096))]
 pub struct PageTable {
     user_l0: [Table; 512],
     kernel_l0: [Table; 512],
+    asid: Asid,
 }
 impl PageTable {
+    /// An empty table, with no ASID until it's first activated.
+    pub const fn new() -> Self {
+        Self {
+            user_l0: [Table::new(); 512],
+            kernel_l0: [Table::new(); 512],
+            asid: Asid::new(),
+        }
+    }
+
+    /// Which TLB entries a change to the mapping of `virt` affects.
+    pub fn scope(&self, virt: usize) -> Scope {
+        if is_user(virt) {
+            self.asid.scope()
+        } else {
+            Scope::Global
+        }
+    }
+
+    /// Switches this CPU's user half to this table.
+    ///
+    /// # Safety
+    /// The table must be in the direct map, and stay alive until the user
+    /// half is switched away from it.
+    pub unsafe fn activate(&self) {
+        let hhdm_start = *HHDM_START.get().unwrap();
+        self.asid
+            .switch_to(self.user_l0.as_ptr() as usize - hhdm_start);
+    }
+
     async fn alloc_tables(hhdm_start: usize) -> Result<PhysPtr<[Table; 512]>, MapError> {
         let phys_alloc = PHYS_ALLOC.get();
         let Some(phys_alloc) = phys_alloc else {
@@ -54,6 +242,10 @@ impl PageTable {
         let Some(frame) = phys_alloc.alloc().await else {
             return Err(MapError::OutOfMem)
         };
+        if let Some(frame) = frames::frame(frame) {
+            frame.insert_flags(FrameFlags::KERNEL);
+            frame.set_owner(Owner::PAGE_TABLE);
+        }
         let ptr = frame.addr.into_ptr();
         let virt = ptr.to_virt_offset(hhdm_start);
         unsafe {
@@ -69,6 +261,10 @@ impl PageTable {
         let Some(frame) = phys_alloc.alloc().await else {
             return Err(MapError::OutOfMem)
         };
+        if let Some(frame) = frames::frame(frame) {
+            frame.insert_flags(FrameFlags::KERNEL);
+            frame.set_owner(Owner::PAGE_TABLE);
+        }
         let ptr = frame.addr.into_ptr();
         let virt = ptr.to_virt_offset(hhdm_start);
         unsafe {
@@ -77,446 +273,962 @@ impl PageTable {
         Ok(ptr)
     }
 }
-impl Mapper<Size4K> for PageTable {
-    type Flush = Flush<Size4K>;
 
-    async fn map(
-        &mut self,
-        page: VirtPage<Size4K>,
-        frame: PhysPage<Size4K>,
-        flags: PageFlags,
-    ) -> Result<Self::Flush, MapError> {
-        let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
+/// The level of the descriptors that map pages of `Size`.
+fn leaf_level<Size: PageSize>() -> usize {
+    match Size::size() {
+        0x1000 => 3,
+        0x20_0000 => 2,
+        0x4000_0000 => 1,
+        size => unreachable!("no level maps {size} byte pages"),
+    }
+}
+/// The size of the pages mapped by a descriptor at `level`.
+fn level_size(level: usize) -> RuntimePageSize {
+    match level {
+        1 => RuntimePageSize::Size1G,
+        2 => RuntimePageSize::Size2M,
+        3 => RuntimePageSize::Size4K,
+        _ => unreachable!("nothing is mapped at level {level}"),
+    }
+}
+/// Whether `virt` is in the user half, which goes through TTBR0. The MMU
+/// picks by bit 55.
+fn is_user(virt: usize) -> bool {
+    virt & 1 << 55 == 0
+}
+/// The index of `virt`'s descriptor in its table at `level`.
+fn index(virt: usize, level: usize) -> usize {
+    virt >> (39 - 9 * level) & 0x1ff
+}
 
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
-        };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            l0_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l0_desc.set_present(true);
-        }
+/// Decides what a walk does when it can't carry on down. By default, it
+/// stops.
+trait Visitor {
+    /// Called on every descriptor the walk reaches, top down, including the
+    /// one it ends on.
+    fn visit(&mut self, _level: usize, _entry: &mut Entry) {}
+    /// `entry` is empty. Returns whether it's been made into a table to carry
+    /// on into.
+    fn missing(&mut self, _level: usize, _entry: &mut Table) -> bool {
+        false
+    }
+    /// `entry` is a block. Returns whether it's been made into a table to
+    /// carry on into.
+    fn block(&mut self, _level: usize, _entry: &mut Entry) -> bool {
+        false
+    }
+}
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size1G));
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            l1_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l1_desc.set_present(true);
-        }
+/// Stops at the first empty descriptor or block.
+struct StopAtBlock;
+impl Visitor for StopAtBlock {}
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let mut l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if l2_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size2M));
-        }
-        let l2_desc = unsafe { &mut l2_desc.table };
-        if !l2_desc.is_present() {
-            l2_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l2_desc.set_present(true);
+/// Fills in empty descriptors with new tables, one per walk: when a walk
+/// ends at [`Walk::Missing`], [`refill`](Self::refill) it and walk again.
+#[derive(Default)]
+struct AllocateMissing {
+    table: Option<PhysPtr<[Table; 512]>>,
+}
+impl AllocateMissing {
+    async fn refill(&mut self, hhdm_start: usize) -> Result<(), MapError> {
+        if self.table.is_none() {
+            self.table = Some(PageTable::alloc_tables(hhdm_start).await?);
         }
+        Ok(())
+    }
+}
+impl Visitor for AllocateMissing {
+    fn missing(&mut self, level: usize, entry: &mut Table) -> bool {
+        let Some(table) = self.table.take() else {
+            return false;
+        };
+        *entry = Table::new();
+        entry.set_ptr(table.cast());
+        entry.set_present(true);
+        occupy(level, (entry as *mut Table).cast());
+        true
+    }
+}
 
-        let mut virt = l2_desc.get_addr().to_virt_offset(hhdm_start);
-        let l3 = unsafe { virt.as_mut() };
-        let mut l3_desc = &mut l3[virt_ptr >> 12 & 0x1ff];
-        let l3_desc = unsafe { &mut l3_desc.page };
-        if l3_desc.is_present() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size4K));
+/// Splits blocks into tables of the next level down, one per walk: when a
+/// walk ends at [`Walk::Block`], [`refill`](Self::refill) it and walk again.
+struct SplitBlocks {
+    /// The address being walked to.
+    virt: usize,
+    scope: Scope,
+    table: Option<PhysPtr<[Table; 512]>>,
+    /// Whether any block has been split.
+    split: bool,
+}
+impl SplitBlocks {
+    fn new(virt: usize, scope: Scope) -> Self {
+        Self {
+            virt,
+            scope,
+            table: None,
+            split: false,
         }
-
-        *l3_desc = Page::from_flags(flags);
-        l3_desc.set_addr(frame.addr);
-        l3_desc.set_present(true);
-
-        Ok(Flush(None))
     }
-
-    fn unmap(&mut self, page: VirtPage<Size4K>) -> Result<Self::Flush, MapError> {
+    async fn refill(&mut self, hhdm_start: usize) -> Result<(), MapError> {
+        if self.table.is_none() {
+            self.table = Some(PageTable::alloc_tables(hhdm_start).await?);
+        }
+        Ok(())
+    }
+}
+impl Visitor for SplitBlocks {
+    fn block(&mut self, level: usize, entry: &mut Entry) -> bool {
+        let Some(table) = self.table.take() else {
+            return false;
+        };
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
+        let data = entry.data();
+        let base = data & OUTPUT_ADDRESS;
+        let attrs = data & !OUTPUT_ADDRESS & !0b11;
+        let user = data & 1 << 6 != 0;
+        let child_level = level + 1;
+
+        let children = unsafe {
+            &mut *table
+                .cast::<[Entry; 512]>()
+                .to_virt_offset(hhdm_start)
+                .get()
         };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            return Ok(Flush(None));
-        }
+        for (index, child) in children.iter_mut().enumerate() {
+            let addr = base + (index * level_span(child_level)) as u64;
+            *child = Entry::leaf(child_level, addr | attrs);
+            occupy(child_level, child);
+            record_mapped(child_level, addr as usize, user);
+        }
+        record_unmapped(level, base as usize);
+
+        // Break before make: the block has to be gone from every TLB before
+        // the table replaces it.
+        entry.table = Table::new();
+        shootdown::flush_page(self.scope, self.virt);
+        let mut desc = Table::new();
+        desc.set_ptr(table.cast());
+        desc.set_present(true);
+        entry.table = desc;
+        self.split = true;
+        true
+    }
+}
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size1G));
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            return Ok(Flush(None));
-        }
+/// Gets a walk down to its level whatever's in the way, by filling in empty
+/// descriptors and splitting blocks.
+struct MakeRoom {
+    missing: AllocateMissing,
+    blocks: SplitBlocks,
+}
+impl Visitor for MakeRoom {
+    fn missing(&mut self, level: usize, entry: &mut Table) -> bool {
+        self.missing.missing(level, entry)
+    }
+    fn block(&mut self, level: usize, entry: &mut Entry) -> bool {
+        self.blocks.block(level, entry)
+    }
+}
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let mut l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if l2_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size2M));
-        }
-        let l2_desc = unsafe { &mut l2_desc.table };
-        if !l2_desc.is_present() {
-            return Ok(Flush(None));
+/// Records the descriptor at each level a walk passes through, on top of
+/// what `V` does.
+struct CollectPath<V> {
+    visitor: V,
+    path: [Option<*mut Entry>; 4],
+}
+impl<V: Visitor> CollectPath<V> {
+    fn new(visitor: V) -> Self {
+        Self {
+            visitor,
+            path: [None; 4],
         }
+    }
+}
+impl<V: Visitor> Visitor for CollectPath<V> {
+    fn visit(&mut self, level: usize, entry: &mut Entry) {
+        self.path[level] = Some(entry);
+        self.visitor.visit(level, entry);
+    }
+    fn missing(&mut self, level: usize, entry: &mut Table) -> bool {
+        self.visitor.missing(level, entry)
+    }
+    fn block(&mut self, level: usize, entry: &mut Entry) -> bool {
+        self.visitor.block(level, entry)
+    }
+}
 
-        let mut virt = l2_desc.get_addr().to_virt_offset(hhdm_start);
-        let l3 = unsafe { virt.as_mut() };
-        let mut l3_desc = &mut l3[virt_ptr >> 12 & 0x1ff];
-        let l3_desc = unsafe { &mut l3_desc.page };
-        if !l3_desc.is_present() {
-            return Ok(Flush(None));
-        }
+/// The descriptor `path` passed through at `level`.
+fn entry_at<V>(path: &CollectPath<V>, level: usize) -> *mut Entry {
+    path.path[level].expect("walk didn't reach level")
+}
 
-        l3_desc.set_present(false);
+/// Where a walk ended.
+enum Walk<'a> {
+    /// At the descriptor for the level it was after, present or not.
+    Target(&'a mut Entry),
+    /// At an empty descriptor above that level.
+    Missing(usize),
+    /// At a block above that level.
+    Block(usize, &'a mut Entry),
+}
 
-        Ok(Flush(Some(page)))
+impl PageTable {
+    /// The level 0 table translating `virt`.
+    fn root(&mut self, virt: usize) -> &mut [Entry; 512] {
+        let l0 = if is_user(virt) {
+            &mut self.user_l0
+        } else {
+            &mut self.kernel_l0
+        };
+        unsafe { &mut *(l0 as *mut [Table; 512]).cast() }
     }
 
-    fn translate(
-        &mut self,
-        page: VirtPage<Size4K>,
-    ) -> Result<(PhysPage<Size4K>, PageFlags), TranslateError> {
+    /// Walks down to `virt`'s descriptor at level `target`, asking `visitor`
+    /// what to do wherever the tables run out first.
+    fn walk(&mut self, virt: usize, target: usize, visitor: &mut impl Visitor) -> Walk<'_> {
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
-        };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            return Err(TranslateError::NotPresent);
+        let mut table: *mut [Entry; 512] = self.root(virt);
+        for level in 0..target {
+            let entry = unsafe { &mut (*table)[index(virt, level)] };
+            visitor.visit(level, entry);
+            if !entry.is_present() {
+                if !visitor.missing(level, unsafe { &mut entry.table }) {
+                    return Walk::Missing(level);
+                }
+            } else if !entry.is_table(level) && !visitor.block(level, entry) {
+                return Walk::Block(level, entry);
+            }
+            table = unsafe { entry.table.get_addr().to_virt_offset(hhdm_start).get() };
         }
+        let entry = unsafe { &mut (*table)[index(virt, target)] };
+        visitor.visit(target, entry);
+        Walk::Target(entry)
+    }
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(TranslateError::SizeMismatch(RuntimePageSize::Size1G));
+    /// The size of the mapping under a table descriptor for `virt`, taken to
+    /// be 4K if it's empty.
+    fn mapped_size(&mut self, virt: usize) -> RuntimePageSize {
+        match self.walk(virt, 3, &mut StopAtBlock) {
+            Walk::Block(level, _) => level_size(level),
+            _ => RuntimePageSize::Size4K,
         }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            return Err(TranslateError::NotPresent);
+    }
+}
+
+/// The number of bytes a descriptor at `level` covers.
+const fn level_span(level: usize) -> usize {
+    1 << (39 - 9 * level)
+}
+
+impl PageTable {
+    /// Maps `len` bytes at `virt` to `phys`, using the biggest pages their
+    /// alignment allows. If any of it is already mapped, whatever was mapped
+    /// by this call is unmapped again.
+    pub async fn map_range(
+        &mut self,
+        virt: VirtAddr,
+        phys: PhysAddr,
+        len: usize,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        let start = virt.get() as usize;
+        let phys = phys.get();
+        assert!(
+            (start | phys | len) % Size4K::size() == 0,
+            "unaligned range: {len:#x} bytes at {start:#x} to {phys:#x}"
+        );
+        let mut offset = 0;
+        while offset < len {
+            let virt = start + offset;
+            let phys = phys + offset;
+            // Largest first.
+            let level = (1..=3)
+                .find(|&level| {
+                    let span = level_span(level);
+                    virt % span == 0 && phys % span == 0 && len - offset >= span
+                })
+                .unwrap_or(3);
+            let result = match level {
+                1 => self.map_at::<Size1G>(virt, phys, flags).await,
+                2 => self.map_at::<Size2M>(virt, phys, flags).await,
+                _ => self.map_at::<Size4K>(virt, phys, flags).await,
+            };
+            if let Err(error) = result {
+                if let Ok(flush) = self
+                    .unmap_range(VirtAddr::new(start as *mut _), offset, false)
+                    .await
+                {
+                    flush.flush();
+                }
+                return Err(error);
+            }
+            offset += level_span(level);
         }
+        Ok(())
+    }
+    async fn map_at<Size: PageSize>(
+        &mut self,
+        virt: usize,
+        phys: usize,
+        flags: PageFlags,
+    ) -> Result<(), MapError> {
+        Mapper::<Size>::map(
+            self,
+            VirtPage::for_addr(VirtAddr::new(virt as *mut _)),
+            PhysPage::for_addr(PhysAddr::new(phys)),
+            flags,
+        )
+        .await
+        .map(CacheFlush::ignore)
+    }
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let mut l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if l2_desc.is_block() {
-            return Err(TranslateError::SizeMismatch(RuntimePageSize::Size2M));
+    /// Unmaps everything in `len` bytes at `virt`, splitting blocks that
+    /// stick out of the range.
+    ///
+    /// If `free_frames` is set, frames that nothing else maps are freed too.
+    /// That can't happen until no TLB holds them, so their part of the range
+    /// is flushed as it goes, and the flush returned only covers the rest.
+    pub async fn unmap_range(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        free_frames: bool,
+    ) -> Result<RangeFlush, MapError> {
+        let start = virt.get() as usize;
+        let end = start + len;
+        let scope = self.scope(start);
+        let mut flush = RangeFlush::new(scope);
+        // The frames of the leaves unmapped since the last flush.
+        let mut unmapped: heapless::Vec<(PhysAddr, usize), FREE_BATCH> = heapless::Vec::new();
+        let mut virt = start;
+        while let Some((leaf, level)) = self.next_leaf(virt, end).await? {
+            if free_frames {
+                let Walk::Target(entry) = self.walk(leaf, level, &mut StopAtBlock) else {
+                    unreachable!("leaf disappeared");
+                };
+                let addr = match level {
+                    3 => unsafe { entry.page.get_addr() },
+                    _ => unsafe { entry.block.get_addr(level) },
+                };
+                unmapped.push((addr, level)).ok().unwrap();
+            }
+            match level {
+                1 => self.unmap_at::<Size1G>(leaf).await?,
+                2 => self.unmap_at::<Size2M>(leaf).await?,
+                _ => self.unmap_at::<Size4K>(leaf).await?,
+            }
+            virt = leaf + level_span(level);
+            flush.add(leaf, virt);
+            if unmapped.is_full() {
+                core::mem::replace(&mut flush, RangeFlush::new(scope)).flush();
+                release_frames(&mut unmapped).await;
+            }
         }
-        let l2_desc = unsafe { &mut l2_desc.table };
-        if !l2_desc.is_present() {
-            return Err(TranslateError::NotPresent);
+        if !unmapped.is_empty() {
+            core::mem::replace(&mut flush, RangeFlush::new(scope)).flush();
+            release_frames(&mut unmapped).await;
         }
+        Ok(flush)
+    }
 
-        let mut virt = l2_desc.get_addr().to_virt_offset(hhdm_start);
-        let l3 = unsafe { virt.as_mut() };
-        let mut l3_desc = &mut l3[virt_ptr >> 12 & 0x1ff];
-        let l3_desc = unsafe { &mut l3_desc.page };
-        if !l3_desc.is_present() {
-            return Err(TranslateError::NotPresent);
-        }
+    async fn unmap_at<Size: PageSize>(&mut self, virt: usize) -> Result<(), MapError> {
+        Mapper::<Size>::unmap(self, VirtPage::for_addr(VirtAddr::new(virt as *mut _)))
+            .await
+            .map(CacheFlush::ignore)
+    }
 
-        let addr = l3_desc.get_addr();
-        let page = PhysPage::for_addr(addr);
+    /// Changes the flags of everything mapped in `len` bytes at `virt`,
+    /// splitting blocks that stick out of the range. Copy on write pages stay
+    /// that way, without write access: they only get it by being copied.
+    pub async fn protect_range(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        flags: PageFlags,
+    ) -> Result<RangeFlush, MapError> {
+        let start = virt.get() as usize;
+        let end = start + len;
+        let mut flush = RangeFlush::new(self.scope(start));
+        let mut virt = start;
+        while let Some((leaf, level)) = self.next_leaf(virt, end).await? {
+            let Walk::Target(entry) = self.walk(leaf, level, &mut StopAtBlock) else {
+                unreachable!("leaf disappeared");
+            };
+            let (_, old) = entry.leaf_mapping(level);
+            access::remap(entry, level, cow_preserving(old, flags), is_user(leaf));
+            virt = leaf + level_span(level);
+            flush.add(leaf, virt);
+        }
+        Ok(flush)
+    }
 
-        Ok((page, l3_desc.get_flags()))
+    /// Maps everything mapped in `len` bytes at `virt` into `child` as well,
+    /// at the same addresses. With `cow` set, both copies become copy on
+    /// write; otherwise they share the frames as they are.
+    pub async fn share_range(
+        &mut self,
+        child: &mut PageTable,
+        virt: VirtAddr,
+        len: usize,
+        cow: bool,
+    ) -> Result<RangeFlush, MapError> {
+        let start = virt.get() as usize;
+        let end = start + len;
+        let mut flush = RangeFlush::new(self.scope(start));
+        let mut virt = start;
+        while let Some((leaf, level)) = self.next_leaf(virt, end).await? {
+            let Walk::Target(entry) = self.walk(leaf, level, &mut StopAtBlock) else {
+                unreachable!("leaf disappeared");
+            };
+            let (addr, mut flags) = entry.leaf_mapping(level);
+            virt = leaf + level_span(level);
+            if cow && !flags.contains(PageFlags::COW) {
+                flags = (flags - PageFlags::WRITE) | PageFlags::COW;
+                access::remap(entry, level, flags, is_user(leaf));
+                flush.add(leaf, virt);
+            }
+            let result = match level {
+                1 => child.map_at::<Size1G>(leaf, addr.get(), flags).await,
+                2 => child.map_at::<Size2M>(leaf, addr.get(), flags).await,
+                _ => child.map_at::<Size4K>(leaf, addr.get(), flags).await,
+            };
+            if let Err(error) = result {
+                flush.flush();
+                return Err(error);
+            }
+        }
+        Ok(flush)
     }
-}
-impl Mapper<Size2M> for PageTable {
-    type Flush = Flush<Size2M>;
 
-    async fn map(
+    /// Gives the copy on write page at `virt` the flags `flags`, copying its
+    /// frame first if anything else maps it. Does nothing if it isn't copy
+    /// on write.
+    pub async fn break_cow(
         &mut self,
-        page: VirtPage<Size2M>,
-        frame: PhysPage<Size2M>,
+        virt: VirtAddr,
         flags: PageFlags,
-    ) -> Result<Self::Flush, MapError> {
+    ) -> Result<Flush<Size4K>, MapError> {
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
+        let virt = virt.get() as usize & !(Size4K::size() - 1);
+        let scope = self.scope(virt);
+        let entry = loop {
+            match self.walk(virt, 3, &mut StopAtBlock) {
+                Walk::Target(entry) => break entry,
+                Walk::Missing(_) => return Ok(Flush::none()),
+                Walk::Block(..) => self.split(virt).await?,
+            }
         };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            l0_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l0_desc.set_present(true);
+        let (addr, old) = entry.leaf_mapping(3);
+        if !entry.is_present() || !old.contains(PageFlags::COW) {
+            return Ok(Flush::none());
+        }
+        let flags = flags - PageFlags::COW;
+        let user = flags.contains(PageFlags::USER_ACCESS);
+        let page = PhysPage::<Size4K>::for_addr(addr);
+        let shared = frames::frame(page).map_or(true, |frame| frame.refcount() > 1);
+        if !shared {
+            // Nothing else can see it, so it just becomes writable.
+            *entry = Entry::mapping(3, addr, flags);
+            if is_user(virt) {
+                entry.set_not_global();
+            }
+            return Ok(Flush::page(scope, virt));
         }
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size1G));
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            l1_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l1_desc.set_present(true);
-        }
+        let phys_alloc = PHYS_ALLOC.get().ok_or(MapError::NoPhysAlloc)?;
+        let copy = phys_alloc.alloc().await.ok_or(MapError::OutOfMem)?;
+        let copy_addr = copy.addr();
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                addr.to_virt_offset(hhdm_start).get() as *const u8,
+                copy_addr.to_virt_offset(hhdm_start).get() as *mut u8,
+                Size4K::size(),
+            );
+        }
+        // Break before make, since the address changes.
+        unsafe { entry.page.set_present(false) };
+        shootdown::flush_page(scope, virt);
+        *entry = Entry::mapping(3, copy_addr, flags);
+        if is_user(virt) {
+            entry.set_not_global();
+        }
+        frames::mapped(copy, user);
+        if frames::unmapped(page) == 0 {
+            // The other mapping went away while this was copying.
+            release_frame::<Size4K>(addr).await;
+        }
+        Ok(Flush::none())
+    }
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if !l2_desc.is_block() && l2_desc.is_present() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size4K));
-        }
-        let l2_desc = unsafe { &mut l2_desc.block };
-        if l2_desc.is_present() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size2M));
+    /// The address and level of the first page or block mapped in
+    /// `[virt, end)`. Blocks that stick out of the range are split first.
+    async fn next_leaf(
+        &mut self,
+        mut virt: usize,
+        end: usize,
+    ) -> Result<Option<(usize, usize)>, MapError> {
+        while virt < end {
+            let level = match self.walk(virt, 3, &mut StopAtBlock) {
+                Walk::Missing(level) => {
+                    // Nothing under this whole descriptor.
+                    match (virt | (level_span(level) - 1)).checked_add(1) {
+                        Some(next) => virt = next,
+                        None => break,
+                    }
+                    continue;
+                }
+                Walk::Target(entry) if !entry.is_present() => {
+                    virt += level_span(3);
+                    continue;
+                }
+                Walk::Target(_) => 3,
+                Walk::Block(level, _) => level,
+            };
+            let span = level_span(level);
+            if virt % span != 0 || end - virt < span {
+                self.split(virt).await?;
+                continue;
+            }
+            return Ok(Some((virt, level)));
         }
-
-        *l2_desc = Block::from_flags(flags);
-        l2_desc.set_addr(frame.addr, 2);
-        l2_desc.set_present(true);
-
-        Ok(Flush(None))
+        Ok(None)
     }
 
-    fn unmap(&mut self, page: VirtPage<Size2M>) -> Result<Self::Flush, MapError> {
+    /// Splits the block `virt` is in into a table of smaller pages with the
+    /// same flags.
+    async fn split(&mut self, virt: usize) -> Result<(), MapError> {
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
+        let Walk::Block(level, _) = self.walk(virt, 3, &mut StopAtBlock) else {
+            return Ok(());
         };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            return Ok(Flush(None));
+        let mut split = SplitBlocks::new(virt, self.scope(virt));
+        while let Walk::Block(..) = self.walk(virt, level + 1, &mut split) {
+            split.refill(hhdm_start).await?;
         }
+        Ok(())
+    }
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size1G));
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            return Ok(Flush(None));
-        }
+    /// Replaces the tables covering `virt` with blocks wherever they map
+    /// contiguous memory with the same flags throughout, 2 MiB first and then
+    /// 1 GiB. Returns whether anything changed.
+    pub async fn collapse(&mut self, virt: VirtAddr) -> bool {
+        let hhdm_start = *HHDM_START.get().unwrap();
+        let virt = virt.get() as usize;
+        let mut collapsed = false;
+        for level in [2, 1] {
+            let span = level_span(level);
+            let base = virt & !(span - 1);
+            let scope = self.scope(base);
+            let Walk::Target(entry) = self.walk(base, level, &mut StopAtBlock) else {
+                break;
+            };
+            if !entry.is_present() || !entry.is_table(level) {
+                break;
+            }
+            let table = unsafe { entry.table.get_addr() };
+            let children = unsafe { &*table.to_virt_offset(hhdm_start).get() };
+            let child_level = level + 1;
+            let child_span = level_span(child_level);
+            let first = children[0].data();
+            let phys = first & OUTPUT_ADDRESS;
+            let attrs = first & !OUTPUT_ADDRESS & !0b11;
+            let uniform = phys % span as u64 == 0
+                && children.iter().enumerate().all(|(index, child)| {
+                    child.data()
+                        == Entry::leaf(child_level, phys + (index * child_span) as u64 | attrs)
+                            .data()
+                });
+            if !uniform {
+                break;
+            }
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if !l2_desc.is_block() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size4K));
-        }
-        let l2_desc = unsafe { &mut l2_desc.block };
-        if !l2_desc.is_present() {
-            return Ok(Flush(None));
+            // Break before make, again.
+            entry.table = Table::new();
+            let mut flush = RangeFlush::new(scope);
+            flush.add(base, base + span);
+            flush.flush();
+            *entry = Entry::leaf(level, phys | attrs);
+
+            let user = attrs & 1 << 6 != 0;
+            for (index, child) in children.iter().enumerate() {
+                record_unmapped(child_level, phys as usize + index * child_span);
+                vacate(child_level, child);
+            }
+            record_mapped(level, phys as usize, user);
+            free_table(table).await;
+            collapsed = true;
         }
+        collapsed
+    }
 
-        l2_desc.set_present(false);
+    /// Unmaps and frees everything in the user half: every page table, and
+    /// if `free_frames` is set, every mapped frame that nothing else maps.
+    /// The user half is left empty.
+    ///
+    /// It must not be in use on any CPU.
+    pub async fn destroy(&mut self, free_frames: bool) -> RangeFlush {
+        let hhdm_start = *HHDM_START.get().unwrap();
+        let mut flush = RangeFlush::new(self.asid.scope());
+        for index in 0..512 {
+            let l0_desc = &mut self.user_l0[index];
+            if !l0_desc.is_present() {
+                continue;
+            }
+            let l1 = l0_desc.get_addr();
+            *l0_desc = Table::new();
+            flush.add(index * level_span(0), (index + 1) * level_span(0));
+
+            // Depth first, with the next index to look at in each table.
+            let mut stack: heapless::Vec<(PhysPtr<[Entry; 512]>, usize, usize), 3> =
+                heapless::Vec::new();
+            stack.push((l1, 1, 0)).ok().unwrap();
+            while let Some(top) = stack.last_mut() {
+                let (table, level, index) = *top;
+                if index == 512 {
+                    stack.pop();
+                    free_table(table).await;
+                    continue;
+                }
+                top.2 += 1;
+
+                let entry = unsafe { &mut (*table.to_virt_offset(hhdm_start).get())[index] };
+                if !entry.is_present() {
+                    continue;
+                }
+                if entry.is_table(level) {
+                    let child = unsafe { entry.table.get_addr() };
+                    entry.table = Table::new();
+                    stack.push((child, level + 1, 0)).ok().unwrap();
+                } else {
+                    free_leaf(entry, level, free_frames).await;
+                }
+                vacate(level, entry);
+            }
+        }
+        flush
+    }
+}
 
-        Ok(Flush(Some(page)))
+/// `flags`, but still copy on write if `old` was.
+fn cow_preserving(old: PageFlags, flags: PageFlags) -> PageFlags {
+    if old.contains(PageFlags::COW) {
+        (flags - PageFlags::WRITE) | PageFlags::COW
+    } else {
+        flags
     }
+}
 
-    fn translate(
-        &mut self,
-        page: VirtPage<Size2M>,
-    ) -> Result<(PhysPage<Size2M>, PageFlags), TranslateError> {
-        let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
+/// The frame of the table `entry` is in, whose refcount is the number of
+/// present entries in it. Level 0 tables are part of the [`PageTable`], and
+/// aren't counted.
+fn table_frame(level: usize, entry: *const Entry) -> Option<&'static Frame> {
+    if level == 0 {
+        return None;
+    }
+    let hhdm_start = *HHDM_START.get().unwrap();
+    frames::frame(PhysPage::<Size4K>::for_addr(PhysAddr::new(
+        entry as usize - hhdm_start,
+    )))
+}
+/// Counts `entry` at `level` as present.
+fn occupy(level: usize, entry: *const Entry) {
+    if let Some(frame) = table_frame(level, entry) {
+        frame.get();
+    }
+}
+/// Counts `entry` at `level` as no longer present, returning whether its
+/// table is now empty.
+fn vacate(level: usize, entry: *const Entry) -> bool {
+    table_frame(level, entry).is_some_and(|frame| frame.put() == 0)
+}
 
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
-        };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            return Err(TranslateError::NotPresent);
-        }
+/// Records a mapping of the page or block at `level` of the frame at `addr`.
+fn record_mapped(level: usize, addr: usize, user: bool) {
+    let addr = PhysAddr::new(addr);
+    match level {
+        1 => frames::mapped(PhysPage::<Size1G>::for_addr(addr), user),
+        2 => frames::mapped(PhysPage::<Size2M>::for_addr(addr), user),
+        _ => frames::mapped(PhysPage::<Size4K>::for_addr(addr), user),
+    };
+}
+/// Records that a mapping of the page or block at `level` of the frame at
+/// `addr` is gone.
+fn record_unmapped(level: usize, addr: usize) {
+    let addr = PhysAddr::new(addr);
+    match level {
+        1 => frames::unmapped(PhysPage::<Size1G>::for_addr(addr)),
+        2 => frames::unmapped(PhysPage::<Size2M>::for_addr(addr)),
+        _ => frames::unmapped(PhysPage::<Size4K>::for_addr(addr)),
+    };
+}
 
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if l1_desc.is_block() {
-            return Err(TranslateError::SizeMismatch(RuntimePageSize::Size1G));
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.table };
-        if !l1_desc.is_present() {
-            return Err(TranslateError::NotPresent);
-        }
+async fn free_table(table: PhysPtr<[Entry; 512]>) {
+    PHYS_ALLOC
+        .get()
+        .unwrap()
+        .free(PhysPage::for_addr(PhysAddr::new(table.get())))
+        .await;
+}
 
-        let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-        let l2 = unsafe { virt.as_mut() };
-        let l2_desc = &mut l2[virt_ptr >> 21 & 0x1ff];
-        if !l2_desc.is_block() {
-            return Err(TranslateError::SizeMismatch(RuntimePageSize::Size2M));
+/// Clears the page or block `entry` at `level`, and frees its frame if
+/// `free_frames` is set and nothing else maps it.
+async fn free_leaf(entry: &mut Entry, level: usize, free_frames: bool) {
+    match level {
+        1 => free_frame::<Size1G>(unsafe { entry.block.get_addr(1) }, free_frames).await,
+        2 => free_frame::<Size2M>(unsafe { entry.block.get_addr(2) }, free_frames).await,
+        _ => free_frame::<Size4K>(unsafe { entry.page.get_addr() }, free_frames).await,
+    }
+    unsafe { entry.page.set_present(false) };
+}
+async fn free_frame<Size: PageSize + Copy>(addr: PhysAddr, free_frames: bool) {
+    frames::unmapped(PhysPage::<Size>::for_addr(addr));
+    if free_frames {
+        release_frame::<Size>(addr).await;
+    }
+}
+/// Frees the frame of an unmapped page or block at `addr` if nothing maps it
+/// any more. Part of a block may still be mapped as smaller pages, if it was
+/// split elsewhere; then only the frames nothing maps are freed, and the
+/// rest go when their pages do.
+async fn release_frame<Size: PageSize + Copy>(addr: PhysAddr) {
+    let page = PhysPage::<Size>::for_addr(addr);
+    let frames = frames::frames(page.addr(), Size::size());
+    let free = |frame: &Frame| {
+        frame.refcount() == 0
+            && !frame
+                .flags()
+                .intersects(FrameFlags::RESERVED | FrameFlags::PINNED)
+    };
+    if frames.is_empty() {
+        // Not RAM.
+        return;
+    }
+    let phys_alloc = PHYS_ALLOC.get().unwrap();
+    if frames.iter().all(free) {
+        phys_alloc.free_page(page).await;
+        return;
+    }
+    for (index, frame) in frames.iter().enumerate() {
+        if free(frame) {
+            let addr = PhysAddr::new(page.addr().get() + index * Size4K::size());
+            phys_alloc.free(PhysPage::for_addr(addr)).await;
         }
-        let l2_desc = unsafe { &mut l2_desc.block };
-        if !l2_desc.is_present() {
-            return Err(TranslateError::NotPresent);
+    }
+}
+/// Releases the frames of the leaves in `unmapped`, each with its level,
+/// leaving it empty.
+async fn release_frames(unmapped: &mut heapless::Vec<(PhysAddr, usize), FREE_BATCH>) {
+    while let Some((addr, level)) = unmapped.pop() {
+        match level {
+            1 => release_frame::<Size1G>(addr).await,
+            2 => release_frame::<Size2M>(addr).await,
+            _ => release_frame::<Size4K>(addr).await,
         }
-
-        Ok((PhysPage::for_addr(l2_desc.get_addr(2)), l2_desc.get_flags()))
     }
 }
-impl Mapper<Size1G> for PageTable {
-    type Flush = Flush<Size1G>;
+
+impl<Size: PageSize> Mapper<Size> for PageTable {
+    type Flush = Flush<Size>;
 
     async fn map(
         &mut self,
-        page: VirtPage<Size1G>,
-        frame: PhysPage<Size1G>,
+        page: VirtPage<Size>,
+        frame: PhysPage<Size>,
         flags: PageFlags,
     ) -> Result<Self::Flush, MapError> {
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
+        let virt = page.addr.get() as usize;
+        let level = leaf_level::<Size>();
 
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
+        let scope = self.scope(virt);
+
+        // Part of a bigger block gets split out of it, and replaced.
+        let mut room = MakeRoom {
+            missing: AllocateMissing::default(),
+            blocks: SplitBlocks::new(virt, scope),
+        };
+        let entry: *mut Entry = loop {
+            match self.walk(virt, level, &mut room) {
+                Walk::Target(entry) => break entry,
+                Walk::Missing(_) => room.missing.refill(hhdm_start).await?,
+                Walk::Block(..) => room.blocks.refill(hhdm_start).await?,
+            }
         };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            l0_desc.set_ptr(Self::alloc_tables(hhdm_start).await?.cast());
-            l0_desc.set_present(true);
-        }
-
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if !l1_desc.is_block() && l1_desc.is_present() {
-            let l1_desc = unsafe { &mut l1_desc.table };
-            let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-            let l2 = unsafe { virt.as_mut() };
-            if l2[virt_ptr >> 21 & 0x1ff].is_block() {
-                return Err(MapError::AlreadyMapped(RuntimePageSize::Size2M));
-            } else {
-                return Err(MapError::AlreadyMapped(RuntimePageSize::Size4K));
+        let entry = unsafe { &mut *entry };
+        if entry.is_present() {
+            if !room.blocks.split || entry.is_table(level) {
+                return Err(MapError::AlreadyMapped(if entry.is_table(level) {
+                    self.mapped_size(virt)
+                } else {
+                    level_size(level)
+                }));
             }
+            // Break before make, since the address changes.
+            let (old, _) = entry.leaf_mapping(level);
+            unsafe { entry.page.set_present(false) };
+            shootdown::flush_page(scope, virt);
+            record_unmapped(level, old.get());
+            vacate(level, entry);
+        }
+
+        let addr = frame.addr;
+        frames::mapped(frame, flags.contains(PageFlags::USER_ACCESS));
+        if level == 3 {
+            let mut page = Page::from_flags(flags);
+            page.set_addr(addr);
+            page.set_present(true);
+            entry.page = page;
+        } else {
+            let mut block = Block::from_flags(flags);
+            block.set_addr(addr, level);
+            block.set_present(true);
+            entry.block = block;
         }
-        let mut l1_desc = unsafe { &mut l1_desc.block };
-        if l1_desc.is_present() {
-            return Err(MapError::AlreadyMapped(RuntimePageSize::Size1G));
+        if is_user(virt) {
+            entry.set_not_global();
         }
+        occupy(level, entry);
 
-        *l1_desc = Block::from_flags(flags);
-        l1_desc.set_addr(frame.addr, 1);
-        l1_desc.set_present(true);
-
-        Ok(Flush(None))
+        Ok(Flush::none())
     }
 
-    fn unmap(&mut self, page: VirtPage<Size1G>) -> Result<Self::Flush, MapError> {
+    async fn unmap(&mut self, page: VirtPage<Size>) -> Result<Self::Flush, MapError> {
         let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
-        };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
-            return Ok(Flush(None));
-        }
-
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if !l1_desc.is_block() && l1_desc.is_present() {
-            let l1_desc = unsafe { &mut l1_desc.table };
-            let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-            let l2 = unsafe { virt.as_mut() };
-            if l2[virt_ptr >> 21 & 0x1ff].is_block() {
-                return Err(MapError::AlreadyMapped(RuntimePageSize::Size2M));
-            } else {
-                return Err(MapError::AlreadyMapped(RuntimePageSize::Size4K));
+        let virt = page.addr.get() as usize;
+        let level = leaf_level::<Size>();
+
+        // Part of a bigger block gets split out of it.
+        let mut path = CollectPath::new(SplitBlocks::new(virt, self.scope(virt)));
+        let entry: *mut Entry = loop {
+            match self.walk(virt, level, &mut path) {
+                Walk::Target(entry) => break entry,
+                Walk::Missing(_) => return Ok(Flush::none()),
+                Walk::Block(..) => path.visitor.refill(hhdm_start).await?,
             }
+        };
+        let entry = unsafe { &mut *entry };
+        if !entry.is_present() {
+            return Ok(Flush::none());
         }
-        let mut l1_desc = unsafe { &mut l1_desc.block };
-        if !l1_desc.is_present() {
-            return Ok(Flush(None));
+        if entry.is_table(level) {
+            return Err(MapError::AlreadyMapped(self.mapped_size(virt)));
         }
 
-        l1_desc.set_present(false);
+        if level == 3 {
+            let page = unsafe { &mut entry.page };
+            page.set_present(false);
+            frames::unmapped(PhysPage::<Size>::for_addr(page.get_addr()));
+        } else {
+            let block = unsafe { &mut entry.block };
+            block.set_present(false);
+            frames::unmapped(PhysPage::<Size>::for_addr(block.get_addr(level)));
+        }
 
-        Ok(Flush(Some(page)))
+        // Free the tables this leaves empty, bottom up.
+        let mut level = level;
+        while vacate(level, entry_at(&path, level)) {
+            let parent = unsafe { &mut *entry_at(&path, level - 1) };
+            let table = unsafe { parent.table.get_addr() };
+            parent.table = Table::new();
+            // No walk may reach the table once it's freed.
+            shootdown::flush_page(self.scope(virt), virt);
+            free_table(table).await;
+            level -= 1;
+        }
+
+        Ok(Flush::page(self.scope(virt), virt))
     }
 
     fn translate(
         &mut self,
-        page: VirtPage<Size1G>,
-    ) -> Result<(PhysPage<Size1G>, PageFlags), TranslateError> {
-        let hhdm_start = *HHDM_START.get().unwrap();
-        let virt_ptr = page.addr.get() as usize;
-
-        let l0 = if virt_ptr & 1 << 48 > 0 {
-            &mut self.kernel_l0
-        } else {
-            &mut self.user_l0
+        page: VirtPage<Size>,
+    ) -> Result<(PhysPage<Size>, PageFlags), TranslateError> {
+        let virt = page.addr.get() as usize;
+        let level = leaf_level::<Size>();
+
+        let entry: *mut Entry = match self.walk(virt, level, &mut StopAtBlock) {
+            Walk::Target(entry) => entry,
+            Walk::Missing(_) => return Err(TranslateError::NotPresent),
+            Walk::Block(level, _) => return Err(TranslateError::SizeMismatch(level_size(level))),
         };
-        let mut l0_desc = &mut l0[virt_ptr >> 39 & 0x1ff];
-        if !l0_desc.is_present() {
+        let entry = unsafe { &mut *entry };
+        if !entry.is_present() {
             return Err(TranslateError::NotPresent);
         }
-
-        let mut virt = l0_desc.get_addr().to_virt_offset(hhdm_start);
-        let l1 = unsafe { virt.as_mut() };
-        let mut l1_desc = &mut l1[virt_ptr >> 30 & 0x1ff];
-        if !l1_desc.is_block() && l1_desc.is_present() {
-            let l1_desc = unsafe { &mut l1_desc.table };
-            let mut virt = l1_desc.get_addr().to_virt_offset(hhdm_start);
-            let l2 = unsafe { virt.as_mut() };
-            if l2[virt_ptr >> 21 & 0x1ff].is_block() {
-                return Err(TranslateError::SizeMismatch(RuntimePageSize::Size2M));
-            } else {
-                return Err(TranslateError::SizeMismatch(RuntimePageSize::Size4K));
-            }
-        }
-        let mut l1_desc = unsafe { &mut l1_desc.block };
-        if !l1_desc.is_present() {
-            return Err(TranslateError::NotPresent);
+        if entry.is_table(level) {
+            return Err(TranslateError::SizeMismatch(self.mapped_size(virt)));
         }
 
-        Ok((PhysPage::for_addr(l1_desc.get_addr(1)), l1_desc.get_flags()))
+        if level == 3 {
+            let page = unsafe { &mut entry.page };
+            Ok((PhysPage::for_addr(page.get_addr()), page.get_flags()))
+        } else {
+            let block = unsafe { &mut entry.block };
+            Ok((PhysPage::for_addr(block.get_addr(level)), block.get_flags()))
+        }
     }
 }
 
 #[derive(Copy, Clone)]
+#[repr(C)]
 union Entry {
     table: Table,
     block: Block,
     page: Page,
 }
 impl Entry {
-    pub fn is_block(&self) -> bool {
-        (unsafe { core::mem::transmute::<_, u64>(*self) }) & 0b10 > 0
+    /// A page or block descriptor at `level`, from its address and
+    /// attributes.
+    const fn leaf(level: usize, data: u64) -> Self {
+        let kind = if level == 3 { 0b11 } else { 0b01 };
+        Self {
+            table: Table { data: data | kind },
+        }
     }
-    pub fn is_present(&self) -> bool {
-        (unsafe { core::mem::transmute::<_, u64>(*self) }) & 1 > 0
+    /// A present page or block descriptor at `level`, mapping `addr` with
+    /// `flags`.
+    fn mapping(level: usize, addr: PhysAddr, flags: PageFlags) -> Self {
+        if level == 3 {
+            let mut page = Page::from_flags(flags);
+            page.set_addr(addr);
+            page.set_present(true);
+            Self { page }
+        } else {
+            let mut block = Block::from_flags(flags);
+            block.set_addr(addr, level);
+            block.set_present(true);
+            Self { block }
+        }
+    }
+    /// The address and flags of the page or block at `level`.
+    fn leaf_mapping(&self, level: usize) -> (PhysAddr, PageFlags) {
+        if level == 3 {
+            let mut page = unsafe { self.page };
+            (page.get_addr(), page.get_flags())
+        } else {
+            let mut block = unsafe { self.block };
+            (block.get_addr(level), block.get_flags())
+        }
+    }
+    /// Tags a page or block's TLB entries with the ASID, for the user half.
+    fn set_not_global(&mut self) {
+        unsafe { self.table.data |= NOT_GLOBAL };
+    }
+    const fn data(&self) -> u64 {
+        unsafe { self.table.data }
+    }
+    /// Whether this points to a table. At level 3, the same bit marks a page.
+    pub const fn is_table(&self, level: usize) -> bool {
+        level < 3 && self.data() & 0b10 != 0
+    }
+    pub const fn is_present(&self) -> bool {
+        self.data() & 1 != 0
     }
 }
 
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 struct Table {
     data: u64,
 }
@@ -549,6 +1261,7 @@ impl Table {
 }
 
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 struct Block {
     data: u64,
 }
@@ -561,8 +1274,11 @@ impl Block {
         if flags.contains(PageFlags::USER_ACCESS) {
             page.data |= 1 << 6;
         }
-        if !flags.contains(PageFlags::WRITE) {
-            page.data |= 1 << 7;
+        if flags.contains(PageFlags::WRITE) {
+            // Starts out dirty.
+            page.data |= DIRTY_BIT_MODIFIER;
+        } else {
+            page.data |= READ_ONLY;
         }
         if !flags.contains(PageFlags::USER_EXEC) {
             page.data |= 1 << 54;
@@ -570,20 +1286,24 @@ impl Block {
         if !flags.contains(PageFlags::KERNEL_EXEC) {
             page.data |= 1 << 53;
         }
+        if flags.contains(PageFlags::COW) {
+            page.data |= COPY_ON_WRITE;
+        }
+        page.data |= memory_attributes(&flags);
         page
     }
 
     fn set_addr(&mut self, ptr: PhysAddr, level: usize) {
         match level {
-            1 => self.data |= ptr.get() as u64 & 0x0000_ffff_8000_0000,
-            2 => self.data |= ptr.get() as u64 & 0x0000_ffff_ffc0_0000,
+            1 => self.data |= ptr.get() as u64 & 0x0000_ffff_c000_0000,
+            2 => self.data |= ptr.get() as u64 & 0x0000_ffff_ffe0_0000,
             _ => panic!("Invalid level"),
         }
     }
     fn get_addr(&mut self, level: usize) -> PhysAddr {
         match level {
-            1 => PhysAddr::new((self.data & 0x0000_ffff_8000_0000) as usize),
-            2 => PhysAddr::new((self.data & 0x0000_ffff_ffc0_0000) as usize),
+            1 => PhysAddr::new((self.data & 0x0000_ffff_c000_0000) as usize),
+            2 => PhysAddr::new((self.data & 0x0000_ffff_ffe0_0000) as usize),
             _ => panic!("Invalid level"),
         }
     }
@@ -603,7 +1323,7 @@ impl Block {
         if self.data & (1 << 6) > 0 {
             flags.insert(PageFlags::USER_ACCESS);
         }
-        if self.data & (1 << 7) == 0 {
+        if self.data & (READ_ONLY | DIRTY_BIT_MODIFIER) != READ_ONLY {
             flags.insert(PageFlags::WRITE);
         }
         if self.data & (1 << 54) == 0 {
@@ -612,14 +1332,20 @@ impl Block {
         if self.data & (1 << 53) == 0 {
             flags.insert(PageFlags::KERNEL_EXEC);
         }
-        if self.data & (1 << 51) > 0 {
+        if self.data & (READ_ONLY | DIRTY_BIT_MODIFIER) == DIRTY_BIT_MODIFIER {
             flags.insert(PageFlags::DIRTY);
         }
-        flags
+        if self.data & COPY_ON_WRITE > 0 {
+            flags.insert(PageFlags::COW);
+        }
+        flags.with_memory_type(memory_type(
+            (self.data & ATTR_INDEX_MASK) >> ATTR_INDEX_SHIFT,
+        ))
     }
 }
 
 #[derive(Copy, Clone)]
+#[repr(transparent)]
 struct Page {
     data: u64,
 }
@@ -632,8 +1358,11 @@ impl Page {
         if flags.contains(PageFlags::USER_ACCESS) {
             page.data |= 1 << 6;
         }
-        if !flags.contains(PageFlags::WRITE) {
-            page.data |= 1 << 7;
+        if flags.contains(PageFlags::WRITE) {
+            // Starts out dirty.
+            page.data |= DIRTY_BIT_MODIFIER;
+        } else {
+            page.data |= READ_ONLY;
         }
         if !flags.contains(PageFlags::USER_EXEC) {
             page.data |= 1 << 54;
@@ -641,6 +1370,10 @@ impl Page {
         if !flags.contains(PageFlags::KERNEL_EXEC) {
             page.data |= 1 << 53;
         }
+        if flags.contains(PageFlags::COW) {
+            page.data |= COPY_ON_WRITE;
+        }
+        page.data |= memory_attributes(&flags);
         page
     }
 
@@ -666,7 +1399,7 @@ impl Page {
         if self.data & (1 << 6) > 0 {
             flags.insert(PageFlags::USER_ACCESS);
         }
-        if self.data & (1 << 7) == 0 {
+        if self.data & (READ_ONLY | DIRTY_BIT_MODIFIER) != READ_ONLY {
             flags.insert(PageFlags::WRITE);
         }
         if self.data & (1 << 54) == 0 {
@@ -675,9 +1408,14 @@ impl Page {
         if self.data & (1 << 53) == 0 {
             flags.insert(PageFlags::KERNEL_EXEC);
         }
-        if self.data & (1 << 51) > 0 {
+        if self.data & (READ_ONLY | DIRTY_BIT_MODIFIER) == DIRTY_BIT_MODIFIER {
             flags.insert(PageFlags::DIRTY);
         }
-        flags
+        if self.data & COPY_ON_WRITE > 0 {
+            flags.insert(PageFlags::COW);
+        }
+        flags.with_memory_type(memory_type(
+            (self.data & ATTR_INDEX_MASK) >> ATTR_INDEX_SHIFT,
+        ))
     }
 }
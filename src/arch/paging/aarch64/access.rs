@@ -0,0 +1,167 @@
+//! The access flag and dirty state of pages, kept up to date by the MMU with
+//! FEAT_HAFDBS, or by faults without it.
+//!
+//! Writable pages are mapped with DBM set. Clearing their dirty state makes
+//! them read-only; a write then either makes them writable again in
+//! hardware, or faults so that [`PageTable::mark_dirty`] can. Likewise a
+//! page whose access flag is cleared gets it back from hardware, or faults
+//! into [`PageTable::mark_accessed`].
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use super::{
+    level_span, Entry, PageFlags, PageTable, RangeFlush, StopAtBlock, Walk, ACCESSED, NOT_GLOBAL,
+};
+use crate::kernel::memory::address::VirtAddr;
+
+/// DBM: the page is writable, even when AP[2] says it's read-only.
+pub(super) const DIRTY_BIT_MODIFIER: u64 = 1 << 51;
+/// AP[2]: the page is read-only.
+pub(super) const READ_ONLY: u64 = 1 << 7;
+
+static HARDWARE_ACCESS: AtomicBool = AtomicBool::new(false);
+static HARDWARE_DIRTY: AtomicBool = AtomicBool::new(false);
+
+/// Enables hardware updates of the access flag and dirty state, as far as
+/// the CPU has them.
+///
+/// # Safety
+/// Must be called once, before anything clears either of them.
+pub unsafe fn init() {
+    let mmfr1: u64;
+    asm!("mrs {}, id_aa64mmfr1_el1", out(reg) mmfr1, options(nomem, nostack));
+    let (access, dirty) = match mmfr1 & 0xf {
+        0 => (false, false),
+        1 => (true, false),
+        _ => (true, true),
+    };
+    if !access {
+        return;
+    }
+    let mut tcr: u64;
+    asm!("mrs {}, tcr_el1", out(reg) tcr, options(nomem, nostack));
+    // TCR_EL1.HA and HD
+    tcr |= 1 << 39;
+    if dirty {
+        tcr |= 1 << 40;
+    }
+    asm!("msr tcr_el1, {}", "isb", in(reg) tcr, options(nostack));
+    HARDWARE_ACCESS.store(access, Ordering::Relaxed);
+    HARDWARE_DIRTY.store(dirty, Ordering::Relaxed);
+}
+
+/// Whether the MMU sets the access flag itself.
+pub fn hardware_access() -> bool {
+    HARDWARE_ACCESS.load(Ordering::Relaxed)
+}
+/// Whether the MMU marks writable pages dirty itself.
+pub fn hardware_dirty() -> bool {
+    HARDWARE_DIRTY.load(Ordering::Relaxed)
+}
+
+/// `entry`'s descriptor, which the MMU may update under us.
+fn descriptor(entry: &mut Entry) -> &AtomicU64 {
+    unsafe { AtomicU64::from_ptr(&mut entry.table.data) }
+}
+
+/// Gives the page or block `entry` at `level` new `flags`, tagging it with the
+/// ASID if `not_global`. The MMU may be updating it at the same time, so this
+/// compare-exchanges rather than stores, and keeps what it saw: the access
+/// flag stays clear if it was, and a page that stays writable stays clean.
+pub(super) fn remap(entry: &mut Entry, level: usize, flags: PageFlags, not_global: bool) {
+    let (addr, _) = entry.leaf_mapping(level);
+    let mut template = Entry::mapping(level, addr, flags).data();
+    if not_global {
+        template |= NOT_GLOBAL;
+    }
+    let descriptor = descriptor(entry);
+    let mut old = descriptor.load(Ordering::Relaxed);
+    loop {
+        let mut new = template;
+        if old & ACCESSED == 0 {
+            new &= !ACCESSED;
+        }
+        if old & new & DIRTY_BIT_MODIFIER != 0 {
+            new |= old & READ_ONLY;
+        }
+        match descriptor.compare_exchange_weak(old, new, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => old = actual,
+        }
+    }
+}
+
+impl PageTable {
+    /// The page or block mapping `virt`, with its level and address.
+    fn leaf(&mut self, virt: usize) -> Option<(&mut Entry, usize, usize)> {
+        match self.walk(virt, 3, &mut StopAtBlock) {
+            Walk::Target(entry) if entry.is_present() => {
+                Some((entry, 3, virt & !(level_span(3) - 1)))
+            }
+            Walk::Block(level, entry) => Some((entry, level, virt & !(level_span(level) - 1))),
+            _ => None,
+        }
+    }
+
+    /// Whether the page or block mapping `virt` has been accessed since this
+    /// was last called on it. If it has, its TLB entries need to go before
+    /// the next access is noticed, so it's added to `flush`.
+    pub fn test_and_clear_accessed(&mut self, virt: VirtAddr, flush: &mut RangeFlush) -> bool {
+        let Some((entry, level, base)) = self.leaf(virt.get() as usize) else {
+            return false;
+        };
+        let old = descriptor(entry).fetch_and(!ACCESSED, Ordering::Relaxed);
+        let accessed = old & ACCESSED != 0;
+        if accessed {
+            flush.add(base, base + level_span(level));
+        }
+        accessed
+    }
+
+    /// Whether the page or block mapping `virt` has been written to since
+    /// this was last called on it. If it has, it's added to `flush`, like
+    /// [`test_and_clear_accessed`](Self::test_and_clear_accessed).
+    pub fn test_and_clear_dirty(&mut self, virt: VirtAddr, flush: &mut RangeFlush) -> bool {
+        let Some((entry, level, base)) = self.leaf(virt.get() as usize) else {
+            return false;
+        };
+        let descriptor = descriptor(entry);
+        if descriptor.load(Ordering::Relaxed) & DIRTY_BIT_MODIFIER == 0 {
+            // Not writable, so it can't be dirty.
+            return false;
+        }
+        let old = descriptor.fetch_or(READ_ONLY, Ordering::Relaxed);
+        let dirty = old & READ_ONLY == 0;
+        if dirty {
+            flush.add(base, base + level_span(level));
+        }
+        dirty
+    }
+
+    /// Sets the access flag of the page or block mapping `virt`, after an
+    /// access flag fault. Returns whether anything maps it.
+    pub fn mark_accessed(&mut self, virt: VirtAddr) -> bool {
+        let Some((entry, ..)) = self.leaf(virt.get() as usize) else {
+            return false;
+        };
+        descriptor(entry).fetch_or(ACCESSED, Ordering::Relaxed);
+        true
+    }
+
+    /// Marks the page or block mapping `virt` dirty, after a write to it
+    /// faulted. Returns whether it was writable, and only clean.
+    pub fn mark_dirty(&mut self, virt: VirtAddr) -> bool {
+        let Some((entry, ..)) = self.leaf(virt.get() as usize) else {
+            return false;
+        };
+        let descriptor = descriptor(entry);
+        if descriptor.load(Ordering::Relaxed) & DIRTY_BIT_MODIFIER == 0 {
+            return false;
+        }
+        descriptor.fetch_and(!READ_ONLY, Ordering::Relaxed);
+        true
+    }
+}
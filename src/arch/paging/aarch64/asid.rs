@@ -0,0 +1,215 @@
+//! Address space IDs, which tag the TLB entries of each user address space so
+//! that switching between them doesn't need a TLB flush.
+//!
+//! There are only 256 or 65536 of them, so they're handed out per
+//! generation: when they run out, a new generation starts, the whole TLB is
+//! flushed, and every address space gets a new ASID the next time it's
+//! switched to. The ones running on a CPU at the time keep theirs, moved into
+//! the new generation straight away so that invalidations still reach them.
+
+use core::{
+    arch::asm,
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use system::cpus::CpuInfo;
+
+use super::shootdown;
+use crate::kernel::memory::numa::MAX_CPUS;
+
+/// How many bits of ASID there are, 8 until [`init`] finds out.
+static ASID_BITS: AtomicUsize = AtomicUsize::new(8);
+/// Mirrors [`Allocator::generation`], so it can be checked without the lock.
+static GENERATION: AtomicU64 = AtomicU64::new(1);
+static ALLOCATOR: Mutex<Allocator> = Mutex::new(Allocator::new());
+
+/// ASID 0 is left for the kernel, with an empty TTBR0.
+const KERNEL_ASID: u16 = 0;
+
+/// Finds out how big ASIDs are, and enables 16 bit ones if the CPU has
+/// them.
+///
+/// # Safety
+/// Must be called once, before any user address space is switched to.
+pub unsafe fn init() {
+    let mmfr0: u64;
+    asm!("mrs {}, id_aa64mmfr0_el1", out(reg) mmfr0, options(nomem, nostack));
+    if mmfr0 >> 4 & 0xf == 0b0010 {
+        let mut tcr: u64;
+        asm!("mrs {}, tcr_el1", out(reg) tcr, options(nomem, nostack));
+        // TCR_EL1.AS
+        tcr |= 1 << 36;
+        asm!(
+            "msr tcr_el1, {}",
+            "isb",
+            "tlbi vmalle1",
+            "dsb nsh",
+            "isb",
+            in(reg) tcr,
+            options(nostack)
+        );
+        ASID_BITS.store(16, Ordering::Relaxed);
+    }
+}
+
+pub fn asid_bits() -> usize {
+    ASID_BITS.load(Ordering::Relaxed)
+}
+
+/// Which TLB entries an invalidation applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Scope {
+    /// Global entries, i.e. the kernel half, in every address space.
+    Global,
+    /// The entries of one address space.
+    Asid(u16),
+    /// An address space that can't have any entries, because it hasn't run
+    /// since the last rollover.
+    Nothing,
+}
+
+/// The ASID of an address space, with the generation it's from. Zero until
+/// it's first switched to.
+#[derive(Debug, Default)]
+pub struct Asid(AtomicU64);
+impl Asid {
+    pub const fn new() -> Self {
+        Self(AtomicU64::new(0))
+    }
+
+    /// Where invalidations for this address space need to go.
+    pub fn scope(&self) -> Scope {
+        let value = self.0.load(Ordering::Acquire);
+        // A rollover moves running ASIDs into the new generation just before
+        // it starts.
+        if generation(value) >= GENERATION.load(Ordering::Acquire) {
+            Scope::Asid(asid(value))
+        } else {
+            Scope::Nothing
+        }
+    }
+
+    /// Switches TTBR0 on this CPU to the table at `table`, tagged with this
+    /// address space's ASID, allocating one first if it needs it.
+    ///
+    /// # Safety
+    /// `table` must be the physical address of a level 0 table that stays
+    /// valid until TTBR0 is switched away from it, and `self` mustn't move or
+    /// go away until then either.
+    pub unsafe fn switch_to(&self, table: usize) {
+        let asid = ALLOCATOR.lock().assign(self, CpuInfo::cpu_id());
+        shootdown::switched(asid);
+        asm!(
+            "msr ttbr0_el1, {}",
+            "isb",
+            in(reg) (asid as u64) << 48 | table as u64,
+            options(nostack)
+        );
+    }
+}
+
+const fn generation(value: u64) -> u64 {
+    value >> 16
+}
+const fn asid(value: u64) -> u16 {
+    value as u16
+}
+
+struct Allocator {
+    generation: u64,
+    /// Which ASIDs are taken in this generation.
+    used: [u64; 65536 / 64],
+    /// Where to start looking for a free ASID.
+    next: usize,
+    /// The ASID (with generation) each CPU is running with.
+    active: [u64; MAX_CPUS],
+    /// Where each CPU's active ASID is kept, for rollovers to update.
+    owners: [*const Asid; MAX_CPUS],
+}
+// The owners are only used under the lock, while their CPUs run them.
+unsafe impl Send for Allocator {}
+impl Allocator {
+    const fn new() -> Self {
+        let mut used = [0; 65536 / 64];
+        used[0] = 1 << KERNEL_ASID;
+        Self {
+            generation: 1,
+            used,
+            next: 1,
+            active: [0; MAX_CPUS],
+            owners: [core::ptr::null(); MAX_CPUS],
+        }
+    }
+
+    /// Makes sure `asid` is from this generation, and records it as running
+    /// on `cpu`. Returns the ASID.
+    fn assign(&mut self, asid: &Asid, cpu: usize) -> u16 {
+        let mut value = asid.0.load(Ordering::Acquire);
+        if generation(value) != self.generation {
+            value = self.renew(value);
+            asid.0.store(value, Ordering::Release);
+        }
+        if cpu < MAX_CPUS {
+            self.active[cpu] = value;
+            self.owners[cpu] = asid;
+        }
+        self::asid(value)
+    }
+
+    /// A current ASID for an address space that last had `old`.
+    fn renew(&mut self, old: u64) -> u64 {
+        if generation(old) != 0 && !self.is_used(asid(old) as usize) {
+            // Still free: keep the same number.
+            self.set_used(asid(old) as usize);
+            return self.generation << 16 | asid(old) as u64;
+        }
+        let asid = match self.find_free() {
+            Some(asid) => asid,
+            None => {
+                self.rollover();
+                self.find_free().expect("more CPUs than ASIDs")
+            }
+        };
+        self.set_used(asid);
+        self.next = asid + 1;
+        self.generation << 16 | asid as u64
+    }
+
+    fn find_free(&self) -> Option<usize> {
+        let count = 1 << asid_bits();
+        (self.next..count)
+            .chain(1..self.next.min(count))
+            .find(|&asid| !self.is_used(asid))
+    }
+    fn is_used(&self, asid: usize) -> bool {
+        self.used[asid / 64] & 1 << (asid % 64) != 0
+    }
+    fn set_used(&mut self, asid: usize) {
+        self.used[asid / 64] |= 1 << (asid % 64);
+    }
+
+    /// Starts a new generation. Only the ASIDs running on a CPU stay taken,
+    /// and everything else is flushed from every TLB.
+    fn rollover(&mut self) {
+        self.generation += 1;
+        self.used = [0; 65536 / 64];
+        self.set_used(KERNEL_ASID as usize);
+        for cpu in 0..MAX_CPUS {
+            let active = self.active[cpu];
+            if active == 0 {
+                continue;
+            }
+            let value = self.generation << 16 | asid(active) as u64;
+            self.set_used(asid(active) as usize);
+            self.active[cpu] = value;
+            // Still alive, since the CPU hasn't switched away from it.
+            unsafe { &*self.owners[cpu] }
+                .0
+                .store(value, Ordering::Release);
+        }
+        self.next = 1;
+        GENERATION.store(self.generation, Ordering::Release);
+        shootdown::flush_all();
+    }
+}
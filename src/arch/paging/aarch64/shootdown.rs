@@ -0,0 +1,248 @@
+//! TLB shootdown: making sure no CPU keeps using a translation after it
+//! changes.
+//!
+//! Invalidating a few pages is broadcast to every CPU by the inner shareable
+//! TLBI instructions, which is as cheap as it gets. Flushing a whole address
+//! space instead only goes to the CPUs running it, by IPI. Other CPUs that
+//! ran it since their TLB was last flushed flush theirs before they next
+//! switch address spaces. Kernel mappings are global, so they're always
+//! broadcast.
+//!
+//! Each CPU remembers the last few ASIDs it switched to, and flushes its TLB
+//! when it runs out of room, so that what it remembers is everything it can
+//! have entries for.
+//!
+//! Until [`enable_ipis`] is called, once the GIC is set up to deliver
+//! [`SGI_SHOOTDOWN`], whole address spaces are broadcast as well.
+
+use core::{
+    arch::asm,
+    hint::spin_loop,
+    sync::atomic::{AtomicBool, AtomicU16, AtomicU64, AtomicUsize, Ordering},
+};
+
+use spin::Mutex;
+use system::cpus::CpuInfo;
+
+use super::asid::Scope;
+use crate::{drivers::gic::GIC, kernel::memory::numa::MAX_CPUS};
+
+/// The SGI that asks a CPU to take part in a shootdown.
+pub const SGI_SHOOTDOWN: u8 = 1;
+
+/// The ASID loaded in each CPU's TTBR0. Kernel threads leave it alone, so a
+/// CPU keeps counting as running an address space until it switches to
+/// another.
+static RUNNING: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// CPUs that weren't sent a shootdown for an address space they might still
+/// have entries for, and have to flush their TLB before the next switch.
+static STALE: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+
+/// How many ASIDs each CPU remembers switching to.
+const HISTORY: usize = 32;
+/// The ASIDs each CPU switched to since its TLB was last flushed: the first
+/// [`RAN_LEN`] of them. Only the CPU itself changes them.
+static RAN: [[AtomicU16; HISTORY]; MAX_CPUS] =
+    [const { [const { AtomicU16::new(0) }; HISTORY] }; MAX_CPUS];
+static RAN_LEN: [AtomicUsize; MAX_CPUS] = [const { AtomicUsize::new(0) }; MAX_CPUS];
+
+/// Whether shootdowns can be sent by IPI.
+static IPIS: AtomicBool = AtomicBool::new(false);
+/// Held for the duration of a shootdown by IPI.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// The ASID being shot down.
+static TARGET: AtomicU64 = AtomicU64::new(0);
+/// The CPUs that have been sent the shootdown and haven't done it yet.
+static PENDING: [AtomicBool; MAX_CPUS] = [const { AtomicBool::new(false) }; MAX_CPUS];
+static REMAINING: AtomicUsize = AtomicUsize::new(0);
+
+/// Lets shootdowns go by IPI rather than broadcast.
+///
+/// # Safety
+/// Every CPU's GIC CPU interface and redistributor must have SGIs enabled,
+/// and the IRQ handler must call [`handle_ipi`] for [`SGI_SHOOTDOWN`].
+/// Otherwise a shootdown waits forever for CPUs that never answer.
+pub unsafe fn enable_ipis() {
+    IPIS.store(true, Ordering::SeqCst);
+}
+
+/// Records that this CPU switched to `asid`, and flushes its TLB if it
+/// missed a shootdown. Must be called before the ASID is used.
+pub(super) fn switched(asid: u16) {
+    let cpu = CpuInfo::cpu_id();
+    if cpu >= MAX_CPUS {
+        return;
+    }
+    // Remembered before it runs, so a shootdown that doesn't find it here
+    // comes before this CPU has any entries for it.
+    if !ran(cpu, asid) {
+        if RAN_LEN[cpu].load(Ordering::Relaxed) == HISTORY {
+            flush_local(cpu);
+        }
+        let len = RAN_LEN[cpu].load(Ordering::Relaxed);
+        RAN[cpu][len].store(asid, Ordering::Relaxed);
+        RAN_LEN[cpu].store(len + 1, Ordering::SeqCst);
+    }
+    RUNNING[cpu].store(asid as u64, Ordering::SeqCst);
+    if STALE[cpu].swap(false, Ordering::SeqCst) {
+        flush_local(cpu);
+        RAN[cpu][0].store(asid, Ordering::Relaxed);
+        RAN_LEN[cpu].store(1, Ordering::SeqCst);
+    }
+}
+
+/// Whether `cpu` can have entries for `asid`.
+fn ran(cpu: usize, asid: u16) -> bool {
+    let len = RAN_LEN[cpu].load(Ordering::SeqCst);
+    RAN[cpu][..len]
+        .iter()
+        .any(|ran| ran.load(Ordering::Relaxed) == asid)
+}
+
+/// Flushes this CPU's TLB, which is `cpu`, and forgets what it ran.
+fn flush_local(cpu: usize) {
+    unsafe {
+        asm!("tlbi vmalle1", "dsb nsh", "isb", options(nostack));
+    }
+    RAN_LEN[cpu].store(0, Ordering::SeqCst);
+}
+
+/// Does this CPU's part of a shootdown. The IRQ handler calls this for
+/// [`SGI_SHOOTDOWN`].
+pub fn handle_ipi() {
+    let cpu = CpuInfo::cpu_id();
+    if cpu < MAX_CPUS && PENDING[cpu].swap(false, Ordering::AcqRel) {
+        let asid = TARGET.load(Ordering::Acquire) as u16;
+        unsafe {
+            asm!(
+                "tlbi aside1, {}",
+                "dsb nsh",
+                "isb",
+                in(reg) operand(asid, 0),
+                options(nostack)
+            );
+        }
+        REMAINING.fetch_sub(1, Ordering::Release);
+    }
+}
+
+/// The operand of a TLBI by address: the page number, tagged with an ASID.
+fn operand(asid: u16, virt: usize) -> u64 {
+    (asid as u64) << 48 | (virt as u64 >> 12 & (1 << 44) - 1)
+}
+
+/// Invalidates the entries for the page at `virt`, at every level, on every
+/// CPU.
+pub fn flush_page(scope: Scope, virt: usize) {
+    flush_ranges(scope, &[(virt, virt + 4096)]);
+}
+
+/// Invalidates the entries for every page in `ranges`, on every CPU, with a
+/// single barrier at the end.
+pub fn flush_ranges(scope: Scope, ranges: &[(usize, usize)]) {
+    let asid = match scope {
+        Scope::Global => 0,
+        Scope::Asid(asid) => asid,
+        Scope::Nothing => return,
+    };
+    unsafe {
+        asm!("dsb ishst", options(nostack));
+        for &(start, end) in ranges {
+            for page in (start..end).step_by(4096) {
+                if scope == Scope::Global {
+                    asm!("tlbi vaae1is, {}", in(reg) operand(0, page), options(nostack));
+                } else {
+                    asm!("tlbi vae1is, {}", in(reg) operand(asid, page), options(nostack));
+                }
+            }
+        }
+        asm!("dsb ish", "isb", options(nostack));
+    }
+}
+
+/// Invalidates all the entries in `scope`, on every CPU.
+pub fn flush_scope(scope: Scope) {
+    match scope {
+        Scope::Global => flush_all(),
+        Scope::Asid(asid) => shoot_down(asid),
+        Scope::Nothing => {}
+    }
+}
+
+/// Invalidates every entry, on every CPU.
+pub fn flush_all() {
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi vmalle1is",
+            "dsb ish",
+            "isb",
+            options(nostack)
+        );
+    }
+}
+
+/// Flushes `asid` here and on the CPUs running it, and marks the others
+/// stale. Without a GIC to send IPIs with, or until IPIs are enabled, it's
+/// broadcast instead.
+fn shoot_down(asid: u16) {
+    let Some(gic) = GIC.get().filter(|_| IPIS.load(Ordering::SeqCst)) else {
+        unsafe {
+            asm!(
+                "dsb ishst",
+                "tlbi aside1is, {}",
+                "dsb ish",
+                "isb",
+                in(reg) operand(asid, 0),
+                options(nostack)
+            );
+        }
+        return;
+    };
+
+    // Keep answering other CPUs' shootdowns while waiting, or two CPUs
+    // shooting each other down would wait forever.
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN.try_lock() {
+            break guard;
+        }
+        handle_ipi();
+        spin_loop();
+    };
+
+    let this = CpuInfo::cpu_id();
+    TARGET.store(asid as u64, Ordering::Release);
+    let mut targets: heapless::Vec<usize, MAX_CPUS> = heapless::Vec::new();
+    for cpu in (0..gic.cpus().min(MAX_CPUS)).filter(|&cpu| cpu != this) {
+        // A CPU that hasn't run it since its last flush has nothing to
+        // invalidate.
+        if !ran(cpu, asid) {
+            continue;
+        }
+        // Stale first: either the CPU sees it when it switches to the ASID,
+        // or this sees the CPU running it.
+        STALE[cpu].store(true, Ordering::SeqCst);
+        if RUNNING[cpu].load(Ordering::SeqCst) == asid as u64 {
+            REMAINING.fetch_add(1, Ordering::AcqRel);
+            PENDING[cpu].store(true, Ordering::Release);
+            let _ = targets.push(cpu);
+        }
+    }
+    for &cpu in &targets {
+        gic.send_sgi(SGI_SHOOTDOWN, cpu);
+    }
+
+    unsafe {
+        asm!(
+            "dsb ishst",
+            "tlbi aside1, {}",
+            "dsb nsh",
+            "isb",
+            in(reg) operand(asid, 0),
+            options(nostack)
+        );
+    }
+    while REMAINING.load(Ordering::Acquire) != 0 {
+        spin_loop();
+    }
+}
@@ -0,0 +1,122 @@
+use linkme::distributed_slice;
+
+use super::*;
+use crate::common::{
+    block_on,
+    test::{Test, TESTS},
+};
+
+/// A user address a 2 MiB block can go at.
+const VIRT: usize = 0x4000_0000;
+
+#[distributed_slice(TESTS)]
+static MAP_PAGE_IN_BLOCK: Test = Test {
+    name: "paging::map_page_in_block",
+    run: map_page_in_block,
+};
+fn map_page_in_block() {
+    block_on(async {
+        let phys_alloc = PHYS_ALLOC.get().unwrap();
+        let mut table = PageTable::new();
+        let flags = PageFlags::WRITE | PageFlags::USER_ACCESS;
+        let block = phys_alloc.alloc_order(9).await.unwrap();
+        let page = phys_alloc.alloc().await.unwrap();
+
+        let virt = |offset: usize| VirtAddr::new((VIRT + offset) as *mut _);
+        Mapper::<Size2M>::map(
+            &mut table,
+            VirtPage::for_addr(virt(0)),
+            PhysPage::for_addr(block),
+            flags,
+        )
+        .await
+        .ok()
+        .unwrap()
+        .ignore();
+        Mapper::<Size4K>::map(&mut table, VirtPage::for_addr(virt(0x3000)), page, flags)
+            .await
+            .ok()
+            .unwrap()
+            .ignore();
+
+        // Only that page moved; the rest of the block is still there, with
+        // its flags.
+        for offset in (0..Size2M::size()).step_by(Size4K::size()) {
+            let Ok((frame, frame_flags)) =
+                Mapper::<Size4K>::translate(&mut table, VirtPage::for_addr(virt(offset)))
+            else {
+                panic!("{offset:#x} isn't mapped as a page");
+            };
+            let expected = if offset == 0x3000 {
+                page.addr()
+            } else {
+                PhysAddr::new(block.get() + offset)
+            };
+            assert!(frame.addr() == expected, "{offset:#x} maps the wrong frame");
+            assert_eq!(frame_flags & flags, flags);
+        }
+        // Not over a page that's already there, though.
+        assert!(matches!(
+            Mapper::<Size4K>::map(&mut table, VirtPage::for_addr(virt(0x3000)), page, flags).await,
+            Err(MapError::AlreadyMapped(RuntimePageSize::Size4K))
+        ));
+
+        table.destroy(false).await.ignore();
+        phys_alloc.free(page).await;
+        phys_alloc.free_order(block, 9).await;
+    });
+}
+
+#[distributed_slice(TESTS)]
+static BREAK_COW_IN_SPLIT_BLOCK: Test = Test {
+    name: "paging::break_cow_in_split_block",
+    run: break_cow_in_split_block,
+};
+fn break_cow_in_split_block() {
+    block_on(async {
+        let phys_alloc = PHYS_ALLOC.get().unwrap();
+        let mut parent = PageTable::new();
+        let mut child = PageTable::new();
+        let flags = PageFlags::WRITE | PageFlags::USER_ACCESS;
+        let block = phys_alloc.alloc_order(9).await.unwrap();
+        let virt = VirtAddr::new(VIRT as *mut _);
+        Mapper::<Size2M>::map(
+            &mut parent,
+            VirtPage::for_addr(virt),
+            PhysPage::for_addr(block),
+            flags,
+        )
+        .await
+        .ok()
+        .unwrap()
+        .ignore();
+        parent
+            .share_range(&mut child, virt, Size2M::size(), true)
+            .await
+            .ok()
+            .unwrap()
+            .ignore();
+
+        // The write splits the parent's block, but the child still maps the
+        // whole of it, so the page has to be copied.
+        let offset = 0x5000;
+        let page = VirtAddr::new((VIRT + offset) as *mut _);
+        parent.break_cow(page, flags).await.ok().unwrap().ignore();
+        let Ok((frame, _)) = Mapper::<Size4K>::translate(&mut parent, VirtPage::for_addr(page))
+        else {
+            panic!("the parent's page isn't mapped");
+        };
+        assert!(frame.addr() != PhysAddr::new(block.get() + offset));
+        let Ok((frame, frame_flags)) =
+            Mapper::<Size2M>::translate(&mut child, VirtPage::for_addr(virt))
+        else {
+            panic!("the child's block isn't mapped");
+        };
+        assert!(frame.addr() == block);
+        assert!(frame_flags.contains(PageFlags::COW));
+
+        // Frees the copy; the block goes with the child's mapping of it.
+        parent.destroy(true).await.ignore();
+        child.destroy(true).await.ignore();
+    });
+}
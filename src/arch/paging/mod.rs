@@ -8,7 +8,7 @@ use self::sealed::PageSize;
 
 pub mod aarch64;
 
-mod sealed {
+pub(crate) mod sealed {
     pub trait PageSize {
         fn size() -> usize;
     }
@@ -84,12 +84,60 @@ impl<Size: PageSize> VirtPage<Size> {
 }
 
 bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct PageFlags: u64 {
         const KERNEL_EXEC = 1;
         const USER_EXEC = 1 << 1;
         const WRITE = 1 << 2;
         const USER_ACCESS = 1 << 3;
+        /// Written to since it was mapped, or since its dirty state was last
+        /// cleared. Only ever read back: writable pages are mapped dirty.
         const DIRTY = 1 << 4;
+        /// The [`MemoryType`] field. Use [`PageFlags::memory_type`] rather
+        /// than `contains` to read it.
+        const MEMORY_TYPE = 0b11 << 5;
+        const NORMAL_NC = (MemoryType::NormalNc as u64) << 5;
+        const DEVICE_NGNRE = (MemoryType::DeviceNGnRE as u64) << 5;
+        const DEVICE_NGNRNE = (MemoryType::DeviceNGnRnE as u64) << 5;
+        /// The frame may be shared, so writing to it means copying it first.
+        /// Such a page is never writable itself.
+        const COW = 1 << 7;
+    }
+}
+impl PageFlags {
+    pub const fn memory_type(&self) -> MemoryType {
+        match (self.bits() & Self::MEMORY_TYPE.bits()) >> 5 {
+            0 => MemoryType::Normal,
+            1 => MemoryType::NormalNc,
+            2 => MemoryType::DeviceNGnRE,
+            _ => MemoryType::DeviceNGnRnE,
+        }
+    }
+    pub const fn with_memory_type(self, ty: MemoryType) -> Self {
+        Self::from_bits_retain(self.bits() & !Self::MEMORY_TYPE.bits() | (ty as u64) << 5)
+    }
+}
+
+/// How accesses to a page are cached and ordered. Anything that isn't RAM
+/// must be mapped as one of the device types.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MemoryType {
+    /// Write-back cacheable RAM.
+    #[default]
+    Normal = 0,
+    /// Uncached RAM. Writes may be combined and reordered, which is what a
+    /// framebuffer wants.
+    NormalNc = 1,
+    /// MMIO that may acknowledge writes early. Right for most device
+    /// registers.
+    DeviceNGnRE = 2,
+    /// MMIO where every access reaches the device, in order, before the next
+    /// one starts.
+    DeviceNGnRnE = 3,
+}
+impl MemoryType {
+    pub const fn is_device(self) -> bool {
+        matches!(self, Self::DeviceNGnRE | Self::DeviceNGnRnE)
     }
 }
 
@@ -101,7 +149,7 @@ pub trait Mapper<Size: PageSize> {
         frame: PhysPage<Size>,
         flags: PageFlags,
     ) -> Result<Self::Flush, MapError>;
-    fn unmap(&mut self, page: VirtPage<Size>) -> Result<Self::Flush, MapError>;
+    async fn unmap(&mut self, page: VirtPage<Size>) -> Result<Self::Flush, MapError>;
     fn translate(
         &mut self,
         page: VirtPage<Size>,
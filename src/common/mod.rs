@@ -1,2 +1,23 @@
+use core::{
+    future::Future,
+    hint::spin_loop,
+    pin::pin,
+    task::{Context, Poll, Waker},
+};
+
 pub mod elf64;
 pub mod sizes;
+#[cfg(feature = "test")]
+pub mod test;
+
+/// Runs `future` to completion on this CPU, spinning while it's pending.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        spin_loop();
+    }
+}
@@ -0,0 +1,33 @@
+//! Tests that need a running kernel, such as the page tables'. With the
+//! `test` feature they run instead of [`main`](crate::main), and QEMU exits
+//! with the result.
+
+use linkme::distributed_slice;
+use log::info;
+use qemu_exit::QEMUExit;
+
+/// Every test, each added with `#[distributed_slice(TESTS)]`.
+#[distributed_slice]
+pub static TESTS: [Test];
+
+pub struct Test {
+    pub name: &'static str,
+    /// Panics if the test fails.
+    pub run: fn(),
+}
+
+/// Runs every test, then exits QEMU.
+pub fn run() -> ! {
+    info!("Running {} tests", TESTS.len());
+    for test in TESTS {
+        info!("{} ...", test.name);
+        (test.run)();
+    }
+    info!("All tests passed");
+    qemu_exit::AArch64::new().exit_success()
+}
+
+/// Exits QEMU after a test panics.
+pub fn fail() -> ! {
+    qemu_exit::AArch64::new().exit_failure()
+}
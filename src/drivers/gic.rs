@@ -0,0 +1,213 @@
+//! The GICv3, as far as the kernel needs it so far: software generated
+//! interrupts between CPUs. They're sent and taken through the CPU
+//! interface's system registers, and enabled in each CPU's redistributor.
+
+use core::{arch::asm, hint::spin_loop};
+
+use spin::Once;
+
+use crate::{drivers::MmioDevice, kernel::memory::numa::MAX_CPUS};
+
+pub static GIC: Once<Gic> = Once::new();
+
+const GICD_CTLR: usize = 0x0;
+/// GICD_CTLR.RWP: a write to GICD_CTLR hasn't taken effect yet.
+const GICD_CTLR_RWP: u32 = 1 << 31;
+
+const GICR_TYPER: usize = 0x8;
+/// GICR_TYPER.VLPIS: the redistributor has the two extra frames of a GICv4.
+const GICR_TYPER_VLPIS: u64 = 1 << 1;
+/// GICR_TYPER.Last: the redistributor is the last one in its region.
+const GICR_TYPER_LAST: u64 = 1 << 4;
+const GICR_WAKER: usize = 0x14;
+const GICR_WAKER_PROCESSOR_SLEEP: u32 = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: u32 = 1 << 2;
+/// The SGI and PPI registers are in a redistributor's second frame.
+const SGI_BASE: usize = 0x1_0000;
+const GICR_IGROUPR0: usize = SGI_BASE + 0x80;
+const GICR_ISENABLER0: usize = SGI_BASE + 0x100;
+const GICR_IPRIORITYR: usize = SGI_BASE + 0x400;
+
+/// The priority SGIs are given. Anything but the lowest gets past the
+/// priority mask.
+const SGI_PRIORITY: u8 = 0x80;
+/// INTIDs from here up to 1023 mean there's no interrupt to take.
+const SPURIOUS: u32 = 1020;
+
+/// The distributor's registers.
+struct Distributor(*mut u8);
+impl MmioDevice for Distributor {
+    fn pointer(&self) -> *mut u8 {
+        self.0
+    }
+}
+/// One CPU's redistributor's registers.
+struct Redistributor(*mut u8);
+impl MmioDevice for Redistributor {
+    fn pointer(&self) -> *mut u8 {
+        self.0
+    }
+}
+
+pub struct Gic {
+    /// The affinity (from MPIDR_EL1) of each CPU, by logical CPU number.
+    mpidrs: heapless::Vec<u64, MAX_CPUS>,
+    distributor: *mut u8,
+    /// The start of the redistributors, one after another.
+    redistributors: *mut u8,
+}
+// The registers are only written while setting up, on the CPU they're for.
+unsafe impl Send for Gic {}
+unsafe impl Sync for Gic {}
+impl Gic {
+    /// `distributor` and `redistributors` are where the GIC's registers are,
+    /// and `mpidrs` are the affinities of the CPUs, in logical CPU order.
+    pub fn new(
+        distributor: *mut u8,
+        redistributors: *mut u8,
+        mpidrs: impl IntoIterator<Item = u64>,
+    ) -> Self {
+        Self {
+            mpidrs: mpidrs.into_iter().take(MAX_CPUS).collect(),
+            distributor,
+            redistributors,
+        }
+    }
+
+    /// Turns on the distributor for group 1 interrupts, with affinity
+    /// routing.
+    ///
+    /// # Safety
+    /// Must be called once, before any CPU is set up with
+    /// [`init_cpu`](Self::init_cpu).
+    pub unsafe fn init(&self) {
+        let mut distributor = Distributor(self.distributor);
+        // ARE_NS, EnableGrp1A, and EnableGrp1 when there's a single security
+        // state.
+        distributor.write_register_32(GICD_CTLR, 1 << 4 | 1 << 1 | 1);
+        while distributor.read_register_32(GICD_CTLR) & GICD_CTLR_RWP != 0 {
+            spin_loop();
+        }
+    }
+
+    /// Sets this CPU up to take `sgis` as IRQs: wakes its redistributor,
+    /// enables them there, and enables the CPU interface. Returns whether
+    /// this CPU's redistributor was found.
+    ///
+    /// # Safety
+    /// Must be called on every CPU that takes the SGIs, after
+    /// [`init`](Self::init). IRQs that get through must be handled.
+    pub unsafe fn init_cpu(&self, sgis: &[u8]) -> bool {
+        let Some(mut redistributor) = self.this_redistributor() else {
+            return false;
+        };
+        let waker = redistributor.read_register_32(GICR_WAKER);
+        redistributor.write_register_32(GICR_WAKER, waker & !GICR_WAKER_PROCESSOR_SLEEP);
+        while redistributor.read_register_32(GICR_WAKER) & GICR_WAKER_CHILDREN_ASLEEP != 0 {
+            spin_loop();
+        }
+
+        for &sgi in sgis {
+            assert!(sgi < 16, "SGI {sgi} out of range");
+            let group = redistributor.read_register_32(GICR_IGROUPR0);
+            redistributor.write_register_32(GICR_IGROUPR0, group | 1 << sgi);
+            redistributor.write_register_byte(GICR_IPRIORITYR, sgi as usize, SGI_PRIORITY);
+            redistributor.write_register_32(GICR_ISENABLER0, 1 << sgi);
+        }
+
+        let mut sre: u64;
+        // ICC_SRE_EL1.SRE, then ICC_PMR_EL1 to let every priority through
+        // and ICC_IGRPEN1_EL1 to enable group 1.
+        asm!("mrs {}, s3_0_c12_c12_5", out(reg) sre, options(nomem, nostack));
+        sre |= 1;
+        asm!(
+            "msr s3_0_c12_c12_5, {}",
+            "isb",
+            "msr s3_0_c4_c6_0, {}",
+            "msr s3_0_c12_c12_7, {}",
+            "isb",
+            in(reg) sre,
+            in(reg) 0xffu64,
+            in(reg) 1u64,
+            options(nostack)
+        );
+        true
+    }
+
+    /// The redistributor for the CPU this runs on, found by its affinity.
+    fn this_redistributor(&self) -> Option<Redistributor> {
+        let mpidr: u64;
+        unsafe { asm!("mrs {}, mpidr_el1", out(reg) mpidr, options(nomem, nostack)) };
+        // Aff3.Aff2.Aff1.Aff0, as GICR_TYPER has it.
+        let affinity = (mpidr >> 32 & 0xff) << 24 | mpidr & 0xff_ffff;
+        let mut frame = self.redistributors;
+        loop {
+            let redistributor = Redistributor(frame);
+            let typer = redistributor.read_register_64(GICR_TYPER);
+            if typer >> 32 == affinity {
+                return Some(redistributor);
+            }
+            if typer & GICR_TYPER_LAST != 0 {
+                return None;
+            }
+            let frames = if typer & GICR_TYPER_VLPIS != 0 { 4 } else { 2 };
+            frame = frame.wrapping_add(frames * 0x1_0000);
+        }
+    }
+
+    /// Takes the highest priority pending interrupt, returning its INTID, or
+    /// `None` if there isn't one. It has to be ended with
+    /// [`end_interrupt`](Self::end_interrupt).
+    pub fn acknowledge() -> Option<u32> {
+        let intid: u64;
+        // ICC_IAR1_EL1
+        unsafe { asm!("mrs {}, s3_0_c12_c12_0", out(reg) intid, options(nostack)) };
+        let intid = intid as u32 & 0xff_ffff;
+        (intid < SPURIOUS || intid > 1023).then_some(intid)
+    }
+
+    /// Tells the GIC that the interrupt `intid` has been handled.
+    pub fn end_interrupt(intid: u32) {
+        // ICC_EOIR1_EL1
+        unsafe {
+            asm!(
+                "msr s3_0_c12_c12_1, {}",
+                "isb",
+                in(reg) intid as u64,
+                options(nostack)
+            )
+        };
+    }
+
+    pub fn cpus(&self) -> usize {
+        self.mpidrs.len()
+    }
+
+    /// Sends SGI `sgi` to `cpu`.
+    pub fn send_sgi(&self, sgi: u8, cpu: usize) {
+        assert!(sgi < 16, "SGI {sgi} out of range");
+        let mpidr = self.mpidrs[cpu];
+        let aff0 = mpidr & 0xff;
+        let aff1 = mpidr >> 8 & 0xff;
+        let aff2 = mpidr >> 16 & 0xff;
+        let aff3 = mpidr >> 32 & 0xff;
+        // The target list covers 16 CPUs at a time; RS picks which 16.
+        let value = aff3 << 48
+            | (aff0 / 16) << 44
+            | aff2 << 32
+            | (sgi as u64) << 24
+            | aff1 << 16
+            | 1 << (aff0 % 16);
+        unsafe {
+            // ICC_SGI1R_EL1, after making earlier writes visible to the
+            // target.
+            asm!(
+                "dsb ishst",
+                "msr s3_0_c12_c11_5, {}",
+                "isb",
+                in(reg) value,
+                options(nostack)
+            );
+        }
+    }
+}
@@ -1,3 +1,4 @@
+pub mod gic;
 pub mod serial;
 
 pub trait MmioDevice {
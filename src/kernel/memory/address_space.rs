@@ -0,0 +1,481 @@
+//! User address spaces: a page table, the free virtual address ranges in its
+//! user half, and the areas mapped in it.
+//!
+//! Every [`Vma`] is one allocation from the address space's [`Vmem`] arena.
+//! Splitting an area, when part of it is unmapped or protected, gives the
+//! arena the pieces as allocations of their own.
+
+use alloc::collections::BTreeMap;
+use core::{
+    ptr::null_mut,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use bitflags::bitflags;
+use mem::vmem::{AllocPolicy, Vmem};
+use system::cpus::CpuInfo;
+
+use crate::arch::paging::{
+    aarch64::{PageTable, RangeFlush},
+    sealed::PageSize,
+    CacheFlush, MapError, Mapper, MemoryType, PageFlags, Size4K, VirtPage,
+};
+
+use super::{
+    address::{PhysAddr, VirtAddr},
+    numa::MAX_CPUS,
+    PHYS_ALLOC,
+};
+
+/// The lowest address that can be mapped. The page at 0 never is, so that
+/// null pointers fault.
+pub const USER_START: usize = 0x1000;
+/// The end of the user half, with 48 bit virtual addresses.
+pub const USER_END: usize = 1 << 48;
+
+bitflags! {
+    /// What user code may do with an area. The bits are the same as
+    /// `PROT_*`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Protection: u32 {
+        const READ = 1;
+        const WRITE = 1 << 1;
+        const EXEC = 1 << 2;
+    }
+}
+
+bitflags! {
+    /// The bits are the same as the corresponding `MAP_*`.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct VmaFlags: u32 {
+        /// Changes are seen by every address space mapping the same pages,
+        /// rather than being private to this one.
+        const SHARED = 1;
+        /// Map at exactly the address asked for, replacing whatever is
+        /// there.
+        const FIXED = 1 << 4;
+        /// Map every page now, instead of when it's first touched.
+        const POPULATE = 1 << 15;
+    }
+}
+
+/// Where the contents of an area come from.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Backing {
+    /// Zeroed memory.
+    Anonymous,
+    /// Part of a file, from `offset`. `file` is a handle for whatever
+    /// provides its pages.
+    File { file: usize, offset: usize },
+    /// Physical memory that isn't managed by the allocator, such as MMIO,
+    /// mapped as it is.
+    Device {
+        phys: PhysAddr,
+        memory_type: MemoryType,
+    },
+}
+impl Backing {
+    /// The backing of the part of an area `offset` bytes in.
+    fn advance(self, offset: usize) -> Self {
+        match self {
+            Self::Anonymous => Self::Anonymous,
+            Self::File { file, offset: base } => Self::File {
+                file,
+                offset: base + offset,
+            },
+            Self::Device { phys, memory_type } => Self::Device {
+                phys: PhysAddr::new(phys.get() + offset),
+                memory_type,
+            },
+        }
+    }
+}
+
+/// A virtual memory area: a range of an address space mapped the same way
+/// throughout.
+#[derive(Clone, Copy)]
+pub struct Vma {
+    pub start: usize,
+    pub len: usize,
+    pub prot: Protection,
+    pub backing: Backing,
+    pub flags: VmaFlags,
+}
+impl Vma {
+    pub fn end(&self) -> usize {
+        self.start + self.len
+    }
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.start..self.end()).contains(&addr)
+    }
+
+    /// The flags for the pages of this area.
+    pub fn page_flags(&self) -> PageFlags {
+        let mut flags = PageFlags::empty();
+        if !self.prot.is_empty() {
+            flags |= PageFlags::USER_ACCESS;
+        }
+        if self.prot.contains(Protection::WRITE) {
+            flags |= PageFlags::WRITE;
+        }
+        if self.prot.contains(Protection::EXEC) {
+            flags |= PageFlags::USER_EXEC;
+        }
+        if let Backing::Device { memory_type, .. } = self.backing {
+            flags = flags.with_memory_type(memory_type);
+        }
+        flags
+    }
+
+    /// Shortens this area to end at `at`, returning the rest.
+    fn split_off(&mut self, at: usize) -> Vma {
+        let offset = at - self.start;
+        let tail = Vma {
+            start: at,
+            len: self.len - offset,
+            backing: self.backing.advance(offset),
+            ..*self
+        };
+        self.len = offset;
+        tail
+    }
+}
+
+pub enum VmError {
+    /// The range is empty, unaligned, or outside of the user half.
+    InvalidRange,
+    /// There's no free range big enough, or a fixed one isn't free.
+    NoSpace,
+    /// Part of the range isn't mapped.
+    NotMapped,
+    Map(MapError),
+}
+impl From<MapError> for VmError {
+    fn from(err: MapError) -> Self {
+        Self::Map(err)
+    }
+}
+
+/// The address space each CPU last switched its user half to, which its
+/// page faults are resolved in.
+static ACTIVE: [AtomicPtr<AddressSpace>; MAX_CPUS] =
+    [const { AtomicPtr::new(null_mut()) }; MAX_CPUS];
+
+pub struct AddressSpace {
+    table: PageTable,
+    vmem: Vmem<'static>,
+    /// By start address.
+    vmas: BTreeMap<usize, Vma>,
+}
+impl AddressSpace {
+    pub async fn new() -> Self {
+        let vmem = Vmem::new(Size4K::size());
+        vmem.add_span(USER_START, USER_END - USER_START).await;
+        Self {
+            table: PageTable::new(),
+            vmem,
+            vmas: BTreeMap::new(),
+        }
+    }
+
+    pub fn page_table(&mut self) -> &mut PageTable {
+        &mut self.table
+    }
+    /// The area `addr` is in, if any.
+    pub fn find(&self, addr: usize) -> Option<&Vma> {
+        self.vmas
+            .range(..=addr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(addr))
+    }
+    /// Every area, in address order.
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Switches this CPU's user half to this address space.
+    ///
+    /// # Safety
+    /// See [`PageTable::activate`]. The address space mustn't move either,
+    /// and page faults on this CPU use it until it switches to another, so
+    /// nothing may hold a reference to it across an access that can fault.
+    pub unsafe fn activate(&mut self) {
+        self.table.activate();
+        if let Some(active) = ACTIVE.get(CpuInfo::cpu_id()) {
+            active.store(self, Ordering::Release);
+        }
+    }
+
+    /// The address space this CPU is running, if it's switched to one.
+    ///
+    /// # Safety
+    /// Only for resolving this CPU's page faults; see
+    /// [`activate`](Self::activate).
+    pub(super) unsafe fn active() -> Option<&'static mut AddressSpace> {
+        let active = ACTIVE.get(CpuInfo::cpu_id())?;
+        active.load(Ordering::Acquire).as_mut()
+    }
+
+    /// Maps `len` bytes, returning where. Unless [`VmaFlags::FIXED`] is set,
+    /// `hint` is only used if it's free.
+    ///
+    /// Device memory and [`VmaFlags::POPULATE`] areas are mapped straight
+    /// away; anything else is left for page faults to fill in. Device memory
+    /// must start on a page boundary.
+    pub async fn mmap(
+        &mut self,
+        hint: Option<VirtAddr>,
+        len: usize,
+        prot: Protection,
+        backing: Backing,
+        flags: VmaFlags,
+    ) -> Result<VirtAddr, VmError> {
+        let len = len.next_multiple_of(Size4K::size());
+        let hint = hint.map(|hint| hint.get() as usize);
+        if len == 0 || len > USER_END - USER_START {
+            return Err(VmError::InvalidRange);
+        }
+        if let Backing::Device { phys, .. } = backing {
+            if phys.get() % Size4K::size() != 0 {
+                return Err(VmError::InvalidRange);
+            }
+        }
+        let start = match hint {
+            Some(hint) if flags.contains(VmaFlags::FIXED) => {
+                Self::check_range(hint, len)?;
+                self.munmap(VirtAddr::new(hint as *mut _), len).await?;
+                self.vmem
+                    .try_alloc_at(hint, len)
+                    .await
+                    .map_err(|_| VmError::NoSpace)?
+            }
+            _ => self.alloc_va(hint, len).await?,
+        };
+
+        let vma = Vma {
+            start,
+            len,
+            prot,
+            backing,
+            flags,
+        };
+        if let Err(err) = self.populate(&vma).await {
+            self.vmem.free(start, len).await;
+            return Err(err.into());
+        }
+        self.vmas.insert(start, vma);
+        Ok(VirtAddr::new(start as *mut _))
+    }
+
+    /// Unmaps everything in `len` bytes at `virt`. Areas that stick out of
+    /// the range are split, and keep the part outside of it.
+    pub async fn munmap(&mut self, virt: VirtAddr, len: usize) -> Result<(), VmError> {
+        let start = virt.get() as usize;
+        let len = len.next_multiple_of(Size4K::size());
+        Self::check_range(start, len)?;
+        let end = start + len;
+        self.split_at(start).await;
+        self.split_at(end).await;
+
+        while let Some((&vma_start, vma)) = self.vmas.range(start..end).next() {
+            let (vma_len, free_frames) = (vma.len, vma.backing == Backing::Anonymous);
+            // The area only goes once its pages have, so that a failure leaves
+            // whatever is still mapped in it accounted for.
+            self.table
+                .unmap_range(VirtAddr::new(vma_start as *mut _), vma_len, free_frames)
+                .await?
+                .flush();
+            self.vmas.remove(&vma_start);
+            self.vmem.free(vma_start, vma_len).await;
+        }
+        Ok(())
+    }
+
+    /// Changes the protection of `len` bytes at `virt`, all of which must be
+    /// mapped.
+    pub async fn mprotect(
+        &mut self,
+        virt: VirtAddr,
+        len: usize,
+        prot: Protection,
+    ) -> Result<(), VmError> {
+        let start = virt.get() as usize;
+        let len = len.next_multiple_of(Size4K::size());
+        Self::check_range(start, len)?;
+        let end = start + len;
+        if !self.is_covered(start, end) {
+            return Err(VmError::NotMapped);
+        }
+        self.split_at(start).await;
+        self.split_at(end).await;
+
+        let mut flush = RangeFlush::new(self.table.scope(start));
+        for vma in self.vmas.range_mut(start..end).map(|(_, vma)| vma) {
+            vma.prot = prot;
+            let range = self
+                .table
+                .protect_range(
+                    VirtAddr::new(vma.start as *mut _),
+                    vma.len,
+                    vma.page_flags(),
+                )
+                .await?;
+            flush.merge(range);
+        }
+        flush.flush();
+        Ok(())
+    }
+
+    /// A copy of this address space, for a new process. Private memory
+    /// becomes copy on write in both; shared memory and devices are mapped by
+    /// both as they are.
+    pub async fn fork(&mut self) -> Result<AddressSpace, VmError> {
+        let mut child = AddressSpace::new().await;
+        let mut flush = RangeFlush::new(self.table.scope(USER_START));
+        for vma in self.vmas.values() {
+            child
+                .vmem
+                .try_alloc_at(vma.start, vma.len)
+                .await
+                .expect("areas overlap");
+            child.vmas.insert(vma.start, *vma);
+            let cow = !vma.flags.contains(VmaFlags::SHARED)
+                && !matches!(vma.backing, Backing::Device { .. });
+            let virt = VirtAddr::new(vma.start as *mut _);
+            match self
+                .table
+                .share_range(&mut child.table, virt, vma.len, cow)
+                .await
+            {
+                Ok(range) => flush.merge(range),
+                Err(err) => {
+                    flush.flush();
+                    child.destroy().await;
+                    return Err(err.into());
+                }
+            }
+        }
+        flush.flush();
+        Ok(child)
+    }
+
+    /// Unmaps everything and frees the page tables.
+    ///
+    /// It must not be in use on any CPU.
+    pub async fn destroy(mut self) {
+        // Goes through munmap so that only anonymous memory is freed.
+        let _ = self
+            .munmap(VirtAddr::new(USER_START as *mut _), USER_END - USER_START)
+            .await;
+        self.table.destroy(false).await.flush();
+    }
+
+    fn check_range(start: usize, len: usize) -> Result<(), VmError> {
+        let valid = len > 0
+            && start % Size4K::size() == 0
+            && start >= USER_START
+            && start.checked_add(len).is_some_and(|end| end <= USER_END);
+        if valid {
+            Ok(())
+        } else {
+            Err(VmError::InvalidRange)
+        }
+    }
+
+    /// Allocates `len` bytes of address space, at `hint` if that's free.
+    async fn alloc_va(&mut self, hint: Option<usize>, len: usize) -> Result<usize, VmError> {
+        if let Some(hint) = hint.filter(|&hint| Self::check_range(hint, len).is_ok()) {
+            if let Ok(start) = self.vmem.try_alloc_at(hint, len).await {
+                return Ok(start);
+            }
+        }
+        self.vmem
+            .try_alloc(len, AllocPolicy::BestFit)
+            .await
+            .map_err(|_| VmError::NoSpace)
+    }
+
+    /// Whether areas cover all of `[start, end)`, without gaps.
+    fn is_covered(&self, start: usize, end: usize) -> bool {
+        let mut cursor = start;
+        if let Some(vma) = self.find(start) {
+            cursor = vma.end();
+        }
+        for vma in self.vmas.range(start..end).map(|(_, vma)| vma) {
+            if vma.start > cursor {
+                return false;
+            }
+            cursor = cursor.max(vma.end());
+        }
+        cursor >= end
+    }
+
+    /// Splits the area `at` is in, if it doesn't already start there.
+    async fn split_at(&mut self, at: usize) {
+        let Some(vma) = self
+            .vmas
+            .range_mut(..at)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(at))
+        else {
+            return;
+        };
+        let (start, len) = (vma.start, vma.len);
+        let tail = vma.split_off(at);
+        self.vmas.insert(at, tail);
+
+        self.vmem.free(start, len).await;
+        self.vmem
+            .try_alloc_at(start, at - start)
+            .await
+            .expect("lost the head of a split area");
+        self.vmem
+            .try_alloc_at(at, start + len - at)
+            .await
+            .expect("lost the tail of a split area");
+    }
+
+    /// Maps whatever of `vma` is mapped up front.
+    async fn populate(&mut self, vma: &Vma) -> Result<(), MapError> {
+        let virt = VirtAddr::new(vma.start as *mut _);
+        match vma.backing {
+            Backing::Device { phys, .. } => {
+                self.table
+                    .map_range(virt, phys, vma.len, vma.page_flags())
+                    .await
+            }
+            Backing::Anonymous if vma.flags.contains(VmaFlags::POPULATE) => {
+                let phys_alloc = PHYS_ALLOC.get().ok_or(MapError::NoPhysAlloc)?;
+                let mut offset = 0;
+                let result = loop {
+                    if offset == vma.len {
+                        break Ok(());
+                    }
+                    let Some(frame) = phys_alloc.alloc_zeroed().await else {
+                        break Err(MapError::OutOfMem);
+                    };
+                    let page = VirtPage::for_addr(VirtAddr::new((vma.start + offset) as *mut _));
+                    match Mapper::<Size4K>::map(&mut self.table, page, frame, vma.page_flags())
+                        .await
+                    {
+                        Ok(flush) => flush.ignore(),
+                        Err(err) => {
+                            phys_alloc.free(frame).await;
+                            break Err(err);
+                        }
+                    }
+                    offset += Size4K::size();
+                };
+                if result.is_err() {
+                    if let Ok(flush) = self.table.unmap_range(virt, offset, true).await {
+                        flush.flush();
+                    }
+                }
+                result
+            }
+            _ => Ok(()),
+        }
+    }
+}
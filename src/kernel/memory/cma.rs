@@ -0,0 +1,147 @@
+//! Contiguous memory areas: regions the device tree's `/reserved-memory` node
+//! sets aside as `reusable` or `shared-dma-pool`, kept out of the buddy
+//! allocator so that drivers can always get large physically contiguous
+//! buffers from them.
+
+use log::info;
+use system::sync::Mutex;
+
+use crate::common::sizes::Size;
+
+use super::address::PhysAddr;
+
+pub const MAX_POOLS: usize = 16;
+
+pub struct CmaPool {
+    name: &'static str,
+    base: usize,
+    pages: usize,
+    /// A bit per page, set if it's allocated.
+    bitmap: &'static mut [u64],
+}
+impl CmaPool {
+    /// How many words of bitmap a pool of `len` bytes needs.
+    pub const fn bitmap_words(len: usize) -> usize {
+        (len / 4096).div_ceil(64)
+    }
+
+    /// Creates a pool over `[base, base + len)`. `bitmap` must be at least
+    /// [`bitmap_words`](Self::bitmap_words) long.
+    pub fn new(name: &'static str, base: usize, len: usize, bitmap: &'static mut [u64]) -> Self {
+        assert!(
+            bitmap.len() >= Self::bitmap_words(len),
+            "CMA bitmap too small"
+        );
+        bitmap.fill(0);
+        Self {
+            name,
+            base,
+            pages: len / 4096,
+            bitmap,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        (self.base..self.base + self.pages * 4096).contains(&addr.get())
+    }
+
+    /// Allocates `pages` contiguous pages, the first aligned to `align`
+    /// pages, first fit.
+    pub fn alloc(&mut self, pages: usize, align: usize) -> Option<PhysAddr> {
+        // Alignment is of the physical address, not the index into the pool.
+        let first = self.base / 4096;
+        let align_up = |page: usize| (first + page).next_multiple_of(align.max(1)) - first;
+        let mut start = align_up(0);
+        while start + pages <= self.pages {
+            match (start..start + pages)
+                .rev()
+                .find(|&page| self.is_used(page))
+            {
+                Some(used) => start = align_up(used + 1),
+                None => {
+                    for page in start..start + pages {
+                        self.set_used(page, true);
+                    }
+                    return Some(PhysAddr::new(self.base + start * 4096));
+                }
+            }
+        }
+        None
+    }
+    pub fn free(&mut self, addr: PhysAddr, pages: usize) {
+        assert!(self.contains(addr), "freed address not in CMA pool");
+        let start = (addr.get() - self.base) / 4096;
+        for page in start..start + pages {
+            assert!(self.is_used(page), "double free in CMA pool {}", self.name);
+            self.set_used(page, false);
+        }
+    }
+
+    fn is_used(&self, page: usize) -> bool {
+        self.bitmap[page / 64] & 1 << (page % 64) != 0
+    }
+    fn set_used(&mut self, page: usize, used: bool) {
+        if used {
+            self.bitmap[page / 64] |= 1 << (page % 64);
+        } else {
+            self.bitmap[page / 64] &= !(1 << (page % 64));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Cma {
+    pools: heapless::Vec<Mutex<CmaPool>, MAX_POOLS>,
+}
+impl Cma {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_pool(&mut self, pool: CmaPool) {
+        info!(
+            "CMA pool {}: {:x} - {:x} ({})",
+            pool.name,
+            pool.base,
+            pool.base + pool.pages * 4096,
+            Size(pool.pages * 4096)
+        );
+        if self.pools.push(Mutex::new(pool)).is_err() {
+            panic!("too many CMA pools");
+        }
+    }
+
+    /// Allocates `pages` contiguous pages aligned to `align` pages from any
+    /// pool.
+    pub async fn alloc(&self, pages: usize, align: usize) -> Option<PhysAddr> {
+        for pool in &self.pools {
+            if let Some(addr) = pool.lock().await.alloc(pages, align) {
+                return Some(addr);
+            }
+        }
+        None
+    }
+    /// Like [`alloc`](Self::alloc), but only from the pool named `name`, for
+    /// drivers with a `memory-region` of their own.
+    pub async fn alloc_from(&self, name: &str, pages: usize, align: usize) -> Option<PhysAddr> {
+        for pool in &self.pools {
+            let mut pool = pool.lock().await;
+            if pool.name == name {
+                return pool.alloc(pages, align);
+            }
+        }
+        None
+    }
+    pub async fn free(&self, addr: PhysAddr, pages: usize) {
+        for pool in &self.pools {
+            let mut pool = pool.lock().await;
+            if pool.contains(addr) {
+                return pool.free(addr, pages);
+            }
+        }
+        panic!("freed address {:x} not in any CMA pool", addr.get());
+    }
+}
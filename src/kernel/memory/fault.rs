@@ -0,0 +1,140 @@
+//! Page faults in user address spaces, which are where anonymous memory gets
+//! its frames.
+
+use log::warn;
+
+use crate::{
+    arch::{
+        interrupts::aarch64::{Access, Fault, FaultKind},
+        paging::{CacheFlush, MapError, Mapper, Size4K, VirtPage},
+    },
+    common::block_on,
+};
+
+use super::{
+    address::VirtAddr,
+    address_space::{AddressSpace, Backing, Protection, USER_END},
+    PHYS_ALLOC,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FaultError {
+    /// The address isn't in any area.
+    NotMapped,
+    /// The area doesn't allow the access.
+    Protection(Access),
+    /// The area's backing can't provide pages on demand.
+    Unsupported,
+    /// The fault isn't one that mapping a page can fix.
+    Unhandled(FaultKind),
+    OutOfMem,
+}
+
+/// Resolves `fault`, returning whether the access can be retried. If it
+/// can't, a user thread that made it has to be killed; a kernel access
+/// panics.
+pub async fn page_fault(space: &mut AddressSpace, fault: Fault) -> bool {
+    let result = if fault.addr < USER_END {
+        space.handle_fault(&fault).await
+    } else {
+        // Nothing in the kernel half is mapped on demand.
+        Err(FaultError::NotMapped)
+    };
+    match result {
+        Ok(()) => true,
+        Err(err) if fault.user => {
+            warn!(
+                "user {:?} at {:#x} failed: {err:?}",
+                fault.access, fault.addr
+            );
+            false
+        }
+        Err(err) => panic!(
+            "kernel {:?} at {:#x} failed: {err:?} ({:?})",
+            fault.access, fault.addr, fault.kind
+        ),
+    }
+}
+
+/// Resolves an abort this CPU took, in the address space it's running.
+/// Returns whether the access can be retried, like [`page_fault`].
+pub fn handle_abort(fault: Fault) -> bool {
+    let Some(space) = (unsafe { AddressSpace::active() }) else {
+        panic!(
+            "{:?} at {:#x} with no address space ({:?})",
+            fault.access, fault.addr, fault.kind
+        );
+    };
+    // A fault can't wait for anything else to run, and what it waits on is
+    // only ever held briefly.
+    block_on(page_fault(space, fault))
+}
+
+impl AddressSpace {
+    /// Maps whatever `fault` needs to succeed when it's retried.
+    pub async fn handle_fault(&mut self, fault: &Fault) -> Result<(), FaultError> {
+        let vma = *self.find(fault.addr).ok_or(FaultError::NotMapped)?;
+        let allowed = match fault.access {
+            Access::Read => !vma.prot.is_empty(),
+            Access::Write => vma.prot.contains(Protection::WRITE),
+            Access::Execute => vma.prot.contains(Protection::EXEC),
+        };
+        if !allowed {
+            return Err(FaultError::Protection(fault.access));
+        }
+
+        let virt = VirtAddr::new(fault.addr as *mut _);
+        match fault.kind {
+            FaultKind::Translation(_) => {}
+            // Without hardware access flag updates.
+            FaultKind::AccessFlag(_) => {
+                self.page_table().mark_accessed(virt);
+                return Ok(());
+            }
+            // A clean page, without hardware dirty state updates.
+            FaultKind::Permission(_)
+                if fault.access == Access::Write && self.page_table().mark_dirty(virt) =>
+            {
+                return Ok(());
+            }
+            FaultKind::Permission(_) if fault.access == Access::Write => {
+                // If the page isn't copy on write, the TLB entry that faulted
+                // was stale and this does nothing.
+                return match self.page_table().break_cow(virt, vma.page_flags()).await {
+                    Ok(flush) => {
+                        flush.flush();
+                        Ok(())
+                    }
+                    Err(_) => Err(FaultError::OutOfMem),
+                };
+            }
+            // The area allows it, so the TLB entry that faulted was stale.
+            FaultKind::Permission(_) => return Ok(()),
+            kind => return Err(FaultError::Unhandled(kind)),
+        }
+        if vma.backing != Backing::Anonymous {
+            return Err(FaultError::Unsupported);
+        }
+
+        let phys_alloc = PHYS_ALLOC.get().ok_or(FaultError::OutOfMem)?;
+        let frame = phys_alloc
+            .alloc_zeroed()
+            .await
+            .ok_or(FaultError::OutOfMem)?;
+        let page = VirtPage::for_addr(virt);
+        match Mapper::<Size4K>::map(self.page_table(), page, frame, vma.page_flags()).await {
+            Ok(flush) => {
+                flush.ignore();
+                Ok(())
+            }
+            Err(err) => {
+                phys_alloc.free(frame).await;
+                match err {
+                    // Someone else got there first.
+                    MapError::AlreadyMapped(_) => Ok(()),
+                    MapError::NoPhysAlloc | MapError::OutOfMem => Err(FaultError::OutOfMem),
+                }
+            }
+        }
+    }
+}
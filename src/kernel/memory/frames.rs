@@ -0,0 +1,219 @@
+//! The page frame database: an entry for every 4 KiB frame of RAM, recording
+//! what state it's in, who owns it and how many mappings refer to it.
+//!
+//! A mapping of a huge page counts as a mapping of every frame in it, so
+//! that each frame's count stays right when the huge page is split into
+//! smaller ones. Flag changes also keep the counters in
+//! [`stats`](super::stats) up to date.
+
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+use bitflags::bitflags;
+
+use crate::{
+    arch::paging::{sealed::PageSize, PhysPage},
+    size_of,
+};
+
+use super::{address::PhysAddr, stats::PHYS_STATS, FRAMES};
+
+bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct FrameFlags: u32 {
+        /// In the physical allocator, zeroed.
+        const FREE = 1;
+        /// In the physical allocator, waiting to be zeroed.
+        const DIRTY = 1 << 1;
+        /// Used by the kernel, e.g. for page tables.
+        const KERNEL = 1 << 2;
+        /// Mapped into a user address space.
+        const USER = 1 << 3;
+        /// Not RAM the allocator may use: firmware, device tree, holes.
+        const RESERVED = 1 << 4;
+        /// Must never be freed or moved, e.g. the kernel image.
+        const PINNED = 1 << 5;
+    }
+}
+
+/// Who a frame belongs to. An opaque tag, which only needs to tell owners
+/// apart.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner(pub usize);
+impl Owner {
+    pub const NONE: Self = Self(0);
+    pub const KERNEL: Self = Self(1);
+    pub const PAGE_TABLE: Self = Self(2);
+}
+
+pub struct Frame {
+    refcount: AtomicU32,
+    flags: AtomicU32,
+    owner: AtomicUsize,
+}
+impl Frame {
+    const fn new(flags: FrameFlags) -> Self {
+        Self {
+            refcount: AtomicU32::new(0),
+            flags: AtomicU32::new(flags.bits()),
+            owner: AtomicUsize::new(Owner::NONE.0),
+        }
+    }
+
+    /// The number of mappings of this frame. For a page table, the number of
+    /// present entries in it instead.
+    pub fn refcount(&self) -> u32 {
+        self.refcount.load(Ordering::Acquire)
+    }
+    /// Takes a reference, returning the new count.
+    pub fn get(&self) -> u32 {
+        self.refcount.fetch_add(1, Ordering::AcqRel) + 1
+    }
+    /// Drops a reference, returning the new count.
+    pub fn put(&self) -> u32 {
+        let old = self.refcount.fetch_sub(1, Ordering::AcqRel);
+        assert!(old > 0, "frame refcount underflow");
+        old - 1
+    }
+
+    pub fn flags(&self) -> FrameFlags {
+        FrameFlags::from_bits_retain(self.flags.load(Ordering::Acquire))
+    }
+    pub fn set_flags(&self, flags: FrameFlags) {
+        let old = self.flags.swap(flags.bits(), Ordering::AcqRel);
+        PHYS_STATS.account(FrameFlags::from_bits_retain(old), flags);
+    }
+    pub fn insert_flags(&self, flags: FrameFlags) {
+        let old = FrameFlags::from_bits_retain(self.flags.fetch_or(flags.bits(), Ordering::AcqRel));
+        PHYS_STATS.account(old, old | flags);
+    }
+    pub fn remove_flags(&self, flags: FrameFlags) {
+        let old =
+            FrameFlags::from_bits_retain(self.flags.fetch_and(!flags.bits(), Ordering::AcqRel));
+        PHYS_STATS.account(old, old - flags);
+    }
+
+    pub fn owner(&self) -> Owner {
+        Owner(self.owner.load(Ordering::Acquire))
+    }
+    pub fn set_owner(&self, owner: Owner) {
+        self.owner.store(owner.0, Ordering::Release);
+    }
+}
+
+pub struct FrameDb {
+    base: usize,
+    frames: &'static [Frame],
+}
+impl FrameDb {
+    /// How many bytes of entries are needed to cover `[start, end)`.
+    pub const fn size(start: usize, end: usize) -> usize {
+        (end - start / 4096 * 4096).div_ceil(4096) * size_of!(Frame)
+    }
+
+    /// Creates a database covering `[start, end)`, in `memory`, which must be
+    /// at least [`size`](Self::size) bytes. Every frame starts out reserved.
+    ///
+    /// # Safety
+    /// `memory` must be valid, aligned, and unused for as long as the database
+    /// lives.
+    pub unsafe fn new(start: usize, end: usize, memory: *mut Frame) -> Self {
+        let base = start / 4096 * 4096;
+        let len = (end - base).div_ceil(4096);
+        for i in 0..len {
+            memory.add(i).write(Frame::new(FrameFlags::RESERVED));
+        }
+        Self {
+            base,
+            frames: core::slice::from_raw_parts(memory, len),
+        }
+    }
+
+    /// The entry for the (first frame of) `page`, if it's RAM.
+    pub fn get<S: PageSize>(&self, page: PhysPage<S>) -> Option<&Frame> {
+        let addr = page.addr().get().checked_sub(self.base)?;
+        self.frames.get(addr / 4096)
+    }
+
+    /// The entries for every frame in `[start, end)` that is RAM.
+    pub fn range(&self, start: usize, end: usize) -> &[Frame] {
+        let first = (start.saturating_sub(self.base) / 4096).min(self.frames.len());
+        let last = (end.saturating_sub(self.base).div_ceil(4096)).min(self.frames.len());
+        &self.frames[first..last.max(first)]
+    }
+    pub fn set_range(&self, start: usize, end: usize, flags: FrameFlags) {
+        for frame in self.range(start, end) {
+            frame.set_flags(flags);
+        }
+    }
+}
+
+/// The entry for `page`, once the database is up.
+pub fn frame<S: PageSize>(page: PhysPage<S>) -> Option<&'static Frame> {
+    FRAMES.get()?.get(page)
+}
+
+/// The entries for every frame in the `len` bytes at `addr`, once the
+/// database is up.
+pub fn frames(addr: PhysAddr, len: usize) -> &'static [Frame] {
+    let Some(db) = FRAMES.get() else {
+        return &[];
+    };
+    db.range(addr.get(), addr.get() + len)
+}
+
+/// Records that the `len` bytes at `addr` have been handed out by the
+/// physical allocator.
+pub fn allocated(addr: PhysAddr, len: usize) {
+    for frame in frames(addr, len) {
+        debug_assert!(frame.refcount() == 0, "allocated frame is still mapped");
+        frame.set_flags(FrameFlags::empty());
+        frame.set_owner(Owner::NONE);
+    }
+}
+
+/// Records that the `len` bytes at `addr` have gone back to the physical
+/// allocator, as dirty or already zeroed.
+pub fn freed(addr: PhysAddr, len: usize, dirty: bool) {
+    let flags = if dirty {
+        FrameFlags::DIRTY
+    } else {
+        FrameFlags::FREE
+    };
+    for frame in frames(addr, len) {
+        assert!(frame.refcount() == 0, "freed frame is still mapped");
+        assert!(
+            !frame.flags().contains(FrameFlags::PINNED),
+            "freed pinned frame"
+        );
+        frame.set_flags(flags);
+        frame.set_owner(Owner::NONE);
+    }
+}
+
+/// Records a new mapping of `page`, returning its first frame's new
+/// refcount.
+pub fn mapped<S: PageSize>(page: PhysPage<S>, user: bool) -> u32 {
+    let owner = if user {
+        FrameFlags::USER
+    } else {
+        FrameFlags::KERNEL
+    };
+    let frames = frames(page.addr(), S::size());
+    for frame in frames {
+        frame.insert_flags(owner);
+        frame.get();
+    }
+    frames.first().map_or(0, Frame::refcount)
+}
+
+/// Records that a mapping of `page` is gone, returning its first frame's new
+/// refcount.
+pub fn unmapped<S: PageSize>(page: PhysPage<S>) -> u32 {
+    let frames = frames(page.addr(), S::size());
+    for frame in frames {
+        if frame.put() == 0 {
+            frame.remove_flags(FrameFlags::USER);
+        }
+    }
+    frames.first().map_or(0, Frame::refcount)
+}
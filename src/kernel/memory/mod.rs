@@ -4,13 +4,23 @@ use log::trace;
 use mem::vmem::Vmem;
 use spin::{Mutex, Once};
 
-use self::{address::PhysAddr, physalloc::PhysAlloc};
+use self::{address::PhysAddr, cma::Cma, frames::FrameDb, numa::Topology, physalloc::PhysAlloc};
 
 pub mod address;
+pub mod address_space;
+pub mod cma;
+pub mod fault;
+pub mod frames;
+pub mod numa;
 pub mod physalloc;
+pub mod stats;
+pub mod zeroing;
 
 pub static HHDM_START: Once<usize> = Once::new();
 pub static PHYS_ALLOC: Once<PhysAlloc> = Once::new();
+pub static FRAMES: Once<FrameDb> = Once::new();
+pub static CMA: Once<Cma> = Once::new();
+pub static NUMA: Once<Topology> = Once::new();
 
 #[global_allocator]
 pub static DUMMY_ALLOC: DummyAlloc = DummyAlloc;
@@ -0,0 +1,90 @@
+//! NUMA topology: which node each CPU belongs to, and how far apart nodes are.
+//!
+//! It comes from the device tree's `numa-node-id` properties on `memory` and
+//! `cpu` nodes, and the optional `/distance-map`. Without them, everything is
+//! node 0.
+
+use system::cpus::CpuInfo;
+
+use super::NUMA;
+
+pub const MAX_NODES: usize = 8;
+pub const MAX_CPUS: usize = 256;
+/// The distance from a node to itself.
+pub const LOCAL_DISTANCE: u8 = 10;
+/// The distance between nodes when firmware doesn't say.
+pub const REMOTE_DISTANCE: u8 = 20;
+
+pub struct Topology {
+    nodes: usize,
+    /// By logical CPU number, the order of the CPUs in `/cpus`.
+    cpu_nodes: heapless::Vec<u8, MAX_CPUS>,
+    distances: [[u8; MAX_NODES]; MAX_NODES],
+}
+impl Topology {
+    pub fn new(nodes: usize) -> Self {
+        assert!(
+            nodes > 0 && nodes <= MAX_NODES,
+            "bad NUMA node count {nodes}"
+        );
+        let mut distances = [[REMOTE_DISTANCE; MAX_NODES]; MAX_NODES];
+        for (node, row) in distances.iter_mut().enumerate() {
+            row[node] = LOCAL_DISTANCE;
+        }
+        Self {
+            nodes,
+            cpu_nodes: heapless::Vec::new(),
+            distances,
+        }
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.nodes
+    }
+
+    /// Records that `cpu` is on `node`. CPUs that are never recorded are on
+    /// node 0.
+    pub fn set_cpu_node(&mut self, cpu: usize, node: usize) {
+        assert!(node < self.nodes, "CPU {cpu} on unknown node {node}");
+        if cpu >= MAX_CPUS {
+            return;
+        }
+        if self.cpu_nodes.len() <= cpu {
+            self.cpu_nodes.resize(cpu + 1, 0).unwrap();
+        }
+        self.cpu_nodes[cpu] = node as u8;
+    }
+    pub fn cpu_node(&self, cpu: usize) -> usize {
+        self.cpu_nodes.get(cpu).map_or(0, |&node| node as usize)
+    }
+
+    pub fn set_distance(&mut self, from: usize, to: usize, distance: u8) {
+        self.distances[from][to] = distance;
+    }
+    pub fn distance(&self, from: usize, to: usize) -> u8 {
+        self.distances[from][to]
+    }
+
+    /// The order to allocate in for `node`: itself, then the others, nearest
+    /// first.
+    pub fn fallback(&self, node: usize) -> heapless::Vec<usize, MAX_NODES> {
+        let mut order: heapless::Vec<usize, MAX_NODES> = (0..self.nodes).collect();
+        order.sort_unstable_by_key(|&other| (other != node, self.distance(node, other), other));
+        order
+    }
+}
+
+/// The node of the CPU this runs on.
+pub fn current_node() -> usize {
+    NUMA.get()
+        .map_or(0, |topology| topology.cpu_node(CpuInfo::cpu_id()))
+}
+
+/// The allocation order for `node`, or just `node` before the topology is
+/// known.
+pub fn fallback(node: usize) -> heapless::Vec<usize, MAX_NODES> {
+    match NUMA.get() {
+        Some(topology) => topology.fallback(node),
+        None => heapless::Vec::from_slice(&[node]).unwrap(),
+    }
+}
@@ -1,55 +1,281 @@
 use core::{fmt::Debug, ptr::NonNull};
 
-use mem::slab::{Alloc, Slab};
+#[cfg(feature = "debug-alloc")]
+use mem::debug;
+
+use mem::{
+    buddy::{block_size, order_for, Buddy},
+    slab::{Alloc, Slab},
+};
 use system::sync::Mutex;
 
 use crate::{
-    arch::paging::{PhysPage, Size4K},
+    arch::paging::{sealed::PageSize, PhysPage, Size4K},
     common::sizes::Size,
     size_of,
 };
 
-use super::address::{PhysAddr, Pointer, Virtual};
+use super::{
+    address::{PhysAddr, Pointer, Virtual},
+    frames,
+    numa::{self, MAX_NODES},
+    zeroing::ZEROING,
+};
 
 #[derive(Debug)]
 pub struct Node {
     pub next: Option<NonNull<Node>>,
 }
 
-pub struct PhysAlloc {
+/// The physical memory of one NUMA node.
+pub struct NodeAlloc {
+    node: usize,
+    start: usize,
+    end: usize,
     slab: Slab<PhysAllocInner, 128>,
 }
-impl PhysAlloc {
-    pub fn new(inner: PhysAllocInner) -> Self {
+impl NodeAlloc {
+    /// Creates the allocator for `node`, whose memory lies within `[start,
+    /// end)`.
+    pub fn new(node: usize, start: usize, end: usize, inner: PhysAllocInner) -> Self {
         Self {
-            slab: Slab::new(inner),
+            node,
+            start,
+            end,
+            // Restock half a magazine (256 KiB) at a time: pages that come
+            // off the dirty list are zeroed under the lock.
+            slab: Slab::new("physalloc", inner).with_watermarks(64, 256),
         }
     }
 
+    pub fn node(&self) -> usize {
+        self.node
+    }
+    pub fn covers(&self, addr: PhysAddr) -> bool {
+        (self.start..self.end).contains(&addr.get())
+    }
+
     pub async fn alloc(&self) -> Option<PhysPage<Size4K>> {
-        self.slab.alloc_shortcircuiting().await
+        let page = self.slab.alloc_shortcircuiting().await?;
+        frames::allocated(page.addr(), 4096);
+        Some(page)
     }
 
     pub async fn free(&self, page: PhysPage<Size4K>) {
+        frames::freed(page.addr(), 4096, true);
         self.slab.free(page).await
     }
 
-    pub async fn clean_dirty(&self) -> bool {
+    pub async fn alloc_bulk(&self, count: usize, mut push: impl FnMut(PhysPage<Size4K>)) -> usize {
+        self.slab
+            .alloc_bulk(count, |page| {
+                frames::allocated(page.addr(), 4096);
+                push(page);
+            })
+            .await
+    }
+
+    pub async fn free_bulk(&self, pages: impl IntoIterator<Item = PhysPage<Size4K>>) {
+        let pages = pages.into_iter().inspect(|page| {
+            frames::freed(page.addr(), 4096, true);
+        });
+        self.slab.free_bulk(pages).await
+    }
+
+    /// Allocates `2^order` physically contiguous, zeroed pages, aligned to
+    /// their size.
+    pub async fn alloc_order(&self, order: usize) -> Option<PhysAddr> {
+        let addr = self.slab.lock_alloc().await.alloc_order(order)?;
+        frames::allocated(addr, block_size(order));
+        Some(addr)
+    }
+
+    pub async fn free_order(&self, addr: PhysAddr, order: usize) {
+        frames::freed(addr, block_size(order), false);
+        self.slab.lock_alloc().await.free_order(addr, order)
+    }
+
+    /// Cleans up to `count` dirty pages under one lock. Returns whether or
+    /// not there are more.
+    pub async fn clean_dirty_batch(&self, count: usize) -> bool {
         let mut inner = self.slab.lock_alloc().await;
-        inner.clean_dirty()
+        for _ in 0..count {
+            if !inner.clean_dirty() {
+                return false;
+            }
+        }
+        inner.dirty.is_some()
     }
 
     pub async fn restock_slab(&self) -> bool {
         self.slab.restock().await
     }
+
+    /// Hands the pages cached in the slab's depot back to the freelist.
+    /// Returns how many there were.
+    pub async fn reap_slab(&self) -> usize {
+        self.slab.reap().await
+    }
+}
+
+/// Physical memory, split up by NUMA node. Allocations come from the current
+/// CPU's node when they can, and from the nearest other nodes when they
+/// can't; frees go back to whichever node the memory belongs to.
+pub struct PhysAlloc {
+    nodes: heapless::Vec<NodeAlloc, MAX_NODES>,
+}
+impl PhysAlloc {
+    pub fn new(nodes: impl IntoIterator<Item = NodeAlloc>) -> Self {
+        let nodes: heapless::Vec<_, MAX_NODES> = nodes.into_iter().collect();
+        assert!(!nodes.is_empty(), "no physical memory");
+        Self { nodes }
+    }
+
+    pub fn nodes(&self) -> &[NodeAlloc] {
+        &self.nodes
+    }
+    /// The allocator for `node`, if it has any memory.
+    pub fn node(&self, node: usize) -> Option<&NodeAlloc> {
+        self.nodes.iter().find(|alloc| alloc.node == node)
+    }
+    /// The allocator `addr` belongs to.
+    fn owner(&self, addr: PhysAddr) -> &NodeAlloc {
+        self.nodes
+            .iter()
+            .find(|alloc| alloc.covers(addr))
+            .unwrap_or_else(|| panic!("{:x} isn't in any NUMA node", addr.get()))
+    }
+    /// The nodes with memory, in the order to allocate from them on the
+    /// current CPU.
+    fn local(&self) -> impl Iterator<Item = &NodeAlloc> {
+        numa::fallback(numa::current_node())
+            .into_iter()
+            .filter_map(|node| self.node(node))
+    }
+
+    pub async fn alloc(&self) -> Option<PhysPage<Size4K>> {
+        for node in self.local() {
+            if let Some(page) = node.alloc().await {
+                return Some(page);
+            }
+        }
+        None
+    }
+    /// Allocates a page and zeroes it. Pages from [`alloc`](Self::alloc) may
+    /// come straight back out of the slab, still holding whatever their last
+    /// owner left in them.
+    pub async fn alloc_zeroed(&self) -> Option<PhysPage<Size4K>> {
+        let page = self.alloc().await?;
+        let ptr: *mut u8 = page
+            .addr()
+            .to_virt_offset(*super::HHDM_START.get()?)
+            .into_ptr()
+            .get();
+        unsafe {
+            ptr.write_bytes(0, 4096);
+        }
+        Some(page)
+    }
+    /// Allocates a page from `node` only.
+    pub async fn alloc_on_node(&self, node: usize) -> Option<PhysPage<Size4K>> {
+        self.node(node)?.alloc().await
+    }
+
+    pub async fn free(&self, page: PhysPage<Size4K>) {
+        self.owner(page.addr()).free(page).await
+    }
+
+    pub async fn alloc_bulk(&self, count: usize, mut push: impl FnMut(PhysPage<Size4K>)) -> usize {
+        let mut allocated = 0;
+        for node in self.local() {
+            if allocated == count {
+                break;
+            }
+            allocated += node.alloc_bulk(count - allocated, &mut push).await;
+        }
+        allocated
+    }
+
+    pub async fn free_bulk(&self, pages: impl IntoIterator<Item = PhysPage<Size4K>>) {
+        // Runs of pages from the same node go back together.
+        let mut pages = pages.into_iter().peekable();
+        while let Some(first) = pages.peek() {
+            let node = self.owner(first.addr());
+            let run = core::iter::from_fn(|| pages.next_if(|page| node.covers(page.addr())));
+            node.free_bulk(run).await;
+        }
+    }
+
+    /// Allocates `2^order` physically contiguous, zeroed pages, aligned to
+    /// their size.
+    pub async fn alloc_order(&self, order: usize) -> Option<PhysAddr> {
+        for node in self.local() {
+            if let Some(addr) = node.alloc_order(order).await {
+                return Some(addr);
+            }
+        }
+        None
+    }
+    /// Like [`alloc_order`](Self::alloc_order), from `node` only.
+    pub async fn alloc_order_on_node(&self, node: usize, order: usize) -> Option<PhysAddr> {
+        self.node(node)?.alloc_order(order).await
+    }
+
+    pub async fn free_order(&self, addr: PhysAddr, order: usize) {
+        self.owner(addr).free_order(addr, order).await
+    }
+
+    /// Allocates a zeroed page of any size, straight from the buddy
+    /// allocator. 4 KiB pages are better off coming from [`alloc`](Self::alloc).
+    pub async fn alloc_page<S: PageSize>(&self) -> Option<PhysPage<S>> {
+        let addr = self.alloc_order(order_for(S::size())).await?;
+        Some(PhysPage::for_addr(addr))
+    }
+
+    pub async fn free_page<S: PageSize>(&self, page: PhysPage<S>) {
+        self.free_order(page.addr(), order_for(S::size())).await
+    }
+
+    /// Cleans up to `count` dirty pages on each node. Returns whether or not
+    /// there are more.
+    pub async fn clean_dirty_batch(&self, count: usize) -> bool {
+        let mut more = false;
+        for node in &self.nodes {
+            more |= node.clean_dirty_batch(count).await;
+        }
+        more
+    }
+
+    /// Refills the current CPU's magazine from its own node.
+    pub async fn restock_slab(&self) -> bool {
+        match self.node(numa::current_node()) {
+            Some(node) => node.restock_slab().await,
+            None => false,
+        }
+    }
+
+    /// Hands the pages cached in every node's depot back to their freelists.
+    /// Returns how many there were.
+    pub async fn reap_slab(&self) -> usize {
+        let mut released = 0;
+        for node in &self.nodes {
+            released += node.reap_slab().await;
+        }
+        released
+    }
 }
 
+/// Physical memory: a [`Buddy`] allocator of clean memory, and a list of
+/// dirty 4 KiB pages waiting to be zeroed before they go back to it.
 pub struct PhysAllocInner {
-    pub free: Option<NonNull<Node>>,
+    pub buddy: Buddy,
     pub dirty: Option<NonNull<Node>>,
+    /// Pages on `dirty`.
+    dirty_count: usize,
 }
 impl Alloc for PhysAllocInner {
     type Item = PhysPage<Size4K>;
+    const ZEROED: bool = true;
 
     async fn alloc(&mut self) -> Option<Self::Item> {
         self.alloc()
@@ -58,68 +284,118 @@ impl Alloc for PhysAllocInner {
     async fn free(&mut self, item: Self::Item) {
         self.free(item)
     }
+
+    fn debug_memory(item: &Self::Item) -> Option<NonNull<[u8]>> {
+        let ptr: *mut u8 = item
+            .addr()
+            .to_virt_offset(*super::HHDM_START.get()?)
+            .into_ptr()
+            .get();
+        Some(NonNull::slice_from_raw_parts(NonNull::new(ptr)?, 4096))
+    }
 }
 impl PhysAllocInner {
+    pub fn new(buddy: Buddy) -> Self {
+        Self {
+            buddy,
+            dirty: None,
+            dirty_count: 0,
+        }
+    }
+
+    #[track_caller]
     pub fn alloc(&mut self) -> Option<PhysPage<Size4K>> {
-        let mut node = if let Some(free) = self.free {
-            free
-        } else if let Some(dirty) = self.dirty {
-            let ptr = dirty.as_ptr().wrapping_add(1);
-            unsafe {
-                ptr.write_bytes(0, 4096 - size_of!(Node));
-            }
-            dirty
-        } else {
-            return None;
-        };
-        let node_ref = unsafe { node.as_mut() };
-        self.free = node_ref.next;
-        node_ref.next = None;
-        Some(PhysPage::for_addr(PhysAddr::new(node.as_ptr() as usize)))
+        if let Some(addr) = self.buddy.alloc(0) {
+            return Some(PhysPage::for_addr(PhysAddr::new(addr)));
+        }
+        let dirty = self.dirty?;
+        #[cfg(feature = "debug-alloc")]
+        Self::check_poison(PhysPage::for_addr(self.phys(dirty)));
+        self.dirty = unsafe { dirty.as_ref() }.next;
+        unsafe {
+            dirty.cast::<u8>().write_bytes(0, 4096);
+        }
+        self.dirty_count -= 1;
+        ZEROING.zeroed_on_alloc();
+        Some(PhysPage::for_addr(self.phys(dirty)))
     }
+    #[track_caller]
     pub fn free(&mut self, page: PhysPage<Size4K>) {
         let node = Node { next: self.dirty };
         let ptr: *mut Node = page
             .addr()
-            .to_virt_offset(*super::HHDM_START.get().unwrap())
+            .to_virt_offset(self.buddy.offset())
             .into_ptr()
             .get();
         unsafe {
             ptr.write(node);
         }
         self.dirty = NonNull::new(ptr);
+        self.dirty_count += 1;
+        ZEROING.dirtied();
     }
-    /// Cleans a single dirty page, and puts it in the freelist.
+    /// Cleans a single dirty page, and gives it back to the buddy allocator.
     /// Returns whether or not there is another dirty page.
+    #[track_caller]
     pub fn clean_dirty(&mut self) -> bool {
-        let Some(mut dirty) = self.dirty else {
+        let Some(dirty) = self.dirty else {
             return false;
         };
 
-        let ptr = dirty.as_ptr().wrapping_add(1);
+        #[cfg(feature = "debug-alloc")]
+        Self::check_poison(PhysPage::for_addr(self.phys(dirty)));
+        self.dirty = unsafe { dirty.as_ref() }.next;
+        let addr = self.phys(dirty);
         unsafe {
-            ptr.write_bytes(0, 4096 - size_of!(Node));
+            dirty.cast::<u8>().write_bytes(0, 4096);
+            self.buddy.free(addr.get(), 0);
         }
-
-        unsafe { dirty.as_mut().next = self.free };
-        self.free = Some(dirty);
+        frames::freed(addr, 4096, false);
+        self.dirty_count -= 1;
+        ZEROING.zeroed();
         self.dirty.is_some()
     }
+
+    /// Allocates `2^order` contiguous pages. If the buddy allocator can't, the
+    /// dirty pages are cleaned first, since they may coalesce into a large
+    /// enough block.
+    pub fn alloc_order(&mut self, order: usize) -> Option<PhysAddr> {
+        let addr = self.buddy.alloc(order).or_else(|| {
+            while self.clean_dirty() {}
+            self.buddy.alloc(order)
+        })?;
+        Some(PhysAddr::new(addr))
+    }
+    /// Frees `2^order` contiguous pages from [`alloc_order`](Self::alloc_order).
+    /// They're zeroed on the spot rather than going through the dirty list.
+    pub fn free_order(&mut self, addr: PhysAddr, order: usize) {
+        let ptr: *mut u8 = addr.to_virt_offset(self.buddy.offset()).into_ptr().get();
+        unsafe {
+            ptr.write_bytes(0, block_size(order));
+            self.buddy.free(addr.get(), order);
+        }
+    }
+
+    fn phys(&self, node: NonNull<Node>) -> PhysAddr {
+        PhysAddr::new(node.as_ptr() as usize - self.buddy.offset())
+    }
+
+    /// Checks that the rest of a dirty page, after its freelist node, hasn't
+    /// been written to since it was freed. The slab poisoned it on the way in.
+    #[cfg(feature = "debug-alloc")]
+    #[track_caller]
+    fn check_poison(page: PhysPage<Size4K>) {
+        let Some(memory) = <Self as Alloc>::debug_memory(&page) else {
+            return;
+        };
+        let page = unsafe { &memory.as_ref()[size_of!(Node)..] };
+        debug::check_poison("physalloc", page, core::panic::Location::caller());
+    }
 }
 impl Debug for PhysAllocInner {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        let mut node = self.free;
-        let mut free_count = 0;
-        while let Some(n) = node {
-            free_count += 1;
-            node = unsafe { n.as_ref().next };
-        }
-        let mut node = self.dirty;
-        let mut dirty_count = 0;
-        while let Some(n) = node {
-            dirty_count += 1;
-            node = unsafe { n.as_ref().next };
-        }
+        let free_count = self.buddy.free_pages();
+        let dirty_count = self.dirty_count;
         f.debug_struct("PhysAlloc")
             .field(
                 "free",
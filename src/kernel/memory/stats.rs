@@ -0,0 +1,203 @@
+//! Physical memory counters, and watermarks that wake the reclaim task when
+//! free memory runs low.
+//!
+//! The counters follow the frame database: every change to a frame's
+//! [`FrameFlags`] is accounted as it happens, so reading them is O(1). A page
+//! is free while the physical allocator holds it, dirty or not.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use mem::vmem::Vmem;
+use spin::Lazy;
+use system::sync::Event;
+
+use super::{
+    frames::FrameFlags,
+    physalloc::PhysAlloc,
+    zeroing::{YieldNow, ZEROING},
+};
+
+pub static PHYS_STATS: Lazy<PhysStats> = Lazy::new(PhysStats::new);
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MemStats {
+    /// Pages of RAM the kernel manages.
+    pub total: usize,
+    /// Pages held by the physical allocator.
+    pub free: usize,
+    /// Free pages on the dirty lists, waiting to be zeroed. This is
+    /// [`ZeroStats::dirty`](super::zeroing::ZeroStats::dirty); pages in the
+    /// allocator's magazines aren't counted.
+    pub dirty: usize,
+    /// Pages used by the kernel.
+    pub kernel: usize,
+    /// Pages mapped into user address spaces.
+    pub user: usize,
+}
+
+/// Free page counts, in pages.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Watermarks {
+    /// Below this, reclaim runs without yielding.
+    pub min: usize,
+    /// Below this, the reclaim task is woken.
+    pub low: usize,
+    /// The reclaim task stops once free memory is back above this.
+    pub high: usize,
+}
+impl Watermarks {
+    /// Watermarks scaled to `total` pages: `min` is 1/256th of memory, kept
+    /// between 512 KiB and 64 MiB, with `low` and `high` a quarter and a half
+    /// above it.
+    pub fn for_total(total: usize) -> Self {
+        let min = (total / 256).clamp(128, 16384);
+        Self {
+            min,
+            low: min + min / 4,
+            high: min + min / 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pressure {
+    /// Free memory is above the low watermark.
+    None,
+    /// Below the low watermark.
+    Low,
+    /// Below the min watermark.
+    Min,
+}
+
+pub struct PhysStats {
+    total: AtomicUsize,
+    free: AtomicUsize,
+    kernel: AtomicUsize,
+    user: AtomicUsize,
+    min: AtomicUsize,
+    low: AtomicUsize,
+    high: AtomicUsize,
+    /// Set from when the reclaim task is woken until it's done.
+    reclaiming: AtomicBool,
+    reclaim: Event,
+}
+impl PhysStats {
+    fn new() -> Self {
+        Self {
+            total: AtomicUsize::new(0),
+            free: AtomicUsize::new(0),
+            kernel: AtomicUsize::new(0),
+            user: AtomicUsize::new(0),
+            min: AtomicUsize::new(0),
+            low: AtomicUsize::new(0),
+            high: AtomicUsize::new(0),
+            reclaiming: AtomicBool::new(false),
+            reclaim: Event::new(),
+        }
+    }
+
+    pub fn stats(&self) -> MemStats {
+        MemStats {
+            total: self.total.load(Ordering::Relaxed),
+            free: self.free.load(Ordering::Relaxed),
+            dirty: ZEROING.stats().dirty,
+            kernel: self.kernel.load(Ordering::Relaxed),
+            user: self.user.load(Ordering::Relaxed),
+        }
+    }
+    pub fn free(&self) -> usize {
+        self.free.load(Ordering::Relaxed)
+    }
+
+    /// Adds `pages` to the total, at boot.
+    pub fn add_total(&self, pages: usize) {
+        self.total.fetch_add(pages, Ordering::Relaxed);
+    }
+
+    pub fn watermarks(&self) -> Watermarks {
+        Watermarks {
+            min: self.min.load(Ordering::Relaxed),
+            low: self.low.load(Ordering::Relaxed),
+            high: self.high.load(Ordering::Relaxed),
+        }
+    }
+    pub fn set_watermarks(&self, watermarks: Watermarks) {
+        assert!(
+            watermarks.min <= watermarks.low && watermarks.low <= watermarks.high,
+            "watermarks out of order: {watermarks:?}"
+        );
+        self.min.store(watermarks.min, Ordering::Relaxed);
+        self.low.store(watermarks.low, Ordering::Relaxed);
+        self.high.store(watermarks.high, Ordering::Relaxed);
+        self.check(self.free());
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        let free = self.free();
+        if free < self.min.load(Ordering::Relaxed) {
+            Pressure::Min
+        } else if free < self.low.load(Ordering::Relaxed) {
+            Pressure::Low
+        } else {
+            Pressure::None
+        }
+    }
+
+    /// Accounts a frame's flags changing from `old` to `new`.
+    pub fn account(&self, old: FrameFlags, new: FrameFlags) {
+        let update = |counter: &AtomicUsize, flags: FrameFlags| {
+            match (old.intersects(flags), new.intersects(flags)) {
+                (false, true) => counter.fetch_add(1, Ordering::Relaxed),
+                (true, false) => counter.fetch_sub(1, Ordering::Relaxed),
+                _ => return,
+            };
+        };
+        update(&self.kernel, FrameFlags::KERNEL);
+        update(&self.user, FrameFlags::USER);
+
+        let free = FrameFlags::FREE | FrameFlags::DIRTY;
+        match (old.intersects(free), new.intersects(free)) {
+            (false, true) => {
+                self.free.fetch_add(1, Ordering::Relaxed);
+            }
+            (true, false) => {
+                let free = self.free.fetch_sub(1, Ordering::Relaxed) - 1;
+                self.check(free);
+            }
+            _ => {}
+        }
+    }
+
+    /// Wakes the reclaim task if `free` is below the low watermark, and it
+    /// isn't already running.
+    fn check(&self, free: usize) {
+        if free < self.low.load(Ordering::Relaxed) && !self.reclaiming.swap(true, Ordering::AcqRel)
+        {
+            self.reclaim.notify();
+        }
+    }
+}
+
+/// The reclaim task. Whenever free memory drops below the low watermark, it
+/// reaps the physical allocator's slab and the quantum caches of `arenas`
+/// until free memory is back above the high watermark, or there's nothing
+/// left to reap. Never returns.
+pub async fn reclaim(phys_alloc: &PhysAlloc, arenas: &[&Vmem<'_>]) -> ! {
+    loop {
+        PHYS_STATS.reclaim.wait().await;
+        loop {
+            let mut released = phys_alloc.reap_slab().await;
+            for arena in arenas {
+                released += arena.reap().await;
+            }
+            if released == 0 || PHYS_STATS.free() >= PHYS_STATS.watermarks().high {
+                break;
+            }
+            if PHYS_STATS.pressure() != Pressure::Min {
+                YieldNow::default().await;
+            }
+        }
+        // The next allocation below the low watermark wakes it again.
+        PHYS_STATS.reclaiming.store(false, Ordering::Release);
+    }
+}
@@ -0,0 +1,153 @@
+//! Zeroing of dirty physical pages in the background, so that allocation
+//! doesn't have to.
+//!
+//! Freed pages go on the physical allocator's dirty list. The
+//! [`zero_dirty_pages`] task takes them off it in batches, zeroes them and
+//! gives them back to the buddy allocator; when and how eagerly it does so is
+//! up to the [`ZeroPolicy`]. Whatever is still dirty when clean memory runs
+//! out gets zeroed on the allocation path instead.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU8, AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+
+use spin::Lazy;
+use system::sync::Event;
+
+use super::physalloc::PhysAlloc;
+
+/// How many pages the task zeroes under one lock of the allocator.
+pub const BATCH: usize = 64;
+
+pub static ZEROING: Lazy<Zeroing> = Lazy::new(Zeroing::new);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ZeroPolicy {
+    /// Zero pages as soon as they're freed, without yielding until the dirty
+    /// list is empty.
+    Eager,
+    /// Zero pages a batch at a time once a batch has built up, yielding
+    /// between batches so that anything else runs first.
+    Idle,
+    /// Never zero ahead of time: pages are zeroed when they're allocated.
+    Lazy,
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ZeroStats {
+    /// Pages on the dirty list.
+    pub dirty: usize,
+    /// Pages zeroed by the background task.
+    pub zeroed_background: usize,
+    /// Pages zeroed on the allocation path, because no clean ones were left.
+    pub zeroed_on_alloc: usize,
+    /// Batches the background task has run.
+    pub batches: usize,
+}
+
+pub struct Zeroing {
+    policy: AtomicU8,
+    work: Event,
+    dirty: AtomicUsize,
+    zeroed_background: AtomicUsize,
+    zeroed_on_alloc: AtomicUsize,
+    batches: AtomicUsize,
+}
+impl Zeroing {
+    fn new() -> Self {
+        Self {
+            policy: AtomicU8::new(ZeroPolicy::Idle as u8),
+            work: Event::new(),
+            dirty: AtomicUsize::new(0),
+            zeroed_background: AtomicUsize::new(0),
+            zeroed_on_alloc: AtomicUsize::new(0),
+            batches: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn policy(&self) -> ZeroPolicy {
+        match self.policy.load(Ordering::Relaxed) {
+            0 => ZeroPolicy::Eager,
+            1 => ZeroPolicy::Idle,
+            _ => ZeroPolicy::Lazy,
+        }
+    }
+    pub fn set_policy(&self, policy: ZeroPolicy) {
+        self.policy.store(policy as u8, Ordering::Relaxed);
+        // Let the task pick up any backlog under the new policy.
+        self.work.notify();
+    }
+
+    pub fn stats(&self) -> ZeroStats {
+        ZeroStats {
+            dirty: self.dirty.load(Ordering::Relaxed),
+            zeroed_background: self.zeroed_background.load(Ordering::Relaxed),
+            zeroed_on_alloc: self.zeroed_on_alloc.load(Ordering::Relaxed),
+            batches: self.batches.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Called by the allocator when a page goes on the dirty list.
+    pub fn dirtied(&self) {
+        let dirty = self.dirty.fetch_add(1, Ordering::Relaxed) + 1;
+        let wake = match self.policy() {
+            ZeroPolicy::Eager => true,
+            ZeroPolicy::Idle => dirty % BATCH == 0,
+            ZeroPolicy::Lazy => false,
+        };
+        if wake {
+            self.work.notify();
+        }
+    }
+    /// Called by the allocator when it zeroes a dirty page itself, to hand it
+    /// out.
+    pub fn zeroed_on_alloc(&self) {
+        self.dirty.fetch_sub(1, Ordering::Relaxed);
+        self.zeroed_on_alloc.fetch_add(1, Ordering::Relaxed);
+    }
+    /// Called by the allocator when it zeroes a dirty page and puts it back.
+    pub fn zeroed(&self) {
+        self.dirty.fetch_sub(1, Ordering::Relaxed);
+        self.zeroed_background.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// The background zeroing task. Never returns.
+pub async fn zero_dirty_pages(phys_alloc: &PhysAlloc) -> ! {
+    loop {
+        ZEROING.work.wait().await;
+        loop {
+            let policy = ZEROING.policy();
+            if policy == ZeroPolicy::Lazy {
+                break;
+            }
+            let more = phys_alloc.clean_dirty_batch(BATCH).await;
+            ZEROING.batches.fetch_add(1, Ordering::Relaxed);
+            if !more {
+                break;
+            }
+            if policy == ZeroPolicy::Idle {
+                YieldNow(false).await;
+            }
+        }
+    }
+}
+
+/// Goes to the back of the queue once.
+#[derive(Default)]
+pub(super) struct YieldNow(bool);
+impl Future for YieldNow {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
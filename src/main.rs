@@ -42,6 +42,9 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
         }
     }
 
+    #[cfg(feature = "test")]
+    common::test::fail();
+
     #[inline(always)]
     fn kalm() -> ! {
         arch::util::wait_forever();
@@ -50,6 +53,9 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
 }
 
 pub fn main() -> ! {
+    #[cfg(feature = "test")]
+    common::test::run();
+
     info!("Main called!");
     panic!("Got to the end of main");
 }